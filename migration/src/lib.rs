@@ -7,6 +7,18 @@ mod m20240505_002524_user_follow_relation;
 mod m20240626_030922_store_ap_json_in_posts;
 mod m20240719_235452_user_ap_column;
 mod m20240725_120932_follow_table_two_point_zero;
+mod m20261019_120000_user_versia_keys;
+mod m20261019_130000_user_private_keys_text;
+mod m20261019_140000_domain_policy_table;
+mod m20261019_150000_processed_activity_table;
+mod m20261019_160000_delivery_job_table;
+mod m20261019_170000_instance_table;
+mod m20261019_180000_user_shared_inbox;
+mod m20261019_190000_delivery_job_host;
+mod m20261019_200000_inbox_job_table;
+mod m20261019_210000_relay_table;
+mod m20261019_220000_processed_activity_processed_at;
+mod m20261019_230000_instance_signature_scheme;
 
 pub struct Migrator;
 
@@ -21,6 +33,18 @@ impl MigratorTrait for Migrator {
             Box::new(m20240626_030922_store_ap_json_in_posts::Migration),
             Box::new(m20240719_235452_user_ap_column::Migration),
             Box::new(m20240725_120932_follow_table_two_point_zero::Migration),
+            Box::new(m20261019_120000_user_versia_keys::Migration),
+            Box::new(m20261019_130000_user_private_keys_text::Migration),
+            Box::new(m20261019_140000_domain_policy_table::Migration),
+            Box::new(m20261019_150000_processed_activity_table::Migration),
+            Box::new(m20261019_160000_delivery_job_table::Migration),
+            Box::new(m20261019_170000_instance_table::Migration),
+            Box::new(m20261019_180000_user_shared_inbox::Migration),
+            Box::new(m20261019_190000_delivery_job_host::Migration),
+            Box::new(m20261019_200000_inbox_job_table::Migration),
+            Box::new(m20261019_210000_relay_table::Migration),
+            Box::new(m20261019_220000_processed_activity_processed_at::Migration),
+            Box::new(m20261019_230000_instance_signature_scheme::Migration),
         ]
     }
 }
@@ -0,0 +1,50 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// Encrypted private keys outgrow a plain varchar, so both key columns become text.
+/// sqlite doesn't enforce column lengths and can't alter column types, so it is skipped.
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        if manager.get_database_backend() == DbBackend::Sqlite {
+            return Ok(());
+        }
+        for column in [User::PrivateKey, User::VersiaPrivateKey] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(User::Table)
+                        .modify_column(ColumnDef::new(column).text())
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        if manager.get_database_backend() == DbBackend::Sqlite {
+            return Ok(());
+        }
+        for column in [User::PrivateKey, User::VersiaPrivateKey] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(User::Table)
+                        .modify_column(ColumnDef::new(column).string())
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+pub enum User {
+    Table,
+    PrivateKey,
+    VersiaPrivateKey,
+}
@@ -0,0 +1,62 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(DomainPolicy::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(DomainPolicy::Domain)
+                            .string()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(DomainPolicy::Reject).boolean().not_null())
+                    .col(ColumnDef::new(DomainPolicy::Silence).boolean().not_null())
+                    .col(
+                        ColumnDef::new(DomainPolicy::StripMedia)
+                            .boolean()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(DomainPolicy::RejectReports)
+                            .boolean()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(DomainPolicy::Allow).boolean().not_null())
+                    .col(ColumnDef::new(DomainPolicy::Reason).string())
+                    .col(
+                        ColumnDef::new(DomainPolicy::CreatedAt)
+                            .timestamp()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(DomainPolicy::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum DomainPolicy {
+    Table,
+    Domain,
+    Reject,
+    Silence,
+    StripMedia,
+    RejectReports,
+    Allow,
+    Reason,
+    CreatedAt,
+}
@@ -0,0 +1,53 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(ProcessedActivity::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(ProcessedActivity::Id)
+                            .string()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(ProcessedActivity::ReceivedAt)
+                            .timestamp()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        // the cleanup job deletes by age
+        manager
+            .create_index(
+                Index::create()
+                    .if_not_exists()
+                    .name("idx_processed_activity_received_at")
+                    .table(ProcessedActivity::Table)
+                    .col(ProcessedActivity::ReceivedAt)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ProcessedActivity::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum ProcessedActivity {
+    Table,
+    Id,
+    ReceivedAt,
+}
@@ -0,0 +1,79 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(DeliveryJob::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(DeliveryJob::Id)
+                            .string()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(DeliveryJob::Protocol).string().not_null())
+                    .col(ColumnDef::new(DeliveryJob::Inbox).string().not_null())
+                    .col(ColumnDef::new(DeliveryJob::SignerId).string().not_null())
+                    .col(ColumnDef::new(DeliveryJob::Body).text().not_null())
+                    .col(ColumnDef::new(DeliveryJob::Status).string().not_null())
+                    .col(
+                        ColumnDef::new(DeliveryJob::Attempts)
+                            .integer()
+                            .not_null()
+                            .default(0),
+                    )
+                    .col(
+                        ColumnDef::new(DeliveryJob::NextAttemptAt)
+                            .timestamp()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(DeliveryJob::LastError).text())
+                    .col(
+                        ColumnDef::new(DeliveryJob::CreatedAt)
+                            .timestamp()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        // workers pick up pending jobs that are due
+        manager
+            .create_index(
+                Index::create()
+                    .if_not_exists()
+                    .name("idx_delivery_job_status_next_attempt_at")
+                    .table(DeliveryJob::Table)
+                    .col(DeliveryJob::Status)
+                    .col(DeliveryJob::NextAttemptAt)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(DeliveryJob::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum DeliveryJob {
+    Table,
+    Id,
+    Protocol,
+    Inbox,
+    SignerId,
+    Body,
+    Status,
+    Attempts,
+    NextAttemptAt,
+    LastError,
+    CreatedAt,
+}
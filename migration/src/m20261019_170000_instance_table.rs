@@ -0,0 +1,81 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Instance::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Instance::Domain)
+                            .string()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Instance::Software).string())
+                    .col(ColumnDef::new(Instance::Version).string())
+                    .col(ColumnDef::new(Instance::LastSuccessAt).timestamp())
+                    .col(ColumnDef::new(Instance::LastFailureAt).timestamp())
+                    .col(
+                        ColumnDef::new(Instance::ConsecutiveFailures)
+                            .integer()
+                            .not_null()
+                            .default(0),
+                    )
+                    .col(ColumnDef::new(Instance::PausedUntil).timestamp())
+                    .col(ColumnDef::new(Instance::NodeinfoFetchedAt).timestamp())
+                    .col(ColumnDef::new(Instance::CreatedAt).timestamp().not_null())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(FollowRelation::Table)
+                    .add_column_if_not_exists(
+                        ColumnDef::new(FollowRelation::FollowerInboxGoneAt).timestamp(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(FollowRelation::Table)
+                    .drop_column(FollowRelation::FollowerInboxGoneAt)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .drop_table(Table::drop().table(Instance::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum Instance {
+    Table,
+    Domain,
+    Software,
+    Version,
+    LastSuccessAt,
+    LastFailureAt,
+    ConsecutiveFailures,
+    PausedUntil,
+    NodeinfoFetchedAt,
+    CreatedAt,
+}
+
+#[derive(DeriveIden)]
+enum FollowRelation {
+    Table,
+    FollowerInboxGoneAt,
+}
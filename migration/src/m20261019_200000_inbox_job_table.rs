@@ -0,0 +1,70 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(InboxJob::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(InboxJob::Id)
+                            .string()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(InboxJob::EntityId).string())
+                    .col(ColumnDef::new(InboxJob::Body).text().not_null())
+                    .col(ColumnDef::new(InboxJob::Status).string().not_null())
+                    .col(
+                        ColumnDef::new(InboxJob::Attempts)
+                            .integer()
+                            .not_null()
+                            .default(0),
+                    )
+                    .col(
+                        ColumnDef::new(InboxJob::NextAttemptAt)
+                            .timestamp()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(InboxJob::LastError).text())
+                    .col(ColumnDef::new(InboxJob::CreatedAt).timestamp().not_null())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .if_not_exists()
+                    .name("idx_inbox_job_status_next_attempt_at")
+                    .table(InboxJob::Table)
+                    .col(InboxJob::Status)
+                    .col(InboxJob::NextAttemptAt)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(InboxJob::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum InboxJob {
+    Table,
+    Id,
+    EntityId,
+    Body,
+    Status,
+    Attempts,
+    NextAttemptAt,
+    LastError,
+    CreatedAt,
+}
@@ -0,0 +1,47 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Relay::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(Relay::Id).string().not_null().primary_key())
+                    .col(ColumnDef::new(Relay::Url).string().not_null().unique_key())
+                    .col(ColumnDef::new(Relay::Actor).string())
+                    .col(ColumnDef::new(Relay::Host).string().not_null())
+                    .col(ColumnDef::new(Relay::Inbox).string().not_null())
+                    .col(ColumnDef::new(Relay::FollowId).string().not_null())
+                    .col(ColumnDef::new(Relay::Status).string().not_null())
+                    .col(ColumnDef::new(Relay::CreatedAt).timestamp().not_null())
+                    .col(ColumnDef::new(Relay::AcceptedAt).timestamp())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Relay::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum Relay {
+    Table,
+    Id,
+    Url,
+    Actor,
+    Host,
+    Inbox,
+    FollowId,
+    Status,
+    CreatedAt,
+    AcceptedAt,
+}
@@ -0,0 +1,50 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(ProcessedActivity::Table)
+                    .add_column_if_not_exists(
+                        ColumnDef::new(ProcessedActivity::ProcessedAt).timestamp(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        // ids stored so far were only ever kept for activities that went through
+        manager
+            .exec_stmt(
+                Query::update()
+                    .table(ProcessedActivity::Table)
+                    .value(
+                        ProcessedActivity::ProcessedAt,
+                        Expr::col(ProcessedActivity::ReceivedAt),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(ProcessedActivity::Table)
+                    .drop_column(ProcessedActivity::ProcessedAt)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum ProcessedActivity {
+    Table,
+    ProcessedAt,
+    ReceivedAt,
+}
@@ -0,0 +1,107 @@
+use activitypub_federation::{
+    config::Data, fetch::object_id::ObjectId, kinds::activity::AnnounceType,
+    traits::ActivityHandler,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::{
+    database::StateHandle,
+    dedup,
+    entities::{post, user},
+    policy::federates_with,
+    relays,
+};
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Announce {
+    pub(crate) actor: ObjectId<user::Model>,
+    /// The announced object, its id, or the activity that created it
+    pub(crate) object: Value,
+    #[serde(rename = "type")]
+    pub(crate) kind: AnnounceType,
+    pub(crate) id: Url,
+}
+
+/// The id of the announced post. Some relays wrap it in the original `Create`.
+pub fn announced_id(object: &Value) -> Option<Url> {
+    let object = match object.get("type").and_then(Value::as_str) {
+        Some("Create") => object.get("object")?,
+        _ => object,
+    };
+    object
+        .as_str()
+        .or(object.get("id").and_then(Value::as_str))
+        .and_then(|id| Url::parse(id).ok())
+}
+
+#[async_trait::async_trait]
+impl ActivityHandler for Announce {
+    type DataType = StateHandle;
+    type Error = crate::error::Error;
+
+    fn id(&self) -> &Url {
+        &self.id
+    }
+
+    fn actor(&self) -> &Url {
+        self.actor.inner()
+    }
+
+    async fn verify(&self, _data: &Data<Self::DataType>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn receive(self, data: &Data<Self::DataType>) -> Result<(), Self::Error> {
+        // boosts by people aren't bridged yet, only what relays pass on
+        if relays::relay_of(self.actor.inner()).await?.is_none() {
+            info!("Ignoring announce {} by {}", self.id, self.actor.inner());
+            return Ok(());
+        }
+        let Some(object_id) = announced_id(&self.object) else {
+            warn!("Relayed announce {} has no object", self.id);
+            return Ok(());
+        };
+        if !federates_with(&object_id) {
+            return Ok(());
+        }
+        let id = self.id.to_string();
+        dedup::once(&id, async {
+            // fetched from its origin, the relay's copy can't be trusted
+            let note = ObjectId::<post::Model>::from(object_id)
+                .dereference(data)
+                .await?;
+            relays::federate_relayed(note).await?;
+            Ok::<_, Self::Error>(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_relay_announced_id() {
+        use super::announced_id;
+        use serde_json::json;
+
+        let note = "https://remote.example/notes/1";
+        assert_eq!(announced_id(&json!(note)).unwrap().as_str(), note);
+        assert_eq!(
+            announced_id(&json!({ "type": "Note", "id": note }))
+                .unwrap()
+                .as_str(),
+            note
+        );
+        let create = json!({
+            "type": "Create",
+            "id": "https://remote.example/notes/1/activity",
+            "object": { "type": "Note", "id": note },
+        });
+        assert_eq!(announced_id(&create).unwrap().as_str(), note);
+        assert!(announced_id(&json!({ "type": "Note" })).is_none());
+    }
+}
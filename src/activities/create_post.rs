@@ -1,29 +1,31 @@
 use crate::{
     database::StateHandle,
-    entities::{self, post, prelude, user},
+    dedup,
+    delivery::deliver,
+    entities::{self, follow_relation, post, prelude, user},
     error::Error,
     objects::{
         person::DbUser,
-        post::{DbPost, Note},
+        post::{spawn_backfill, DbPost, Note},
     },
+    policy::policy_for_url,
+    queue::{enqueue, Protocol},
     utils::{base_url_encode, generate_create_id, generate_random_object_id},
     versia::{
         conversion::{versia_post_from_db, versia_user_from_db},
+        funcs::versia_delivery_inboxes,
         objects::SortAlphabetically,
-        superx::request_client,
     },
-    API_DOMAIN, AUTH, DB,
+    API_DOMAIN, DB,
 };
 use activitypub_federation::{
-    activity_sending::SendActivityTask,
     config::Data,
     fetch::object_id::ObjectId,
     kinds::activity::CreateType,
     protocol::{context::WithContext, helpers::deserialize_one_or_many},
-    traits::{ActivityHandler, Object},
+    traits::{ActivityHandler, Actor, Object},
 };
-use reqwest::RequestBuilder;
-use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, Set};
 use serde::{Deserialize, Serialize};
 use tracing::{info, warn};
 use url::Url;
@@ -57,16 +59,7 @@ impl CreatePost {
             id: generate_create_id(data.domain(), &db_entry.id, &encoded_url)?,
         };
         let create_with_context = WithContext::new_default(create);
-        let sends = SendActivityTask::prepare(
-            &create_with_context,
-            &data.local_user().await?,
-            vec![inbox],
-            data,
-        )
-        .await?;
-        for send in sends {
-            send.sign_and_send(data).await?;
-        }
+        deliver(&create_with_context, &data.local_user().await?, vec![inbox]).await?;
         Ok(())
     }
     pub async fn sends(
@@ -85,12 +78,7 @@ impl CreatePost {
             id: generate_create_id(data.domain(), &db_entry.id, &encoded_url)?,
         };
         let create_with_context = WithContext::new_default(create);
-        let sends =
-            SendActivityTask::prepare(&create_with_context, &data.local_user().await?, inbox, data)
-                .await?;
-        for send in sends {
-            send.sign_and_send(data).await?;
-        }
+        deliver(&create_with_context, &data.local_user().await?, inbox).await?;
         Ok(())
     }
 }
@@ -114,17 +102,38 @@ impl ActivityHandler for CreatePost {
     }
 
     async fn receive(self, data: &Data<Self::DataType>) -> Result<(), Self::Error> {
-        let note = post::Model::from_json(self.object, data).await?;
-        federate_inbox(note).await?;
-        Ok(())
+        let id = self.id.to_string();
+        dedup::once(&id, async {
+            let note = post::Model::from_json(self.object.clone(), data).await?;
+            spawn_backfill(self.object, data.reset_request_count());
+            federate_inbox(note).await?;
+            Ok::<_, Self::Error>(())
+        })
+        .await
     }
 }
 
 async fn federate_inbox(note: crate::entities::post::Model) -> anyhow::Result<()> {
+    let db = DB.get().unwrap();
+
+    let model = prelude::User::find()
+        .filter(user::Column::Id.eq(note.creator.as_str()))
+        .one(db)
+        .await?
+        .unwrap();
+    let policy = policy_for_url(&Url::parse(&model.url)?);
+    if policy.reject {
+        info!("Not bridging {}, its domain is rejected", note.url);
+        return Ok(());
+    }
+
     let versia_post = versia_post_from_db(note.clone()).await?;
 
     let mut array;
-    if versia_post.mentions.is_some() {
+    if policy.silence {
+        // silenced domains only reach followers, not whoever they mention
+        array = Vec::new();
+    } else if versia_post.mentions.is_some() {
         info!("good");
         array = versia_post.mentions.clone().unwrap();
         info!("{:#?}", versia_post.mentions.clone().unwrap());
@@ -133,49 +142,49 @@ async fn federate_inbox(note: crate::entities::post::Model) -> anyhow::Result<()
         array = Vec::new();
     }
 
-    let db = DB.get().unwrap();
-
     let list_model = entities::prelude::FollowRelation::find()
         .filter(entities::follow_relation::Column::FolloweeId.eq(note.creator.to_string()))
+        .filter(entities::follow_relation::Column::AcceptId.is_not_null())
         .all(db)
         .await?;
 
     let mut list_url = Vec::new();
 
-    for model in list_model {
-        let url = Url::parse(&model.follower_inbox.unwrap())?;
-        list_url.push(url);
+    for relation in list_model {
+        match follower_inbox(relation).await {
+            Ok(url) => list_url.push(url),
+            Err(err) => warn!("Skipping a follower of {}: {}", note.creator, err),
+        }
     }
 
     array.append(&mut list_url);
 
-    array.sort();
-    array.dedup();
+    let array = versia_delivery_inboxes(array).await;
 
-    let req_client = request_client();
-    let model = prelude::User::find()
-        .filter(user::Column::Id.eq(note.creator.as_str()))
-        .one(db)
-        .await?
-        .unwrap();
-    for inbox in array {
-        let push = req_client
-            .post(inbox.clone())
-            .bearer_auth(AUTH.to_string())
-            .json(&SortAlphabetically(&versia_post));
-        warn!("{}", inbox.to_string());
-        tokio::spawn(push_to_inbox(push));
-    }
+    let body = serde_json::to_string(&SortAlphabetically(&versia_post))?;
+    enqueue(Protocol::Versia, array, body, &model).await?;
 
     Ok(())
 }
 
-async fn push_to_inbox(req: RequestBuilder) -> anyhow::Result<()> {
-    let req_client = request_client();
-    let response = req_client.execute(req.build()?).await?;
-
-    info!("{}", response.status());
-    info!("{:?}", response.text().await?);
-
-    Ok(())
+/// The inbox of a follower, filled in from the follower for follows stored without one
+pub async fn follower_inbox(relation: follow_relation::Model) -> anyhow::Result<Url> {
+    if let Some(inbox) = relation.follower_inbox {
+        return Ok(Url::parse(&inbox)?);
+    }
+    let db = DB.get().unwrap();
+    let follower = prelude::User::find_by_id(relation.follower_id.as_str())
+        .one(db)
+        .await?
+        .ok_or(anyhow::anyhow!("Unknown follower {}", relation.follower_id))?;
+    let inbox = follower.shared_inbox_or_inbox();
+    follow_relation::ActiveModel {
+        id: Set(relation.id),
+        follower_inbox: Set(Some(inbox.to_string())),
+        follower_host: Set(inbox.host_str().map(str::to_string)),
+        ..Default::default()
+    }
+    .update(db)
+    .await?;
+    Ok(inbox)
 }
@@ -1,24 +1,29 @@
 use activitypub_federation::{
-    activity_sending::SendActivityTask,
     config::Data,
     fetch::object_id::ObjectId,
     protocol::context::WithContext,
     traits::{ActivityHandler, Actor, Object},
 };
-use activitystreams_kinds::activity::{AcceptType, FollowType};
+use activitystreams_kinds::activity::{AcceptType, FollowType, RejectType, UndoType};
+use anyhow::anyhow;
 use sea_orm::{ActiveModelTrait, ColumnTrait, EntityOrSelect, EntityTrait, QueryFilter, Set};
 use serde::{Deserialize, Serialize};
+use tracing::info;
 use url::Url;
 
 use crate::{
     database::StateHandle,
+    dedup,
+    delivery::deliver,
     entities::{
         follow_relation::{self, Entity},
         post, prelude, user,
     },
     error,
-    utils::{generate_follow_accept_id, generate_random_object_id},
-    versia::funcs::send_follow_accept_to_versia,
+    http::{is_bridge_host, local_db_user_from_url},
+    relays, service_actor,
+    utils::{generate_follow_accept_id, generate_follow_reject_id, generate_random_object_id},
+    versia::funcs::{send_follow_accept_to_versia, send_follow_to_versia},
     DB,
 };
 
@@ -46,33 +51,45 @@ impl Follow {
             id: generate_random_object_id(data.domain())?,
         };
         let create_with_context = WithContext::new_default(create);
-        let sends = SendActivityTask::prepare(
-            &create_with_context,
-            &data.local_user().await?,
-            vec![inbox],
-            data,
-        )
-        .await?;
-        for send in sends {
-            send.sign_and_send(data).await?;
-        }
+        deliver(&create_with_context, &data.local_user().await?, vec![inbox]).await?;
         Ok(())
     }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Accept {
-    actor: ObjectId<user::Model>,
-    object: Follow,
+    pub(crate) actor: ObjectId<user::Model>,
+    pub(crate) object: Follow,
+    #[serde(rename = "type")]
+    pub(crate) kind: AcceptType,
+    pub(crate) id: Url,
+}
+
+/// Turns down a follow of a bridged user that its Versia instance rejected
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Reject {
+    pub(crate) actor: ObjectId<user::Model>,
+    pub(crate) object: Follow,
     #[serde(rename = "type")]
-    kind: AcceptType,
-    id: Url,
+    pub(crate) kind: RejectType,
+    pub(crate) id: Url,
+}
+
+/// Withdraws a follow. Only sent, when unsubscribing from a relay.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Undo {
+    pub actor: ObjectId<user::Model>,
+    pub object: Follow,
+    #[serde(rename = "type")]
+    pub kind: UndoType,
+    pub id: Url,
 }
 
 impl Accept {
     pub async fn send(
         follow_relation: follow_relation::Model,
         follow_req: Follow,
+        followee: &user::Model,
         inbox: Url,
         data: &Data<StateHandle>,
     ) -> Result<(), error::Error> {
@@ -84,16 +101,26 @@ impl Accept {
             id: generate_follow_accept_id(data.domain(), follow_relation.id.to_string().as_str())?,
         };
         let create_with_context = WithContext::new_default(create);
-        let sends = SendActivityTask::prepare(
-            &create_with_context,
-            &data.local_user().await?,
-            vec![inbox],
-            data,
-        )
-        .await?;
-        for send in sends {
-            send.sign_and_send(data).await?;
-        }
+        deliver(&create_with_context, followee, vec![inbox]).await?;
+        Ok(())
+    }
+}
+
+impl Reject {
+    pub async fn send(
+        follow_relation: follow_relation::Model,
+        follow_req: Follow,
+        followee: &user::Model,
+        inbox: Url,
+        data: &Data<StateHandle>,
+    ) -> Result<(), error::Error> {
+        let reject = Reject {
+            actor: follow_req.object.clone(),
+            object: follow_req,
+            kind: RejectType::Reject,
+            id: generate_follow_reject_id(data.domain(), &follow_relation.id)?,
+        };
+        deliver(&WithContext::new_default(reject), followee, vec![inbox]).await?;
         Ok(())
     }
 }
@@ -116,8 +143,15 @@ impl ActivityHandler for Follow {
     }
 
     async fn receive(self, data: &Data<Self::DataType>) -> Result<(), Self::Error> {
-        //accept_follow(self, data).await?; TODO replace w/ versia forward
-        Ok(())
+        let id = self.id.to_string();
+        dedup::once(&id, async {
+            if let Some(relay) = relays::relay_of(self.actor.inner()).await? {
+                relays::accept_follow(relay, self, data).await?;
+                return Ok(());
+            }
+            request_follow(self, data).await
+        })
+        .await
     }
 }
 
@@ -139,36 +173,156 @@ impl ActivityHandler for Accept {
     }
 
     async fn receive(self, data: &Data<Self::DataType>) -> Result<(), Self::Error> {
-        let user = self.actor.dereference(data).await?;
-        let follower_id;
-        let follower_bridge_url = self.object.actor.clone().to_string();
-        let split = follower_bridge_url.split("/").collect::<Vec<&str>>();
-        if split[split.len() - 1].is_empty() {
-            follower_id = split[split.len() - 2];
-        } else {
-            follower_id = split[split.len() - 1];
-        }
-        let follower = prelude::User::find()
-            .filter(user::Column::Id.eq(follower_id))
-            .one(data.database_connection.as_ref())
-            .await?;
-        save_accept_follow(user, follower.unwrap(), self).await?;
-        Ok(())
+        let id = self.id.to_string();
+        dedup::once(&id, async {
+            if relays::accepted(&self.object.id).await? {
+                return Ok(());
+            }
+            let user = self.actor.dereference(data).await?;
+            let follower_id;
+            let follower_bridge_url = self.object.actor.clone().to_string();
+            let split = follower_bridge_url.split("/").collect::<Vec<&str>>();
+            if split[split.len() - 1].is_empty() {
+                follower_id = split[split.len() - 2];
+            } else {
+                follower_id = split[split.len() - 1];
+            }
+            let follower = prelude::User::find()
+                .filter(user::Column::Id.eq(follower_id))
+                .one(data.database_connection.as_ref())
+                .await?;
+            save_accept_follow(user, follower.unwrap(), self).await?;
+            Ok::<_, Self::Error>(())
+        })
+        .await
     }
 }
 
-/*
-async fn accept_follow(
+/// Passes a follow of a bridged user on to the Versia instance, which decides on it. The
+/// follow is accepted once it answers, see [`follow_answered`].
+async fn request_follow(
     follow_req: Follow,
     data: &Data<StateHandle>,
 ) -> Result<(), crate::error::Error> {
-    let local_user = follow_req.actor.dereference(data).await?;
-    let follower = follow_req.object.dereference(data).await?;
-    let follow_relation = save_follow(local_user, follower.clone()).await?;
-    Accept::send(follow_relation, follow_req, follower.inbox().clone(), data).await?;
+    if !follow_req
+        .object
+        .inner()
+        .host_str()
+        .is_some_and(is_bridge_host)
+    {
+        return Err(anyhow!("{} is not one of our actors", follow_req.object).into());
+    }
+    let followee = local_db_user_from_url(follow_req.object.inner()).await?;
+    if followee.url == service_actor::actor_url().as_str() {
+        info!(
+            "Ignoring follow of the service actor by {}",
+            follow_req.actor
+        );
+        return Ok(());
+    }
+    let follower = follow_req.actor.dereference(data).await?;
+    let follow_relation = save_follow(&follower, &followee, &follow_req).await?;
+    match follow_relation.accept_id {
+        // followed before, the follower may have missed the Accept
+        Some(_) => {
+            Accept::send(
+                follow_relation,
+                follow_req,
+                &followee,
+                follower.inbox(),
+                data,
+            )
+            .await?
+        }
+        None => send_follow_to_versia(follow_relation).await?,
+    }
+    Ok(())
+}
+
+/// Accepts or rejects a follow of a bridged user, as the Versia instance answered it
+pub async fn follow_answered(
+    follower_url: &Url,
+    followee_url: &Url,
+    accepted: bool,
+    data: &Data<StateHandle>,
+) -> Result<(), crate::error::Error> {
+    let db = DB.get().unwrap();
+    let find_user = |url: &Url| {
+        prelude::User::find()
+            .filter(user::Column::Url.eq(url.as_str()))
+            .one(db)
+    };
+    let (Some(follower), Some(followee)) = (
+        find_user(follower_url).await?,
+        find_user(followee_url).await?,
+    ) else {
+        return Err(anyhow!("No follow of {} by {}", followee_url, follower_url).into());
+    };
+    let relation = prelude::FollowRelation::find()
+        .filter(follow_relation::Column::FollowerId.eq(follower.id.as_str()))
+        .filter(follow_relation::Column::FolloweeId.eq(followee.id.as_str()))
+        .filter(follow_relation::Column::Remote.eq(true))
+        .one(db)
+        .await?
+        .ok_or(anyhow!("No follow of {} by {}", followee_url, follower_url))?;
+    let follow_req: Follow = serde_json::from_str(&relation.ap_json)?;
+    if !accepted {
+        prelude::FollowRelation::delete_by_id(relation.id.as_str())
+            .exec(db)
+            .await?;
+        Reject::send(relation, follow_req, &followee, follower.inbox(), data).await?;
+        return Ok(());
+    }
+    if relation.accept_id.is_some() {
+        return Ok(());
+    }
+    let relation = follow_relation::ActiveModel {
+        id: Set(relation.id),
+        accept_id: Set(Some(uuid::Uuid::now_v7().to_string())),
+        ..Default::default()
+    }
+    .update(db)
+    .await?;
+    Accept::send(relation, follow_req, &followee, follower.inbox(), data).await?;
     Ok(())
 }
-*/
+
+/// Stores a follow of a bridged user, pending until the Versia instance accepts it, or
+/// returns the one stored before
+pub async fn save_follow(
+    follower: &user::Model,
+    followee: &user::Model,
+    follow_req: &Follow,
+) -> Result<follow_relation::Model, crate::error::Error> {
+    let db = DB.get().unwrap();
+    let existing = prelude::FollowRelation::find()
+        .filter(follow_relation::Column::FollowerId.eq(follower.id.as_str()))
+        .filter(follow_relation::Column::FolloweeId.eq(followee.id.as_str()))
+        .one(db)
+        .await?;
+    if let Some(existing) = existing {
+        return Ok(existing);
+    }
+    // followers on one server then share a delivery, as they do for mentions
+    let follower_inbox = follower.shared_inbox_or_inbox();
+    let model = follow_relation::ActiveModel {
+        id: Set(uuid::Uuid::now_v7().to_string()),
+        followee_id: Set(followee.id.clone()),
+        follower_id: Set(follower.id.clone()),
+        followee_inbox: Set(Some(followee.inbox.clone())),
+        followee_host: Set(Url::parse(&followee.inbox)?.host_str().map(str::to_string)),
+        follower_inbox: Set(Some(follower_inbox.to_string())),
+        follower_host: Set(follower_inbox.host_str().map(str::to_string)),
+        accept_id: Set(None),
+        ap_id: Set(Some(follow_req.id.to_string())),
+        ap_json: Set(serde_json::to_string(follow_req)?),
+        remote: Set(true),
+        ..Default::default()
+    }
+    .insert(db)
+    .await?;
+    Ok(model)
+}
 
 async fn save_accept_follow(
     followee: user::Model,
@@ -201,3 +355,74 @@ async fn save_accept_follow(
 
     Ok(model)
 }
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn test_follow_waits_for_versia() {
+        use super::{follow_answered, save_follow, Follow};
+        use crate::{
+            entities::{delivery_job, prelude, user},
+            testing,
+        };
+        use activitystreams_kinds::activity::FollowType;
+        use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+        use url::Url;
+
+        let db = testing::db().await;
+        let data = testing::federation().await.to_request_data();
+        let followee = testing::user("locked-followee", true).await;
+        let follow = |follower: &str| Follow {
+            actor: Url::parse(&format!("https://remote.example/users/{follower}"))
+                .unwrap()
+                .into(),
+            object: Url::parse("https://bridge.example/apbridge/user/locked-followee")
+                .unwrap()
+                .into(),
+            kind: FollowType::Follow,
+            id: Url::parse(&format!("https://remote.example/follows/{follower}")).unwrap(),
+        };
+        let answer = |follower: &user::Model, accepted: bool| {
+            let follower = Url::parse(&follower.url).unwrap();
+            let followee = Url::parse(&followee.url).unwrap();
+            let data = &data;
+            async move { follow_answered(&follower, &followee, accepted, data).await }
+        };
+        let jobs = |follower: &user::Model| {
+            prelude::DeliveryJob::find()
+                .filter(delivery_job::Column::SignerId.eq(followee.id.as_str()))
+                .filter(delivery_job::Column::Inbox.eq(follower.inbox.as_str()))
+                .all(db)
+        };
+
+        let accepted = testing::user("locked-accepted", false).await;
+        let relation = save_follow(&accepted, &followee, &follow("locked-accepted"))
+            .await
+            .unwrap();
+        assert!(relation.accept_id.is_none());
+        answer(&accepted, true).await.unwrap();
+        let relation = prelude::FollowRelation::find_by_id(relation.id)
+            .one(db)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(relation.accept_id.is_some());
+        let sent = jobs(&accepted).await.unwrap();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].body.contains("\"Accept\""));
+
+        let rejected = testing::user("locked-rejected", false).await;
+        let relation = save_follow(&rejected, &followee, &follow("locked-rejected"))
+            .await
+            .unwrap();
+        answer(&rejected, false).await.unwrap();
+        assert!(prelude::FollowRelation::find_by_id(relation.id)
+            .one(db)
+            .await
+            .unwrap()
+            .is_none());
+        let sent = jobs(&rejected).await.unwrap();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].body.contains("\"Reject\""));
+    }
+}
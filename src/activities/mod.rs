@@ -1,2 +1,3 @@
+pub mod announce;
 pub mod create_post;
 pub mod follow;
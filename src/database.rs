@@ -1,12 +1,8 @@
 use super::entities::prelude::User;
-use crate::{entities::user, error::Error, objects::person::DbUser, LOCAL_USER_NAME};
-use anyhow::anyhow;
+use crate::{entities::user, error::Error, objects::person::DbUser, service_actor};
 use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
 use serde::{Deserialize, Serialize};
-use std::{
-    env,
-    sync::{Arc, Mutex},
-};
+use std::sync::{Arc, Mutex};
 
 #[derive(Debug, Clone)]
 pub struct Config {}
@@ -25,24 +21,18 @@ pub struct Database {
 }
 
 impl State {
+    /// The bridge's service actor
     pub async fn local_user(&self) -> Result<user::Model, Error> {
-        let user = User::find()
-            .filter(
-                user::Column::Username
-                    .eq(env::var("LOCAL_USER_NAME").unwrap_or(LOCAL_USER_NAME.to_string())),
-            )
-            .one(self.database_connection.as_ref())
-            .await?
-            .unwrap();
-        Ok(user.clone())
+        Ok(service_actor::find().await?)
     }
 
-    pub async fn read_user(&self, name: &str) -> Result<user::Model, Error> {
-        let db_user = self.local_user().await?;
-        if name == db_user.username {
-            Ok(db_user)
-        } else {
-            Err(anyhow!("Invalid user {name} // {0}", db_user.username).into())
-        }
+    /// Looks up a bridged Versia user by their username
+    pub async fn read_user(&self, name: &str) -> Result<Option<user::Model>, Error> {
+        let user = User::find()
+            .filter(user::Column::Username.eq(name))
+            .filter(user::Column::Local.eq(true))
+            .one(self.database_connection.as_ref())
+            .await?;
+        Ok(user)
     }
 }
@@ -0,0 +1,186 @@
+use std::{future::Future, time::Duration};
+
+use chrono::Utc;
+use sea_orm::{
+    sea_query::{Expr, OnConflict},
+    ColumnTrait, DbErr, EntityTrait, QueryFilter, Set,
+};
+use tracing::{info, warn};
+
+use crate::{
+    entities::{prelude, processed_activity},
+    DB, PROCESSED_ACTIVITY_TTL,
+};
+
+/// How often expired activity ids are cleaned up
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long a claim holds without the activity being marked processed. A worker that died
+/// mid-processing doesn't keep retries of the activity out for longer than this.
+const CLAIM_LEASE: Duration = Duration::from_secs(10 * 60);
+
+/// Claims `id` for processing. Returns false if it was processed already, or another
+/// delivery claimed it within the lease, in which case it must not be processed now. The
+/// database decides, so concurrent deliveries can't both win.
+pub async fn claim(id: &str) -> Result<bool, DbErr> {
+    let db = DB.get().unwrap();
+    let now = Utc::now();
+    let inserted = prelude::ProcessedActivity::insert(processed_activity::ActiveModel {
+        id: Set(id.to_string()),
+        received_at: Set(now),
+        processed_at: Set(None),
+    })
+    .on_conflict(
+        OnConflict::column(processed_activity::Column::Id)
+            .do_nothing()
+            .to_owned(),
+    )
+    .exec_without_returning(db)
+    .await?;
+    if inserted > 0 {
+        return Ok(true);
+    }
+    let expired = now - chrono::Duration::from_std(CLAIM_LEASE).unwrap();
+    let taken_over = prelude::ProcessedActivity::update_many()
+        .col_expr(processed_activity::Column::ReceivedAt, Expr::value(now))
+        .filter(processed_activity::Column::Id.eq(id))
+        .filter(processed_activity::Column::ProcessedAt.is_null())
+        .filter(processed_activity::Column::ReceivedAt.lt(expired))
+        .exec(db)
+        .await?;
+    Ok(taken_over.rows_affected > 0)
+}
+
+/// Marks a claimed `id` as processed, after which it is skipped until it expires
+pub async fn mark_processed(id: &str) -> Result<(), DbErr> {
+    prelude::ProcessedActivity::update_many()
+        .col_expr(
+            processed_activity::Column::ProcessedAt,
+            Expr::value(Utc::now()),
+        )
+        .filter(processed_activity::Column::Id.eq(id))
+        .exec(DB.get().unwrap())
+        .await?;
+    Ok(())
+}
+
+/// Forgets `id`, so a retried delivery gets processed
+pub async fn release(id: &str) -> Result<(), DbErr> {
+    prelude::ProcessedActivity::delete_by_id(id)
+        .exec(DB.get().unwrap())
+        .await?;
+    Ok(())
+}
+
+/// Runs `process` unless the activity `id` was processed before or is being processed. If
+/// processing fails the id is released again, since the sender will retry.
+pub async fn once<E: From<DbErr>>(
+    id: &str,
+    process: impl Future<Output = Result<(), E>>,
+) -> Result<(), E> {
+    if !claim(id).await? {
+        info!("Skipping already processed activity {}", id);
+        return Ok(());
+    }
+    let result = process.await;
+    match &result {
+        Ok(()) => mark_processed(id).await?,
+        Err(_) => {
+            if let Err(err) = release(id).await {
+                warn!("Could not release activity {}: {}", id, err);
+            }
+        }
+    }
+    result
+}
+
+/// Deletes activity ids older than the configured TTL, after which a replay would be
+/// processed again. Senders stop retrying long before that.
+pub fn spawn_cleanup() {
+    tokio::spawn(async {
+        loop {
+            let ttl = chrono::Duration::hours(*PROCESSED_ACTIVITY_TTL);
+            let deleted = prelude::ProcessedActivity::delete_many()
+                .filter(processed_activity::Column::ReceivedAt.lt(Utc::now() - ttl))
+                .exec(DB.get().unwrap())
+                .await;
+            match deleted {
+                Ok(deleted) if deleted.rows_affected > 0 => {
+                    info!("Cleaned up {} processed activities", deleted.rows_affected)
+                }
+                Ok(_) => {}
+                Err(err) => warn!("Could not clean up processed activities: {}", err),
+            }
+            tokio::time::sleep(CLEANUP_INTERVAL).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn test_claims() {
+        use super::{claim, mark_processed, release};
+        use crate::{
+            entities::{prelude, processed_activity},
+            testing,
+        };
+        use chrono::{Duration, Utc};
+        use sea_orm::{EntityTrait, Set};
+
+        let db = testing::db().await;
+        assert!(claim("dedup-claim").await.unwrap());
+        // held by the first claim while it is being processed
+        assert!(!claim("dedup-claim").await.unwrap());
+        mark_processed("dedup-claim").await.unwrap();
+        assert!(!claim("dedup-claim").await.unwrap());
+
+        assert!(claim("dedup-release").await.unwrap());
+        release("dedup-release").await.unwrap();
+        assert!(claim("dedup-release").await.unwrap());
+
+        // claims that were never marked processed expire, processed ones don't
+        for (id, processed_at) in [
+            ("dedup-stale", None),
+            ("dedup-old", Some(Utc::now() - Duration::hours(1))),
+        ] {
+            prelude::ProcessedActivity::insert(processed_activity::ActiveModel {
+                id: Set(id.to_string()),
+                received_at: Set(Utc::now() - Duration::hours(1)),
+                processed_at: Set(processed_at),
+            })
+            .exec_without_returning(db)
+            .await
+            .unwrap();
+        }
+        assert!(claim("dedup-stale").await.unwrap());
+        assert!(!claim("dedup-stale").await.unwrap());
+        assert!(!claim("dedup-old").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_once() {
+        use super::once;
+        use crate::testing;
+        use sea_orm::DbErr;
+
+        testing::db().await;
+        let failed: Result<(), DbErr> = once("dedup-once", async {
+            Err(DbErr::Custom("failed".to_string()))
+        })
+        .await;
+        assert!(failed.is_err());
+
+        let mut runs = 0;
+        for _ in 0..2 {
+            once("dedup-once", async {
+                runs += 1;
+                Ok::<(), DbErr>(())
+            })
+            .await
+            .unwrap();
+        }
+        // retried after the failure, then skipped once it went through
+        assert_eq!(runs, 1);
+    }
+}
@@ -0,0 +1,253 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use activitypub_federation::FEDERATION_CONTENT_TYPE;
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use reqwest::{
+    header::{CONTENT_TYPE, DATE},
+    RequestBuilder, StatusCode,
+};
+use sea_orm::{ColumnTrait, DbErr, EntityTrait, QueryFilter};
+use serde::Serialize;
+use thiserror::Error;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::{
+    entities::{instance, prelude, user},
+    fetcher::{fetcher, FetchError},
+    http_signatures::{actor_private_key, digest, sign_cavage},
+    instances::record_signature_scheme,
+    integrity_proofs::sign_activity,
+    message_signatures::{
+        self, content_digest, key_id, Message, CONTENT_DIGEST_HEADER, SIGNATURE_INPUT_HEADER,
+    },
+    policy::deliverable,
+    queue::{enqueue, Protocol},
+    DB,
+};
+
+/// How a delivery is signed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureScheme {
+    /// draft-cavage-http-signatures, understood by virtually all of the fediverse
+    Cavage,
+    /// RFC 9421 HTTP Message Signatures
+    Rfc9421,
+}
+
+impl SignatureScheme {
+    fn other(self) -> SignatureScheme {
+        match self {
+            SignatureScheme::Cavage => SignatureScheme::Rfc9421,
+            SignatureScheme::Rfc9421 => SignatureScheme::Cavage,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            SignatureScheme::Cavage => "cavage",
+            SignatureScheme::Rfc9421 => "rfc9421",
+        }
+    }
+
+    fn parse(scheme: &str) -> Option<SignatureScheme> {
+        match scheme {
+            "cavage" => Some(SignatureScheme::Cavage),
+            "rfc9421" => Some(SignatureScheme::Rfc9421),
+            _ => None,
+        }
+    }
+}
+
+/// The scheme each host last accepted, tried first on the next delivery there. Kept in the
+/// instance table as well, so hosts needing RFC 9421 don't cost a refused delivery after
+/// every restart.
+static KNOWN_SCHEMES: Lazy<Mutex<HashMap<String, SignatureScheme>>> = Lazy::new(Default::default);
+
+/// Loads the schemes hosts accepted before
+pub async fn load_schemes() -> Result<(), DbErr> {
+    let instances = prelude::Instance::find()
+        .filter(instance::Column::SignatureScheme.is_not_null())
+        .all(DB.get().unwrap())
+        .await?;
+    *KNOWN_SCHEMES.lock().unwrap() = instances
+        .into_iter()
+        .filter_map(|instance| {
+            let scheme = SignatureScheme::parse(instance.signature_scheme.as_deref()?)?;
+            Some((instance.domain, scheme))
+        })
+        .collect();
+    Ok(())
+}
+
+/// Remembers the scheme `host` accepted, writing it only when it changed
+async fn remember_scheme(host: String, scheme: SignatureScheme) {
+    if KNOWN_SCHEMES.lock().unwrap().insert(host.clone(), scheme) == Some(scheme) {
+        return;
+    }
+    if let Err(err) = record_signature_scheme(&host, scheme.as_str()).await {
+        warn!("Could not store the signature scheme of {}: {}", host, err);
+    }
+}
+
+fn preferred_scheme(host: &str) -> SignatureScheme {
+    KNOWN_SCHEMES
+        .lock()
+        .unwrap()
+        .get(host)
+        .copied()
+        .unwrap_or(SignatureScheme::Cavage)
+}
+
+#[derive(Debug, Error)]
+pub enum DeliveryError {
+    #[error("{0} answered with status {1}")]
+    Status(Url, StatusCode),
+    #[error(transparent)]
+    Fetch(#[from] FetchError),
+    #[error(transparent)]
+    Signing(#[from] anyhow::Error),
+    #[error("{0} is paused after repeated failures")]
+    Paused(String),
+}
+
+/// The value of the `Host` header reqwest sends for `url`
+fn authority(url: &Url) -> String {
+    match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    }
+}
+
+fn signed_post(
+    inbox: &Url,
+    body: &[u8],
+    actor: &user::Model,
+    scheme: SignatureScheme,
+) -> anyhow::Result<RequestBuilder> {
+    let key = actor_private_key(actor)?;
+    let key_id = key_id(actor);
+    let date = Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+    let request = fetcher()
+        .client()
+        .post(inbox.clone())
+        .header(CONTENT_TYPE, FEDERATION_CONTENT_TYPE)
+        .header(DATE, &date);
+    let request = match scheme {
+        SignatureScheme::Cavage => {
+            let digest = digest(body);
+            let signature = sign_cavage(
+                "POST",
+                inbox,
+                &[
+                    ("host", authority(inbox).as_str()),
+                    ("date", date.as_str()),
+                    ("digest", digest.as_str()),
+                    ("content-type", FEDERATION_CONTENT_TYPE),
+                ],
+                &key_id,
+                &key,
+            )?;
+            request
+                .header("Digest", digest)
+                .header(message_signatures::SIGNATURE_HEADER, signature)
+        }
+        SignatureScheme::Rfc9421 => {
+            let content_digest = content_digest(body);
+            let message = Message {
+                method: "POST".to_string(),
+                target_uri: inbox.clone(),
+                headers: HashMap::from([
+                    ("content-digest".to_string(), content_digest.clone()),
+                    (
+                        "content-type".to_string(),
+                        FEDERATION_CONTENT_TYPE.to_string(),
+                    ),
+                ]),
+            };
+            let (signature_input, signature) = message_signatures::sign(
+                &message,
+                &["@method", "@target-uri", "content-digest", "content-type"],
+                &key_id,
+                &key,
+                Utc::now().timestamp(),
+            )?;
+            request
+                .header(CONTENT_DIGEST_HEADER, content_digest)
+                .header(SIGNATURE_INPUT_HEADER, signature_input)
+                .header(message_signatures::SIGNATURE_HEADER, signature)
+        }
+    };
+    Ok(request.body(body.to_vec()))
+}
+
+async fn post(inbox: &Url, request: RequestBuilder) -> Result<StatusCode, DeliveryError> {
+    let response = request.send().await.map_err(FetchError::from)?;
+    let status = response.status();
+    if !status.is_success() && status != StatusCode::UNAUTHORIZED {
+        return Err(DeliveryError::Status(inbox.clone(), status));
+    }
+    Ok(status)
+}
+
+/// Delivers `body` to one inbox. When the signature scheme is refused with 401 the delivery
+/// is repeated with the other one ("double-knocking"), and whichever worked is remembered
+/// for the host.
+pub async fn deliver_to(
+    inbox: &Url,
+    body: &[u8],
+    actor: &user::Model,
+) -> Result<(), DeliveryError> {
+    fetcher().check(inbox)?;
+    let host = authority(inbox);
+    let mut scheme = preferred_scheme(&host);
+    let mut status = post(inbox, signed_post(inbox, body, actor, scheme)?).await?;
+    if status == StatusCode::UNAUTHORIZED {
+        info!("{} refused a {:?} signature, knocking again", host, scheme);
+        scheme = scheme.other();
+        status = post(inbox, signed_post(inbox, body, actor, scheme)?).await?;
+    }
+    if status == StatusCode::UNAUTHORIZED {
+        return Err(DeliveryError::Status(inbox.clone(), status));
+    }
+    remember_scheme(host, scheme).await;
+    Ok(())
+}
+
+/// Queues an activity for delivery to every inbox the domain policy lets us deliver to, with
+/// an integrity proof when its actor is one of our bridged users
+pub async fn deliver<A: Serialize>(
+    activity: &A,
+    actor: &user::Model,
+    inboxes: Vec<Url>,
+) -> anyhow::Result<()> {
+    let body = serde_json::to_string(&sign_activity(serde_json::to_value(activity)?).await)?;
+    enqueue(Protocol::ActivityPub, deliverable(inboxes), body, actor).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn test_schemes_survive_restart() {
+        use super::{
+            load_schemes, preferred_scheme, remember_scheme, SignatureScheme, KNOWN_SCHEMES,
+        };
+        use crate::testing;
+
+        testing::db().await;
+        remember_scheme("rfc9421.example".to_string(), SignatureScheme::Rfc9421).await;
+        remember_scheme("cavage.example".to_string(), SignatureScheme::Cavage).await;
+        KNOWN_SCHEMES.lock().unwrap().clear();
+        assert_eq!(preferred_scheme("rfc9421.example"), SignatureScheme::Cavage);
+
+        load_schemes().await.unwrap();
+        assert_eq!(
+            preferred_scheme("rfc9421.example"),
+            SignatureScheme::Rfc9421
+        );
+        assert_eq!(preferred_scheme("cavage.example"), SignatureScheme::Cavage);
+        assert_eq!(preferred_scheme("unknown.example"), SignatureScheme::Cavage);
+    }
+}
@@ -1,5 +1,6 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.12.10
 
+use chrono::Utc;
 use sea_orm::entity::prelude::*;
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
@@ -19,6 +20,8 @@ pub struct Model {
     pub remote: bool,
     pub ap_json: String,
     pub ap_accept_json: Option<String>,
+    #[sea_orm(column_type = "Timestamp")]
+    pub follower_inbox_gone_at: Option<chrono::DateTime<Utc>>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -2,6 +2,12 @@
 
 pub mod prelude;
 
+pub mod delivery_job;
+pub mod domain_policy;
 pub mod follow_relation;
+pub mod inbox_job;
+pub mod instance;
 pub mod post;
+pub mod processed_activity;
+pub mod relay;
 pub mod user;
@@ -1,5 +1,11 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.12.10
 
+pub use super::delivery_job::Entity as DeliveryJob;
+pub use super::domain_policy::Entity as DomainPolicy;
 pub use super::follow_relation::Entity as FollowRelation;
+pub use super::inbox_job::Entity as InboxJob;
+pub use super::instance::Entity as Instance;
 pub use super::post::Entity as Post;
+pub use super::processed_activity::Entity as ProcessedActivity;
+pub use super::relay::Entity as Relay;
 pub use super::user::Entity as User;
@@ -27,6 +27,9 @@ pub struct Model {
     pub followers: Option<String>,
     pub inbox: String,
     pub ap_json: Option<String>,
+    pub versia_public_key: Option<String>,
+    pub versia_private_key: Option<String>,
+    pub shared_inbox: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -0,0 +1,600 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use activitypub_federation::config::UrlVerifier;
+use once_cell::sync::Lazy;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    header::CONTENT_TYPE,
+    redirect, Client, Response,
+};
+use reqwest_middleware::ClientWithMiddleware;
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+use url::{Host, Url};
+
+use crate::{policy::federates_with, LYSAND_DOMAIN};
+
+/// Limits every outbound fetch of remote data is held to
+#[derive(Clone, Debug)]
+pub struct FetchPolicy {
+    /// Only meant for tests against a local stand-in server
+    pub allow_private_addresses: bool,
+    pub max_redirects: usize,
+    pub max_body_size: usize,
+    pub timeout: Duration,
+}
+
+impl Default for FetchPolicy {
+    fn default() -> Self {
+        FetchPolicy {
+            allow_private_addresses: false,
+            max_redirects: 5,
+            max_body_size: 5 * 1024 * 1024,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum FetchError {
+    #[error("Refusing to fetch {0}: only http and https are allowed")]
+    UnsupportedScheme(Url),
+    #[error("Refusing to connect to non-public address of {0}")]
+    ForbiddenAddress(String),
+    #[error("Could not resolve {0}")]
+    Unresolvable(String),
+    #[error("Too many redirects")]
+    Redirect,
+    #[error("Response is larger than {0} bytes")]
+    BodyTooLarge(usize),
+    #[error("Request timed out")]
+    Timeout,
+    #[error("Response has status {0}")]
+    Status(reqwest::StatusCode),
+    #[error(transparent)]
+    Request(reqwest::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+impl From<reqwest::Error> for FetchError {
+    fn from(err: reqwest::Error) -> Self {
+        // errors of our resolver and redirect policy come back wrapped by reqwest
+        let mut source = std::error::Error::source(&err);
+        while let Some(inner) = source {
+            match inner.downcast_ref::<FetchError>() {
+                Some(FetchError::ForbiddenAddress(host)) => {
+                    return FetchError::ForbiddenAddress(host.clone())
+                }
+                Some(FetchError::Unresolvable(host)) => {
+                    return FetchError::Unresolvable(host.clone())
+                }
+                Some(FetchError::UnsupportedScheme(url)) => {
+                    return FetchError::UnsupportedScheme(url.clone())
+                }
+                Some(FetchError::Redirect) => return FetchError::Redirect,
+                _ => {}
+            }
+            source = inner.source();
+        }
+        if err.is_timeout() {
+            FetchError::Timeout
+        } else if err.is_redirect() {
+            FetchError::Redirect
+        } else {
+            FetchError::Request(err)
+        }
+    }
+}
+
+/// Whether an address is reachable on the public internet, as opposed to loopback,
+/// private, link-local and other special purpose ranges
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ipv4(ip),
+            None => is_public_ipv6(ip),
+        },
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // 0.0.0.0/8, shared address space 100.64.0.0/10, benchmarking 198.18.0.0/15
+        // and the reserved 240.0.0.0/4
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240)
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // unique local fc00::/7, link-local fe80::/10 and documentation 2001:db8::/32
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+/// Resolves host names and drops every address we must not connect to
+struct PublicResolver {
+    allow_private_addresses: bool,
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allow_private_addresses = self.allow_private_addresses;
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs = resolve_public(&host, allow_private_addresses).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+impl federation_reqwest::dns::Resolve for PublicResolver {
+    fn resolve(
+        &self,
+        name: federation_hyper::client::connect::dns::Name,
+    ) -> federation_reqwest::dns::Resolving {
+        let allow_private_addresses = self.allow_private_addresses;
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs = resolve_public(&host, allow_private_addresses).await?;
+            Ok(Box::new(addrs.into_iter()) as federation_reqwest::dns::Addrs)
+        })
+    }
+}
+
+async fn resolve_public(
+    host: &str,
+    allow_private_addresses: bool,
+) -> Result<Vec<SocketAddr>, FetchError> {
+    let addrs = tokio::net::lookup_host((host, 0))
+        .await
+        .map_err(|_| FetchError::Unresolvable(host.to_string()))?
+        .collect::<Vec<_>>();
+    if addrs.is_empty() {
+        return Err(FetchError::Unresolvable(host.to_string()));
+    }
+    check_addresses(host, &addrs, allow_private_addresses)?;
+    Ok(addrs)
+}
+
+/// The Versia server we bridge is trusted wherever it runs, including next to us on a
+/// private network
+pub fn is_upstream_host(host: &str) -> bool {
+    Url::parse(&format!("https://{}", *LYSAND_DOMAIN))
+        .ok()
+        .and_then(|url| {
+            url.host_str()
+                .map(|upstream| upstream.eq_ignore_ascii_case(host))
+        })
+        .unwrap_or(false)
+}
+
+fn check_addresses(
+    host: &str,
+    addrs: &[SocketAddr],
+    allow_private_addresses: bool,
+) -> Result<(), FetchError> {
+    if allow_private_addresses || is_upstream_host(host) {
+        return Ok(());
+    }
+    // a single private address is enough to refuse, so a host can't mix in an internal one
+    if addrs.iter().any(|addr| !is_public_ip(addr.ip())) {
+        return Err(FetchError::ForbiddenAddress(host.to_string()));
+    }
+    Ok(())
+}
+
+/// The checks that can be made on a url without resolving it
+fn check_url(url: &Url, allow_private_addresses: bool) -> Result<(), FetchError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(FetchError::UnsupportedScheme(url.clone()));
+    }
+    // literal addresses never reach the resolver
+    let ip = match url.host() {
+        Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+        Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+        Some(Host::Domain(_)) => return Ok(()),
+        None => return Err(FetchError::UnsupportedScheme(url.clone())),
+    };
+    let upstream = url.host_str().is_some_and(is_upstream_host);
+    if !allow_private_addresses && !upstream && !is_public_ip(ip) {
+        return Err(FetchError::ForbiddenAddress(ip.to_string()));
+    }
+    Ok(())
+}
+
+/// Whether a redirect to `url` may be followed after `previous` ones
+fn check_redirect(previous: usize, url: &Url, policy: &FetchPolicy) -> Result<(), FetchError> {
+    if previous > policy.max_redirects {
+        return Err(FetchError::Redirect);
+    }
+    check_url(url, policy.allow_private_addresses)
+}
+
+const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// activitypub_federation is still on reqwest 0.11, so its client is built separately
+fn federation_client_builder(policy: &FetchPolicy) -> federation_reqwest::ClientBuilder {
+    let redirect_policy = {
+        let policy = policy.clone();
+        federation_reqwest::redirect::Policy::custom(move |attempt| {
+            match check_redirect(attempt.previous().len(), attempt.url(), &policy) {
+                Ok(()) => attempt.follow(),
+                Err(err) => attempt.error(err),
+            }
+        })
+    };
+    federation_reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .dns_resolver(Arc::new(PublicResolver {
+            allow_private_addresses: policy.allow_private_addresses,
+        }))
+        .redirect(redirect_policy)
+        .timeout(policy.timeout)
+        .connect_timeout(policy.timeout)
+        .no_proxy()
+}
+
+/// HTTP client for everything remote servers point us at: actors, notes, media and inboxes
+pub struct Fetcher {
+    policy: FetchPolicy,
+    client: Client,
+}
+
+impl Fetcher {
+    pub fn new(policy: FetchPolicy) -> Fetcher {
+        let redirect_policy = {
+            let policy = policy.clone();
+            redirect::Policy::custom(move |attempt| {
+                match check_redirect(attempt.previous().len(), attempt.url(), &policy) {
+                    Ok(()) => attempt.follow(),
+                    Err(err) => attempt.error(err),
+                }
+            })
+        };
+        let client = Client::builder()
+            .user_agent(USER_AGENT)
+            .dns_resolver(Arc::new(PublicResolver {
+                allow_private_addresses: policy.allow_private_addresses,
+            }))
+            .redirect(redirect_policy)
+            .timeout(policy.timeout)
+            .connect_timeout(policy.timeout)
+            // a proxy would resolve names itself, past our resolver
+            .no_proxy()
+            .build()
+            .unwrap();
+        Fetcher { policy, client }
+    }
+
+    /// The underlying client, for requests that need more than a plain GET.
+    /// Urls have to be passed through [`Fetcher::check`] first.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// A client for activitypub_federation to fetch actors and objects with, held to the same
+    /// rules. The library caps response bodies itself.
+    pub fn federation_client(&self) -> ClientWithMiddleware {
+        ClientWithMiddleware::from(federation_client_builder(&self.policy).build().unwrap())
+    }
+
+    pub fn check(&self, url: &Url) -> Result<(), FetchError> {
+        check_url(url, self.policy.allow_private_addresses)
+    }
+
+    pub async fn get(&self, url: &Url) -> Result<Response, FetchError> {
+        self.check(url)?;
+        let response = self.client.get(url.clone()).send().await?;
+        if !response.status().is_success() {
+            return Err(FetchError::Status(response.status()));
+        }
+        Ok(response)
+    }
+
+    /// Reads a response body, giving up as soon as it exceeds the size limit
+    pub async fn read_body(&self, mut response: Response) -> Result<Vec<u8>, FetchError> {
+        let limit = self.policy.max_body_size;
+        if response
+            .content_length()
+            .is_some_and(|length| length as usize > limit)
+        {
+            return Err(FetchError::BodyTooLarge(limit));
+        }
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if body.len() + chunk.len() > limit {
+                return Err(FetchError::BodyTooLarge(limit));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+
+    pub async fn get_json<T: DeserializeOwned>(&self, url: &Url) -> Result<T, FetchError> {
+        let response = self.get(url).await?;
+        let body = self.read_body(response).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// The media type a remote file is served with, without downloading it
+    pub async fn media_type(&self, url: &Url) -> Result<Option<String>, FetchError> {
+        let response = self.get(url).await?;
+        Ok(response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string()))
+    }
+}
+
+static FETCHER: Lazy<Fetcher> = Lazy::new(|| Fetcher::new(FetchPolicy::default()));
+
+pub fn fetcher() -> &'static Fetcher {
+    &FETCHER
+}
+
+/// Applies the same address rules to fetches made by activitypub_federation, which brings
+/// its own client, and keeps it away from rejected domains
+#[derive(Clone)]
+pub struct PublicUrlVerifier;
+
+#[async_trait::async_trait]
+impl UrlVerifier for PublicUrlVerifier {
+    async fn verify(&self, url: &Url) -> Result<(), activitypub_federation::error::Error> {
+        let refused = || {
+            activitypub_federation::error::Error::UrlVerificationError(
+                "Url points to a non-public address",
+            )
+        };
+        if !federates_with(url) {
+            return Err(activitypub_federation::error::Error::UrlVerificationError(
+                "Domain is rejected by federation policy",
+            ));
+        }
+        check_url(url, false).map_err(|_| refused())?;
+        if let Some(Host::Domain(domain)) = url.host() {
+            resolve_public(domain, false).await.map_err(|_| refused())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_upstream_may_be_private() {
+        use super::{check_addresses, check_url, FetchError};
+        use crate::testing;
+        use std::net::SocketAddr;
+        use url::Url;
+
+        testing::env();
+        let loopback: SocketAddr = "127.0.0.1:443".parse().unwrap();
+        let private: SocketAddr = "10.0.0.2:443".parse().unwrap();
+
+        assert!(check_addresses(testing::LYSAND_DOMAIN, &[loopback], false).is_ok());
+        assert!(check_addresses("VERSIA.example", &[private], false).is_ok());
+        assert!(matches!(
+            check_addresses("remote.example", &[private], false),
+            Err(FetchError::ForbiddenAddress(_))
+        ));
+        assert!(check_addresses("remote.example", &[private], true).is_ok());
+        assert!(matches!(
+            check_url(&Url::parse("http://10.0.0.2/users/a").unwrap(), false),
+            Err(FetchError::ForbiddenAddress(_))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_fetcher_rules() {
+        use super::{FetchError, FetchPolicy, Fetcher};
+        use actix_web::{web, App, HttpResponse, HttpServer};
+        use std::time::Duration;
+        use url::Url;
+
+        // a stand-in for a remote server, listening on loopback
+        let server = HttpServer::new(|| {
+            App::new()
+                .route(
+                    "/note",
+                    web::get()
+                        .to(|| async { HttpResponse::Ok().json(serde_json::json!({"id": 1})) }),
+                )
+                .route(
+                    "/large",
+                    web::get().to(|| async { HttpResponse::Ok().body(vec![b'a'; 4096]) }),
+                )
+                .route(
+                    "/slow",
+                    web::get().to(|| async {
+                        tokio::time::sleep(Duration::from_secs(2)).await;
+                        HttpResponse::Ok().finish()
+                    }),
+                )
+                .route(
+                    "/loop",
+                    web::get().to(|| async {
+                        HttpResponse::Found()
+                            .insert_header(("Location", "/loop"))
+                            .finish()
+                    }),
+                )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let port = server.addrs()[0].port();
+        tokio::spawn(server.run());
+        let url = |path: &str| Url::parse(&format!("http://127.0.0.1:{port}{path}")).unwrap();
+        let named_url = |path: &str| Url::parse(&format!("http://localhost:{port}{path}")).unwrap();
+
+        let strict = Fetcher::new(FetchPolicy::default());
+        assert!(matches!(
+            strict.get(&url("/note")).await,
+            Err(FetchError::ForbiddenAddress(_))
+        ));
+        assert!(matches!(
+            strict.get(&named_url("/note")).await,
+            Err(FetchError::ForbiddenAddress(_))
+        ));
+        assert!(matches!(
+            strict.get(&Url::parse("file:///etc/passwd").unwrap()).await,
+            Err(FetchError::UnsupportedScheme(_))
+        ));
+
+        let local = Fetcher::new(FetchPolicy {
+            allow_private_addresses: true,
+            max_redirects: 3,
+            max_body_size: 1024,
+            timeout: Duration::from_millis(500),
+        });
+        let note: serde_json::Value = local.get_json(&named_url("/note")).await.unwrap();
+        assert_eq!(note["id"], 1);
+        assert!(matches!(
+            local.get_json::<serde_json::Value>(&url("/large")).await,
+            Err(FetchError::BodyTooLarge(1024))
+        ));
+        assert!(matches!(
+            local.get(&url("/slow")).await,
+            Err(FetchError::Timeout)
+        ));
+        assert!(matches!(
+            local.get(&url("/loop")).await,
+            Err(FetchError::Redirect)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_federation_client_refuses_loopback() {
+        use super::{federation_client_builder, FetchPolicy};
+        use activitypub_federation::{
+            config::{FederationConfig, UrlVerifier},
+            error::Error,
+            fetch::fetch_object_http,
+        };
+        use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+        use reqwest_middleware::ClientWithMiddleware;
+        use serde_json::{json, Value};
+        use std::{
+            net::SocketAddr,
+            sync::{
+                atomic::{AtomicBool, Ordering},
+                Arc,
+            },
+        };
+        use url::Url;
+
+        // as if the verifier had resolved the name to a public address
+        #[derive(Clone)]
+        struct AllowAll;
+
+        #[async_trait::async_trait]
+        impl UrlVerifier for AllowAll {
+            async fn verify(&self, _url: &Url) -> Result<(), Error> {
+                Ok(())
+            }
+        }
+
+        let reached = Arc::new(AtomicBool::new(false));
+        let server = {
+            let reached = reached.clone();
+            HttpServer::new(move || {
+                let reached = reached.clone();
+                App::new()
+                    .route(
+                        "/users/alice",
+                        web::get().to(|request: HttpRequest| async move {
+                            let id =
+                                format!("http://{}/users/alice", request.connection_info().host());
+                            HttpResponse::Ok()
+                                .content_type("application/activity+json")
+                                .body(json!({ "id": id, "type": "Person" }).to_string())
+                        }),
+                    )
+                    .route(
+                        "/users/{redirect}",
+                        web::get().to(|request: HttpRequest| async move {
+                            let port = request.app_config().local_addr().port();
+                            let host = match request.match_info().query("redirect") {
+                                "literal" => "127.0.0.1",
+                                _ => "localhost",
+                            };
+                            HttpResponse::Found()
+                                .insert_header((
+                                    "Location",
+                                    format!("http://{host}:{port}/internal"),
+                                ))
+                                .finish()
+                        }),
+                    )
+                    .route(
+                        "/internal",
+                        web::get().to(move || {
+                            reached.store(true, Ordering::SeqCst);
+                            async { HttpResponse::Ok().finish() }
+                        }),
+                    )
+            })
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap()
+        };
+        let port = server.addrs()[0].port();
+        tokio::spawn(server.run());
+
+        // remote.example stands in for a public server, so only the redirect leads to loopback
+        let client = federation_client_builder(&FetchPolicy::default())
+            .resolve("remote.example", SocketAddr::from(([127, 0, 0, 1], port)))
+            .build()
+            .unwrap();
+        let config = FederationConfig::builder()
+            .domain("bridge.example")
+            .app_data(())
+            .debug(true)
+            .client(ClientWithMiddleware::from(client))
+            .url_verifier(Box::new(AllowAll))
+            .build()
+            .await
+            .unwrap();
+        let fetch = |url: String| {
+            let data = config.to_request_data();
+            async move { fetch_object_http::<(), Value>(&Url::parse(&url).unwrap(), &data).await }
+        };
+
+        let alice = fetch(format!("http://remote.example:{port}/users/alice"))
+            .await
+            .unwrap();
+        assert_eq!(alice.object["type"], "Person");
+        assert!(fetch(format!("http://remote.example:{port}/users/literal"))
+            .await
+            .is_err());
+        assert!(fetch(format!("http://remote.example:{port}/users/named"))
+            .await
+            .is_err());
+        // a name that resolves to loopback by the time we connect
+        assert!(fetch(format!("http://localhost:{port}/users/alice"))
+            .await
+            .is_err());
+        assert!(!reached.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,274 @@
+//! Inbox forwarding (ActivityPub 7.1.2). Replies addressed to a bridged user's followers
+//! only reach the followers through the bridge, which owns that collection.
+
+use std::collections::HashSet;
+
+use sea_orm::{ColumnTrait, Condition, DbErr, EntityTrait, QueryFilter};
+use serde_json::Value;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::{
+    activities::create_post::follower_inbox,
+    dedup,
+    entities::{follow_relation, prelude, user},
+    http::is_bridge_host,
+    policy::deliverable,
+    queue::{enqueue, Protocol},
+    DB,
+};
+
+/// How deep embedded objects are searched for references to our objects
+const MAX_DEPTH: usize = 3;
+
+/// The ids in a property that may hold an id, an object or a list of either
+fn ids(value: Option<&Value>) -> Vec<&str> {
+    match value {
+        Some(Value::String(id)) => vec![id.as_str()],
+        Some(Value::Array(values)) => values.iter().flat_map(|value| ids(Some(value))).collect(),
+        // mentions and other links carry an href instead
+        Some(Value::Object(object)) => object
+            .get("id")
+            .or(object.get("href"))
+            .and_then(Value::as_str)
+            .into_iter()
+            .collect(),
+        _ => vec![],
+    }
+}
+
+fn embedded(value: Option<&Value>) -> Vec<&Value> {
+    match value {
+        Some(Value::Object(_)) => vec![value.unwrap()],
+        Some(Value::Array(values)) => values.iter().filter(|value| value.is_object()).collect(),
+        _ => vec![],
+    }
+}
+
+/// Everyone the activity and the object it carries are addressed to
+pub fn addressees(activity: &Value) -> Vec<&str> {
+    let mut addressees = vec![];
+    for object in [Some(activity), activity.get("object")]
+        .into_iter()
+        .flatten()
+    {
+        for field in ["to", "cc", "audience"] {
+            addressees.extend(ids(object.get(field)));
+        }
+    }
+    addressees
+}
+
+/// Whether the activity replies to, acts on, targets or tags an object that `owned` accepts,
+/// directly or through the objects it embeds
+pub fn references(value: &Value, owned: &impl Fn(&str) -> bool, depth: usize) -> bool {
+    if depth > MAX_DEPTH {
+        return false;
+    }
+    ["inReplyTo", "object", "target", "tag"]
+        .iter()
+        .any(|field| {
+            let property = value.get(field);
+            ids(property).into_iter().any(owned)
+                || embedded(property)
+                    .into_iter()
+                    .any(|object| references(object, owned, depth + 1))
+        })
+}
+
+fn is_bridge_url(id: &str) -> bool {
+    Url::parse(id)
+        .ok()
+        .and_then(|url| url.host_str().map(is_bridge_host))
+        .unwrap_or(false)
+}
+
+/// The bridged users whose followers collection is among the addressees
+async fn collection_owners(addressees: &[&str]) -> Result<Vec<user::Model>, DbErr> {
+    let mut ids = vec![];
+    let mut collections = vec![];
+    for addressee in addressees {
+        let Ok(url) = Url::parse(addressee) else {
+            continue;
+        };
+        let segments = url
+            .path_segments()
+            .map(|segments| segments.filter(|s| !s.is_empty()).collect::<Vec<_>>())
+            .unwrap_or_default();
+        match segments.as_slice() {
+            ["apbridge", "user", id, "followers"] if is_bridge_url(addressee) => {
+                ids.push(id.to_string())
+            }
+            // older bridged posts were addressed to the Versia collection
+            _ => collections.push(addressee.to_string()),
+        }
+    }
+    prelude::User::find()
+        .filter(user::Column::Local.eq(true))
+        .filter(
+            Condition::any()
+                .add(user::Column::Id.is_in(ids))
+                .add(user::Column::Followers.is_in(collections)),
+        )
+        .all(DB.get().unwrap())
+        .await
+}
+
+async fn follower_inboxes(owner: &user::Model) -> Result<Vec<Url>, DbErr> {
+    let relations = prelude::FollowRelation::find()
+        .filter(follow_relation::Column::FolloweeId.eq(owner.id.as_str()))
+        .filter(follow_relation::Column::AcceptId.is_not_null())
+        .filter(follow_relation::Column::FollowerInboxGoneAt.is_null())
+        .all(DB.get().unwrap())
+        .await?;
+    let mut inboxes = vec![];
+    for relation in relations {
+        match follower_inbox(relation).await {
+            Ok(inbox) => inboxes.push(inbox),
+            Err(err) => warn!("Not forwarding to a follower of {}: {}", owner.url, err),
+        }
+    }
+    Ok(inboxes)
+}
+
+/// Forwards a received activity to the followers of the bridged users whose followers it
+/// is addressed to, if it is about one of our objects. The body is passed on untouched, so
+/// an integrity proof or LD signature in it still authenticates the original actor. The
+/// HTTP signature is the collection owner's, since the original one covers our inbox only.
+pub async fn forward(activity: &Value, body: &[u8]) -> anyhow::Result<()> {
+    let addressees = addressees(activity);
+    if addressees.is_empty() || !references(activity, &is_bridge_url, 0) {
+        return Ok(());
+    }
+    let owners = collection_owners(&addressees).await?;
+    let Some(id) = activity.get("id").and_then(Value::as_str) else {
+        return Ok(());
+    };
+    if owners.is_empty() || !dedup::claim(&format!("forward:{id}")).await? {
+        return Ok(());
+    }
+    let body = String::from_utf8(body.to_vec())?;
+    // the sender's server has the activity already
+    let sender_host = activity
+        .get("actor")
+        .and_then(Value::as_str)
+        .and_then(|actor| Url::parse(actor).ok())
+        .and_then(|actor| actor.host_str().map(str::to_string));
+    let mut forwarded = HashSet::new();
+    for owner in owners {
+        let mut inboxes = follower_inboxes(&owner).await?;
+        inboxes.retain(|inbox| {
+            inbox.host_str().map(str::to_string) != sender_host && forwarded.insert(inbox.clone())
+        });
+        let inboxes = deliverable(inboxes);
+        if inboxes.is_empty() {
+            continue;
+        }
+        info!(
+            "Forwarding {} to {} inboxes of {}'s followers",
+            id,
+            inboxes.len(),
+            owner.username
+        );
+        enqueue(Protocol::ActivityPub, inboxes, body.clone(), &owner).await?;
+    }
+    dedup::mark_processed(&format!("forward:{id}")).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_forwarding_references() {
+        use super::{addressees, references};
+        use serde_json::json;
+
+        let owned = |id: &str| id.starts_with("https://bridge.example/");
+        let reply = json!({
+            "type": "Create",
+            "actor": "https://remote.example/users/a",
+            "to": ["https://www.w3.org/ns/activitystreams#Public"],
+            "object": {
+                "type": "Note",
+                "inReplyTo": "https://bridge.example/apbridge/object/1",
+                "cc": ["https://bridge.example/apbridge/user/2/followers"],
+            },
+        });
+        assert!(references(&reply, &owned, 0));
+        assert!(addressees(&reply).contains(&"https://bridge.example/apbridge/user/2/followers"));
+
+        let unrelated = json!({
+            "type": "Create",
+            "object": { "type": "Note", "inReplyTo": "https://remote.example/notes/1" },
+        });
+        assert!(!references(&unrelated, &owned, 0));
+
+        let mention = json!({
+            "type": "Note",
+            "tag": [{ "type": "Mention", "href": "https://bridge.example/apbridge/user/2" }],
+        });
+        assert!(references(&mention, &owned, 0));
+    }
+
+    #[tokio::test]
+    async fn test_forward_to_followers() {
+        use super::forward;
+        use crate::{
+            activities::follow::{save_follow, Follow},
+            entities::{delivery_job, follow_relation, prelude},
+            testing,
+        };
+        use activitystreams_kinds::activity::FollowType;
+        use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, Set};
+        use serde_json::json;
+
+        let db = testing::db().await;
+        let owner = testing::user("forward-owner", true).await;
+        let follower = testing::user("forward-follower", false).await;
+        let follow = Follow {
+            actor: url::Url::parse(&follower.url).unwrap().into(),
+            object: url::Url::parse("https://bridge.example/apbridge/user/forward-owner")
+                .unwrap()
+                .into(),
+            kind: FollowType::Follow,
+            id: url::Url::parse("https://remote.example/follows/forward").unwrap(),
+        };
+        let relation = save_follow(&follower, &owner, &follow).await.unwrap();
+        // a second delivery of the follow doesn't add a follower
+        save_follow(&follower, &owner, &follow).await.unwrap();
+        // as the Versia instance would answer
+        follow_relation::ActiveModel {
+            id: Set(relation.id),
+            accept_id: Set(Some("forward-accept".to_string())),
+            ..Default::default()
+        }
+        .update(db)
+        .await
+        .unwrap();
+
+        let reply = json!({
+            "id": "https://third.example/activities/forward",
+            "type": "Create",
+            "actor": "https://third.example/users/c",
+            "to": ["https://www.w3.org/ns/activitystreams#Public"],
+            "object": {
+                "type": "Note",
+                "inReplyTo": "https://bridge.example/apbridge/object/1",
+                "cc": ["https://bridge.example/apbridge/user/forward-owner/followers"],
+            },
+        });
+        let body = serde_json::to_vec(&reply).unwrap();
+        forward(&reply, &body).await.unwrap();
+        // forwarded once only
+        forward(&reply, &body).await.unwrap();
+
+        let jobs = prelude::DeliveryJob::find()
+            .filter(delivery_job::Column::SignerId.eq(owner.id.as_str()))
+            .all(db)
+            .await
+            .unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].inbox, follower.inbox);
+        assert_eq!(jobs[0].body, String::from_utf8(body).unwrap());
+    }
+}
@@ -1,29 +1,43 @@
 use crate::{
     database::StateHandle,
-    entities::user,
+    entities::{prelude, user},
     error::Error,
+    forwarding::forward,
+    http_signatures::{signature_key_id, verify_fetch_signature},
+    integrity_proofs::{actor_context, has_proof, verify_activity_proof},
+    limits::{limit_inbox_rate, limit_signer_rate, read_inbox_body},
+    message_signatures::{has_message_signature, verify_message_signature},
     objects::person::{DbUser, PersonAcceptedActivities},
-    utils::generate_user_id,
+    policy::policy_for,
+    service_actor,
+    utils::generate_versia_profile_url,
     versia::{
         self,
         conversion::{db_user_from_url, local_db_user_from_name, receive_versia_note},
     },
-    API_DOMAIN, LYSAND_DOMAIN,
+    API_DOMAIN, AUTHORIZED_FETCH, DB, FEDERATED_DOMAIN, LYSAND_DOMAIN, SUBSCRIBE_TEMPLATE,
+    USERNAME,
 };
 use activitypub_federation::{
     actix_web::{inbox::receive_activity, signing_actor},
     config::{Data, FederationConfig, FederationMiddleware},
-    fetch::webfinger::{build_webfinger_response, extract_webfinger_name, WebFingerError},
-    protocol::context::WithContext,
-    traits::{Actor, Object},
+    fetch::webfinger::{
+        build_webfinger_response, WebFingerError, WebfingerLink, WEBFINGER_CONTENT_TYPE,
+    },
+    protocol::{context::WithContext, verification::verify_domains_match},
+    traits::{ActivityHandler, Actor, Object},
     FEDERATION_CONTENT_TYPE,
 };
-use actix_web::{web, web::Bytes, App, HttpRequest, HttpResponse, HttpServer};
+use actix_web::{
+    http::header::{ACCEPT, LOCATION},
+    web, App, HttpRequest, HttpResponse, HttpServer,
+};
 use anyhow::anyhow;
 use once_cell::sync::Lazy;
 use regex::Regex;
-use serde::Deserialize;
-use tracing::info;
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
 use url::Url;
 use webfinger::resolve;
 
@@ -62,41 +76,210 @@ pub fn versia_inbox(
         .json(WithContext::new_default(json_user)))
 }*/
 
+/// Why a fetch of a bridged resource was refused
+#[derive(Debug, thiserror::Error)]
+pub enum FetchRefusal {
+    #[error("Authorized fetch is enabled, requests must be signed")]
+    Unsigned,
+    #[error("Invalid signature: {0}")]
+    InvalidSignature(String),
+    #[error("Domain {0} is blocked")]
+    Blocked(String),
+}
+
+impl FetchRefusal {
+    pub fn response(&self) -> HttpResponse {
+        match self {
+            FetchRefusal::Blocked(_) => HttpResponse::Forbidden().body(self.to_string()),
+            _ => HttpResponse::Unauthorized().body(self.to_string()),
+        }
+    }
+}
+
+/// Returns the actor behind a signed fetch.
+///
+/// Unsigned requests and requests with a broken signature are only let through, as
+/// anonymous, while authorized fetch is disabled.
+pub async fn fetch_signer(
+    request: &HttpRequest,
+    data: &Data<StateHandle>,
+) -> Result<Option<user::Model>, FetchRefusal> {
+    let Some(key_id) = signature_key_id(request) else {
+        return match *AUTHORIZED_FETCH {
+            true => Err(FetchRefusal::Unsigned),
+            false => Ok(None),
+        };
+    };
+    if let Some(host) = key_id.host_str().filter(|host| policy_for(host).reject) {
+        return Err(FetchRefusal::Blocked(host.to_string()));
+    }
+    let verified = match has_message_signature(request) {
+        true => verify_message_signature(request, &[], data).await,
+        false => verify_fetch_signature(request, data).await,
+    };
+    match verified {
+        Ok(actor) => {
+            info!("Fetch of {} is signed by {}", request.path(), actor.url);
+            Ok(Some(actor))
+        }
+        Err(err) if *AUTHORIZED_FETCH => Err(FetchRefusal::InvalidSignature(err.to_string())),
+        Err(err) => {
+            info!("Ignoring invalid signature on {}: {}", request.path(), err);
+            Ok(None)
+        }
+    }
+}
+
 /// Handles requests to fetch user json over HTTP
 pub async fn http_get_user(
     request: HttpRequest,
     user_name: web::Path<String>,
     data: Data<StateHandle>,
 ) -> Result<HttpResponse, Error> {
-    //let signed_by = signing_actor::<DbUser>(&request, None, &data).await?;
-    // here, checks can be made on the actor or the domain to which
-    // it belongs, to verify whether it is allowed to access this resource
-    //info!(
-    //    "Fetch user request is signed by system account {}",
-    //    signed_by.id()
-    //);
-
-    let db_user = data.local_user().await?;
-    if user_name.into_inner() == db_user.username {
-        let json_user = db_user.into_json(&data).await?;
-        Ok(HttpResponse::Ok()
-            .content_type(FEDERATION_CONTENT_TYPE)
-            .json(WithContext::new_default(json_user)))
-    } else {
-        Err(anyhow!("Invalid user").into())
+    let db_user = match data.read_user(&user_name).await? {
+        Some(db_user) => db_user,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    // the service actor signs our own fetches, so it has to stay reachable for everyone
+    if db_user.username != USERNAME.as_str() {
+        if let Err(refusal) = fetch_signer(&request, &data).await {
+            return Ok(refusal.response());
+        }
     }
+
+    if !accepts_activity_json(&request) {
+        let profile_page = generate_versia_profile_url(&LYSAND_DOMAIN, &db_user.username)?;
+        return Ok(HttpResponse::Found()
+            .insert_header((LOCATION, profile_page.to_string()))
+            .finish());
+    }
+
+    let json_user = db_user.into_json(&data).await?;
+    Ok(HttpResponse::Ok()
+        .content_type(FEDERATION_CONTENT_TYPE)
+        .json(WithContext::new(json_user, actor_context())))
+}
+
+/// Whether the client asked for ActivityStreams rather than a web page
+fn accepts_activity_json(request: &HttpRequest) -> bool {
+    request
+        .headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| {
+            accept.contains("application/activity+json") || accept.contains("application/ld+json")
+        })
 }
 
 /// Handles messages received in user inbox
 pub async fn http_post_user_inbox(
     request: HttpRequest,
-    body: Bytes,
+    user_name: web::Path<String>,
+    payload: web::Payload,
     data: Data<StateHandle>,
 ) -> Result<HttpResponse, Error> {
-    receive_activity::<WithContext<PersonAcceptedActivities>, user::Model, StateHandle>(
-        request, body, &data,
-    )
-    .await
+    if let Some(limited) = limit_inbox_rate(&request, "activitypub") {
+        return Ok(limited);
+    }
+    let known_user = match data.read_user(&user_name).await? {
+        Some(_) => true,
+        None => prelude::User::find_by_id(user_name.as_str())
+            .filter(user::Column::Local.eq(true))
+            .one(data.database_connection.as_ref())
+            .await?
+            .is_some(),
+    };
+    if !known_user {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    let body = match read_inbox_body(&request, payload, "activitypub").await {
+        Ok(body) => body,
+        Err(refused) => return Ok(refused),
+    };
+
+    // checked before receive_activity, which would fetch the actor to verify the signature
+    let activity = serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default();
+    let actor = activity
+        .get("actor")
+        .and_then(|actor| actor.as_str())
+        .and_then(|actor| Url::parse(actor).ok());
+    let Some(actor_host) = actor.as_ref().and_then(|actor| actor.host_str()) else {
+        return Ok(HttpResponse::BadRequest().body("Activity has no actor"));
+    };
+    let policy = policy_for(actor_host);
+    if policy.reject {
+        return Ok(HttpResponse::Forbidden().body(format!("Domain {actor_host} is blocked")));
+    }
+    if policy.reject_reports && activity.get("type").and_then(|kind| kind.as_str()) == Some("Flag")
+    {
+        info!("Dropping report from {}", actor_host);
+        return Ok(HttpResponse::Accepted().finish());
+    }
+
+    // a valid proof authenticates the activity on its own, also when it was forwarded
+    // by someone else and the HTTP signature is theirs
+    let proof_signer = if has_proof(&activity) {
+        match verify_activity_proof(&activity, &data).await {
+            Ok(signer) => signer,
+            Err(err) => return Ok(HttpResponse::Unauthorized().body(err.to_string())),
+        }
+    } else {
+        None
+    };
+    // receive_activity verifies draft-cavage signatures itself, but doesn't tell who signed
+    let cavage = proof_signer.is_none() && !has_message_signature(&request);
+    let verified = match proof_signer {
+        Some(signer) => Ok(signer),
+        None if cavage => signing_actor::<user::Model>(&request, Some(body.clone()), &data).await,
+        None => verify_message_signature(&request, &body, &data).await,
+    };
+    let signer = match verified {
+        Ok(signer) => signer,
+        Err(err) => return Ok(HttpResponse::Unauthorized().body(err.to_string())),
+    };
+    if let Some(limited) = limit_signer_rate(&Url::parse(&signer.url)?, "activitypub") {
+        return Ok(limited);
+    }
+    let response = match cavage {
+        true => {
+            receive_activity::<WithContext<PersonAcceptedActivities>, user::Model, StateHandle>(
+                request,
+                body.clone(),
+                &data,
+            )
+            .await?
+        }
+        false => receive_verified_activity(&body, &signer, &data).await?,
+    };
+    if response.status().is_success() {
+        if let Err(err) = forward(&activity, &body).await {
+            warn!("Could not forward activity: {}", err);
+        }
+    }
+    Ok(response)
+}
+
+/// Verifies and receives an activity whose sender has been authenticated as `signer`
+async fn receive_verified_activity(
+    body: &[u8],
+    signer: &user::Model,
+    data: &Data<StateHandle>,
+) -> Result<HttpResponse, Error> {
+    let activity = match serde_json::from_slice::<WithContext<PersonAcceptedActivities>>(body) {
+        Ok(activity) => activity,
+        Err(err) => return Ok(HttpResponse::BadRequest().body(err.to_string())),
+    };
+    if activity.actor().as_str() != signer.url {
+        return Ok(HttpResponse::Unauthorized().body(format!(
+            "Signer {} is not the actor of the activity",
+            signer.url
+        )));
+    }
+    verify_domains_match(activity.id(), activity.actor())?;
+    activity.verify(data).await?;
+    activity.receive(data).await?;
+    Ok(HttpResponse::Ok().finish())
 }
 
 #[derive(Deserialize)]
@@ -110,14 +293,375 @@ pub async fn webfinger(
 ) -> Result<HttpResponse, Error> {
     static WEBFINGER_REGEX: Lazy<Regex> =
         Lazy::new(|| Regex::new(r"^acct:([\p{L}0-9_\.\-]+)@(.*)$").expect("compile regex"));
-    let captures = WEBFINGER_REGEX
-        .captures(&query.resource)
-        .ok_or(WebFingerError::WrongFormat)?;
-    let account_name = captures.get(1).ok_or(WebFingerError::WrongFormat)?;
-    let name = account_name.as_str();
-    let user = local_db_user_from_name(name.to_string()).await?;
-    Ok(HttpResponse::Ok().json(build_webfinger_response(
-        query.resource.clone(),
-        generate_user_id(&API_DOMAIN, &user.id)?,
-    )))
+    let user = if let Some(captures) = WEBFINGER_REGEX.captures(&query.resource) {
+        let account_name = captures.get(1).ok_or(WebFingerError::WrongFormat)?;
+        let host = captures.get(2).ok_or(WebFingerError::WrongFormat)?;
+        if !is_bridge_host(host.as_str()) {
+            return Ok(HttpResponse::NotFound().finish());
+        }
+        local_db_user_from_name(account_name.as_str().to_string()).await
+    } else if let Ok(url) = Url::parse(&query.resource) {
+        if !url.host_str().is_some_and(is_bridge_host) {
+            return Ok(HttpResponse::NotFound().finish());
+        }
+        local_db_user_from_url(&url).await
+    } else {
+        return Err(WebFingerError::WrongFormat.into());
+    };
+    let user = match user {
+        Ok(user) => user,
+        Err(err) => {
+            info!("Webfinger lookup for {} failed: {}", query.resource, err);
+            return Ok(HttpResponse::NotFound().finish());
+        }
+    };
+
+    let actor_id = Actor::id(&user);
+    let profile_page = generate_versia_profile_url(&LYSAND_DOMAIN, &user.username)?;
+    let mut response = build_webfinger_response(
+        format!("acct:{}@{}", user.username, FEDERATED_DOMAIN.as_str()),
+        actor_id.clone(),
+    );
+    response.aliases = vec![actor_id, profile_page.clone()];
+    // the library's profile page is the actor itself, browsers belong on the Versia profile
+    for link in &mut response.links {
+        if link.rel.as_deref() == Some("http://webfinger.net/rel/profile-page") {
+            link.href = Some(profile_page.clone());
+        }
+    }
+    response.links.push(WebfingerLink {
+        rel: Some("http://ostatus.org/schema/1.0/subscribe".to_string()),
+        template: Some(SUBSCRIBE_TEMPLATE.to_string()),
+        ..Default::default()
+    });
+    Ok(HttpResponse::Ok()
+        .content_type(WEBFINGER_CONTENT_TYPE.clone())
+        .json(response))
+}
+
+#[derive(Serialize)]
+struct HostMeta {
+    links: Vec<HostMetaLink>,
+}
+
+#[derive(Serialize)]
+struct HostMetaLink {
+    rel: String,
+    template: String,
+}
+
+fn webfinger_template() -> String {
+    format!(
+        "https://{}/.well-known/webfinger?resource={{uri}}",
+        FEDERATED_DOMAIN.as_str()
+    )
+}
+
+/// Redirects host-meta lookups made against the federated domain to the api domain,
+/// for setups where both differ and only the former is proxied to us
+fn host_meta_redirect(request: &HttpRequest) -> Option<HttpResponse> {
+    let connection_info = request.connection_info();
+    let host = connection_info.host();
+    if API_DOMAIN.as_str() == FEDERATED_DOMAIN.as_str()
+        || host.eq_ignore_ascii_case(API_DOMAIN.as_str())
+    {
+        return None;
+    }
+    Some(
+        HttpResponse::MovedPermanently()
+            .insert_header((
+                LOCATION,
+                format!("https://{}{}", API_DOMAIN.as_str(), request.uri()),
+            ))
+            .finish(),
+    )
+}
+
+/// XRD host-meta, still used by Friendica and older Pleroma for account discovery
+pub async fn host_meta(request: HttpRequest) -> Result<HttpResponse, Error> {
+    if let Some(redirect) = host_meta_redirect(&request) {
+        return Ok(redirect);
+    }
+    let xrd = format!(
+        concat!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+            "\n",
+            r#"<XRD xmlns="http://docs.oasis-open.org/ns/xri/xrd-1.0">"#,
+            "\n",
+            r#"  <Link rel="lrdd" type="application/xrd+xml" template="{}"/>"#,
+            "\n",
+            "</XRD>\n"
+        ),
+        webfinger_template()
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("application/xrd+xml; charset=utf-8")
+        .body(xrd))
+}
+
+/// JSON flavour of host-meta
+pub async fn host_meta_json(request: HttpRequest) -> Result<HttpResponse, Error> {
+    if let Some(redirect) = host_meta_redirect(&request) {
+        return Ok(redirect);
+    }
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(HostMeta {
+            links: vec![HostMetaLink {
+                rel: "lrdd".to_string(),
+                template: webfinger_template(),
+            }],
+        }))
+}
+
+/// Whether webfinger and friends should answer for this host
+pub fn is_bridge_host(host: &str) -> bool {
+    host.eq_ignore_ascii_case(FEDERATED_DOMAIN.as_str())
+        || host.eq_ignore_ascii_case(API_DOMAIN.as_str())
+}
+
+/// Resolves the actor urls we hand out (`/apbridge/user/{id}`, `/{username}` and `/actor`)
+pub async fn local_db_user_from_url(url: &Url) -> anyhow::Result<user::Model> {
+    let segments = url
+        .path_segments()
+        .map(|segments| segments.filter(|s| !s.is_empty()).collect::<Vec<_>>())
+        .unwrap_or_default();
+    match segments.as_slice() {
+        ["apbridge", "user", id] => prelude::User::find_by_id(id.to_string())
+            .filter(user::Column::Local.eq(true))
+            .one(DB.get().unwrap())
+            .await?
+            .ok_or(anyhow!("Unknown user {id}")),
+        ["actor"] => service_actor::find().await,
+        [name] => local_db_user_from_name(name.to_string()).await,
+        _ => Err(anyhow!("Not an actor url: {url}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[actix_web::test]
+    async fn test_webfinger() {
+        use super::webfinger;
+        use crate::testing;
+        use activitypub_federation::config::FederationMiddleware;
+        use actix_web::{http::StatusCode, test, web, App};
+        use serde_json::Value;
+
+        testing::user("webfinger-user", true).await;
+        let app = test::init_service(
+            App::new()
+                .wrap(FederationMiddleware::new(testing::federation().await))
+                .route("/.well-known/webfinger", web::get().to(webfinger)),
+        )
+        .await;
+        let lookup = |resource: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/.well-known/webfinger?resource={resource}"))
+                .to_request()
+        };
+
+        let response = test::call_service(&app, lookup("acct:webfinger-user@bridge.example")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let content_type = response.headers().get("content-type").unwrap();
+        assert!(content_type
+            .to_str()
+            .unwrap()
+            .starts_with("application/jrd+json"));
+        let jrd: Value = test::read_body_json(response).await;
+        assert_eq!(jrd["subject"], "acct:webfinger-user@social.example");
+        assert_eq!(
+            jrd["aliases"],
+            serde_json::json!([
+                "https://bridge.example/apbridge/user/webfinger-user",
+                "https://versia.example/@webfinger-user",
+            ])
+        );
+        let link = |rel: &str| {
+            jrd["links"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|link| link["rel"] == rel)
+                .cloned()
+                .unwrap()
+        };
+        assert_eq!(
+            link("self")["href"],
+            "https://bridge.example/apbridge/user/webfinger-user"
+        );
+        assert_eq!(
+            link("http://webfinger.net/rel/profile-page")["href"],
+            "https://versia.example/@webfinger-user"
+        );
+        let profile_pages = jrd["links"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|link| link["rel"] == "http://webfinger.net/rel/profile-page")
+            .count();
+        assert_eq!(profile_pages, 1);
+        assert_eq!(
+            link("http://ostatus.org/schema/1.0/subscribe")["template"],
+            "https://versia.example/authorize_interaction?uri={uri}"
+        );
+
+        // both of our domains, in any case, by account or by actor url
+        for resource in [
+            "acct:webfinger-user@social.example",
+            "acct:webfinger-user@Bridge.Example",
+            "https://bridge.example/apbridge/user/webfinger-user",
+            "https://social.example/webfinger-user",
+        ] {
+            let response = test::call_service(&app, lookup(resource)).await;
+            assert_eq!(response.status(), StatusCode::OK, "{resource}");
+        }
+        for resource in [
+            "acct:webfinger-user@other.example",
+            "https://other.example/apbridge/user/webfinger-user",
+            "https://bridge.example/apbridge/user/nobody",
+        ] {
+            let response = test::call_service(&app, lookup(resource)).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{resource}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_host_meta_redirect() {
+        use super::{host_meta, host_meta_json};
+        use crate::testing;
+        use actix_web::{
+            http::{header, StatusCode},
+            test, web, App,
+        };
+        use serde_json::Value;
+
+        testing::env();
+        let app = test::init_service(
+            App::new()
+                .route("/.well-known/host-meta", web::get().to(host_meta))
+                .route("/.well-known/host-meta.json", web::get().to(host_meta_json)),
+        )
+        .await;
+        let request = |path: &str, host: &str| {
+            test::TestRequest::get()
+                .uri(path)
+                .insert_header((header::HOST, host))
+                .to_request()
+        };
+
+        // asked on the federated domain, answered on the api domain
+        for path in ["/.well-known/host-meta", "/.well-known/host-meta.json?x=1"] {
+            let response = test::call_service(&app, request(path, "social.example")).await;
+            assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+            assert_eq!(
+                response.headers().get(header::LOCATION).unwrap(),
+                format!("https://bridge.example{path}").as_str()
+            );
+        }
+
+        let template = "https://social.example/.well-known/webfinger?resource={uri}";
+        let response =
+            test::call_service(&app, request("/.well-known/host-meta", "BRIDGE.EXAMPLE")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let xrd = test::read_body(response).await;
+        assert!(String::from_utf8(xrd.to_vec()).unwrap().contains(template));
+
+        let response = test::call_service(
+            &app,
+            request("/.well-known/host-meta.json", "bridge.example"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let json: Value = test::read_body_json(response).await;
+        assert_eq!(json["links"][0]["rel"], "lrdd");
+        assert_eq!(json["links"][0]["template"], template);
+    }
+
+    #[actix_web::test]
+    async fn test_http_get_user() {
+        use super::http_get_user;
+        use crate::{entities::user, testing};
+        use activitypub_federation::config::FederationMiddleware;
+        use actix_web::{
+            http::{header, StatusCode},
+            test, web, App,
+        };
+        use sea_orm::{ActiveModelTrait, Set};
+        use serde_json::{json, Value};
+
+        let local = testing::user("get-user-local", true).await;
+        testing::user("get-user-remote", false).await;
+        // the profile as stored when the user is bridged
+        let actor_id = "https://bridge.example/apbridge/user/get-user-local";
+        let inbox = "https://bridge.example/apbridge/user/get-user-local/inbox";
+        let public_key = json!({
+            "id": format!("{actor_id}#main-key"),
+            "owner": actor_id,
+            "publicKeyPem": "-----BEGIN PUBLIC KEY-----\n-----END PUBLIC KEY-----\n",
+        });
+        user::ActiveModel {
+            id: Set(local.id),
+            ap_json: Set(Some(
+                json!({
+                    "type": "Person",
+                    "id": actor_id,
+                    "preferredUsername": "get-user-local",
+                    "name": "get-user-local",
+                    "url": local.url,
+                    "inbox": inbox,
+                    "publicKey": public_key,
+                })
+                .to_string(),
+            )),
+            ..Default::default()
+        }
+        .update(testing::db().await)
+        .await
+        .unwrap();
+        let app = test::init_service(
+            App::new()
+                .wrap(FederationMiddleware::new(testing::federation().await))
+                .route("/{user}", web::get().to(http_get_user)),
+        )
+        .await;
+        let get = |path: &str, accept: &str| {
+            test::TestRequest::get()
+                .uri(path)
+                .insert_header((header::ACCEPT, accept))
+                .to_request()
+        };
+
+        let response =
+            test::call_service(&app, get("/get-user-local", "application/activity+json")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let content_type = response.headers().get(header::CONTENT_TYPE).unwrap();
+        assert!(content_type
+            .to_str()
+            .unwrap()
+            .starts_with("application/activity+json"));
+        let person: Value = test::read_body_json(response).await;
+        assert_eq!(person["type"], "Person");
+        assert_eq!(person["id"], actor_id);
+        assert_eq!(person["inbox"], inbox);
+        assert_eq!(person["publicKey"], public_key);
+        assert_eq!(person["followers"], format!("{actor_id}/followers"));
+        // the key integrity proofs are checked against
+        assert_eq!(person["assertionMethod"][0]["controller"], actor_id);
+
+        for path in ["/get-user-unknown", "/get-user-remote"] {
+            let response = test::call_service(&app, get(path, "application/activity+json")).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{path}");
+        }
+
+        // browsers are sent to the profile on the Versia instance
+        for accept in ["text/html", "*/*"] {
+            let response = test::call_service(&app, get("/get-user-local", accept)).await;
+            assert_eq!(response.status(), StatusCode::FOUND, "{accept}");
+            assert_eq!(
+                response.headers().get(header::LOCATION).unwrap(),
+                "https://versia.example/@get-user-local"
+            );
+        }
+    }
 }
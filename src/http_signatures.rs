@@ -0,0 +1,283 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use activitypub_federation::{
+    config::Data, fetch::object_id::ObjectId, protocol::public_key::PublicKey, traits::Actor,
+};
+use actix_web::HttpRequest;
+use anyhow::anyhow;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http_signature_normalization::Config;
+use once_cell::sync::Lazy;
+use rsa::{
+    pkcs1::DecodeRsaPrivateKey,
+    pkcs8::{DecodePrivateKey, DecodePublicKey},
+    Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::{
+    database::StateHandle,
+    entities::user,
+    error::Error,
+    fetcher::fetcher,
+    message_signatures::{parse_signature_input, SIGNATURE_INPUT_HEADER},
+    objects::person::Person,
+};
+
+/// Same validity window activitypub_federation uses for the signatures it makes
+const EXPIRES_AFTER: Duration = Duration::from_secs(60 * 60);
+
+static FETCH_CONFIG: Lazy<Config> = Lazy::new(|| {
+    Config::new()
+        .mastodon_compat()
+        .set_expiration(EXPIRES_AFTER)
+});
+
+/// The key id of a draft-cavage `Signature` header, or of the first RFC 9421 signature,
+/// without verifying anything
+pub fn signature_key_id(request: &HttpRequest) -> Option<Url> {
+    if let Some(input) = request.headers().get(SIGNATURE_INPUT_HEADER) {
+        let inputs = parse_signature_input(input.to_str().ok()?).ok()?;
+        let key_id = inputs.iter().find_map(|(_, params)| params.key_id())?;
+        return Url::parse(key_id).ok();
+    }
+    let header = request.headers().get("Signature")?.to_str().ok()?;
+    let key_id = header
+        .split(',')
+        .find_map(|part| part.trim().strip_prefix("keyId="))?;
+    Url::parse(key_id.trim_matches('"')).ok()
+}
+
+/// A fetched key id: the key itself, or an actor or actor stub publishing it
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KeyDocument {
+    id: Url,
+    owner: Option<Url>,
+    public_key: Option<PublicKey>,
+}
+
+/// Who a key document says owns `key_id`, if it is about that key at all
+fn document_owner(document: KeyDocument, key_id: &Url) -> Option<Url> {
+    match document.public_key {
+        Some(key) if key.id == key_id.as_str() => Some(key.owner),
+        _ if document.id == *key_id => document.owner,
+        _ => None,
+    }
+}
+
+/// Whether `actor` publishes `key_id` as its own key
+fn publishes_key(actor: &user::Model, key_id: &Url) -> bool {
+    actor
+        .ap_json
+        .as_ref()
+        .and_then(|json| serde_json::from_str::<Person>(json).ok())
+        .is_some_and(|person| {
+            person.public_key.id == key_id.as_str()
+                && person.public_key.owner == *person.id.inner()
+                && person.public_key.owner.as_str() == actor.url
+        })
+}
+
+/// The actor that owns the key `key_id`. Mastodon and most others make the key a fragment
+/// of the actor (`https://example.com/users/alice#main-key`), GoToSocial and a few others
+/// give it a document of its own (`https://example.com/users/alice/main-key`), which names
+/// the owner. Either way the owner has to publish the key itself.
+pub async fn key_owner(key_id: &Url, data: &Data<StateHandle>) -> Result<user::Model, Error> {
+    let owner = match key_id.fragment() {
+        Some(_) => {
+            let mut owner = key_id.clone();
+            owner.set_fragment(None);
+            owner
+        }
+        None => {
+            let document = fetcher().get_json::<KeyDocument>(key_id).await?;
+            document_owner(document, key_id)
+                .ok_or(anyhow!("{key_id} does not name the owner of the key"))?
+        }
+    };
+    let owner = ObjectId::<user::Model>::from(owner);
+    let actor = owner.dereference(data).await?;
+    if publishes_key(&actor, key_id) {
+        return Ok(actor);
+    }
+    // the stored copy may predate a key rotation
+    let actor = owner.dereference_forced(data).await?;
+    if !publishes_key(&actor, key_id) {
+        return Err(anyhow!("{} does not publish the key {}", actor.url, key_id).into());
+    }
+    Ok(actor)
+}
+
+/// Verifies the draft-cavage signature of a request without body, as sent for signed fetches,
+/// and returns the signing actor.
+///
+/// `activitypub_federation::actix_web::signing_actor` insists on a `Digest` header, which
+/// GET requests don't carry.
+pub async fn verify_fetch_signature(
+    request: &HttpRequest,
+    data: &Data<StateHandle>,
+) -> Result<user::Model, Error> {
+    let headers = request
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect::<BTreeMap<_, _>>();
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    let unverified = FETCH_CONFIG
+        .begin_verify(request.method().as_str(), path, headers)
+        .map_err(|e| anyhow!("Unusable signature: {e}"))?;
+
+    let actor = key_owner(&Url::parse(unverified.key_id())?, data).await?;
+
+    let public_key = RsaPublicKey::from_public_key_pem(actor.public_key_pem())?;
+    let signature = STANDARD.decode(unverified.signature())?;
+    public_key
+        .verify(
+            Pkcs1v15Sign::new::<Sha256>(),
+            &Sha256::digest(unverified.signing_string().as_bytes()),
+            &signature,
+        )
+        .map_err(|_| anyhow!("Signature does not match"))?;
+
+    Ok(actor)
+}
+
+/// The RSA key an actor signs its activities with
+pub fn actor_private_key(actor: &user::Model) -> anyhow::Result<RsaPrivateKey> {
+    let pem = actor
+        .private_key_pem()
+        .ok_or(anyhow!("{} has no private key", actor.id))?;
+    RsaPrivateKey::from_pkcs8_pem(&pem)
+        .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&pem))
+        .map_err(|e| anyhow!("Unusable private key of {}: {e}", actor.id))
+}
+
+/// The `Digest` header of a body
+pub fn digest(body: &[u8]) -> String {
+    format!("SHA-256={}", STANDARD.encode(Sha256::digest(body)))
+}
+
+/// A draft-cavage `Signature` header over `(request-target)` and `headers`, which have to
+/// be sent exactly as given
+pub fn sign_cavage(
+    method: &str,
+    url: &Url,
+    headers: &[(&str, &str)],
+    key_id: &str,
+    key: &RsaPrivateKey,
+) -> anyhow::Result<String> {
+    let request_target = match url.query() {
+        Some(query) => format!("{} {}?{}", method.to_lowercase(), url.path(), query),
+        None => format!("{} {}", method.to_lowercase(), url.path()),
+    };
+    let mut lines = vec![format!("(request-target): {request_target}")];
+    lines.extend(
+        headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}", name.to_lowercase(), value)),
+    );
+    let signature = key.sign(
+        Pkcs1v15Sign::new::<Sha256>(),
+        &Sha256::digest(lines.join("\n").as_bytes()),
+    )?;
+    let names = std::iter::once("(request-target)".to_string())
+        .chain(headers.iter().map(|(name, _)| name.to_lowercase()))
+        .collect::<Vec<_>>()
+        .join(" ");
+    Ok(format!(
+        r#"keyId="{}",algorithm="rsa-sha256",headers="{}",signature="{}""#,
+        key_id,
+        names,
+        STANDARD.encode(signature)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_key_documents() {
+        use super::{document_owner, KeyDocument};
+        use serde_json::json;
+        use url::Url;
+
+        let key_id = Url::parse("https://gts.example/users/a/main-key").unwrap();
+        let owner = |document: serde_json::Value| {
+            document_owner(
+                serde_json::from_value::<KeyDocument>(document).unwrap(),
+                &key_id,
+            )
+            .map(String::from)
+        };
+        let public_key = json!({
+            "id": "https://gts.example/users/a/main-key",
+            "owner": "https://gts.example/users/a",
+            "publicKeyPem": "",
+        });
+
+        // GoToSocial answers with a stub of the actor
+        assert_eq!(
+            owner(json!({ "id": "https://gts.example/users/a", "publicKey": public_key })),
+            Some("https://gts.example/users/a".to_string())
+        );
+        // others with the key itself
+        assert_eq!(
+            owner(public_key.clone()),
+            Some("https://gts.example/users/a".to_string())
+        );
+        // a document about some other key
+        assert_eq!(
+            owner(json!({
+                "id": "https://gts.example/users/b",
+                "publicKey": {
+                    "id": "https://gts.example/users/b/main-key",
+                    "owner": "https://gts.example/users/b",
+                    "publicKeyPem": "",
+                },
+            })),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publishes_key() {
+        use super::publishes_key;
+        use crate::testing;
+        use serde_json::json;
+        use url::Url;
+
+        let mut actor = testing::user("key-owner", false).await;
+        let person = |key_id: &str, owner: &str| {
+            json!({
+                "type": "Person",
+                "id": actor.url,
+                "preferredUsername": "key-owner",
+                "name": "key-owner",
+                "url": actor.url,
+                "inbox": actor.inbox,
+                "publicKey": { "id": key_id, "owner": owner, "publicKeyPem": "" },
+            })
+            .to_string()
+        };
+        let key_id = Url::parse(&format!("{}/main-key", actor.url)).unwrap();
+
+        actor.ap_json = Some(person(key_id.as_str(), &actor.url.clone()));
+        assert!(publishes_key(&actor, &key_id));
+        actor.ap_json = Some(person(
+            &format!("{}#main-key", actor.url),
+            &actor.url.clone(),
+        ));
+        assert!(!publishes_key(&actor, &key_id));
+        actor.ap_json = Some(person(
+            key_id.as_str(),
+            "https://remote.example/users/other",
+        ));
+        assert!(!publishes_key(&actor, &key_id));
+    }
+}
@@ -0,0 +1,465 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use sea_orm::{
+    sea_query::{Expr, Query},
+    ActiveModelTrait, ColumnTrait, Condition, DbErr, EntityTrait, QueryFilter, QueryOrder,
+    QuerySelect, Set,
+};
+use serde::Deserialize;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::{
+    delivery::DeliveryError,
+    entities::{follow_relation, instance, prelude, user},
+    fetcher::{fetcher, FetchError},
+    DB, FOLLOWER_GONE_GRACE_DAYS, INSTANCE_FAILURE_THRESHOLD,
+};
+
+/// How often paused instances are probed and nodeinfo is refreshed
+const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Instances probed per round, so one round can't hold up the next
+const PROBES_PER_ROUND: u64 = 20;
+
+const FIRST_PAUSE: Duration = Duration::from_secs(10 * 60);
+const MAX_PAUSE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long software and version are trusted before they are looked up again
+const NODEINFO_TTL: chrono::Duration = chrono::Duration::days(1);
+
+/// Successes of a healthy instance are written at most this often
+const SUCCESS_WRITE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+const GONE_CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Debug, Default)]
+struct Health {
+    failures: i32,
+    paused_until: Option<DateTime<Utc>>,
+    success_written: Option<Instant>,
+}
+
+/// Health of the instances delivered to, mirrored from the database
+static HEALTH: Lazy<Mutex<HashMap<String, Health>>> = Lazy::new(Default::default);
+
+/// Follower inboxes that answered 410 Gone and haven't come back since
+static GONE: Lazy<Mutex<HashSet<String>>> = Lazy::new(Default::default);
+
+/// How long an instance that failed `failures` times in a row is paused for: nothing
+/// below the threshold, then doubling from 10 minutes up to a day
+pub fn pause_for(failures: i32, threshold: i32) -> Option<Duration> {
+    if failures < threshold.max(1) {
+        return None;
+    }
+    let doublings = (failures - threshold).clamp(0, 20) as u32;
+    Some(
+        FIRST_PAUSE
+            .saturating_mul(2u32.pow(doublings))
+            .min(MAX_PAUSE),
+    )
+}
+
+/// Loads the unhealthy instances and gone inboxes, which every lookup is served from
+pub async fn load() -> Result<(), DbErr> {
+    let db = DB.get().unwrap();
+    let unhealthy = prelude::Instance::find()
+        .filter(instance::Column::ConsecutiveFailures.gt(0))
+        .all(db)
+        .await?;
+    *HEALTH.lock().unwrap() = unhealthy
+        .into_iter()
+        .map(|instance| {
+            (
+                instance.domain,
+                Health {
+                    failures: instance.consecutive_failures,
+                    paused_until: instance.paused_until,
+                    success_written: None,
+                },
+            )
+        })
+        .collect();
+    let gone = prelude::FollowRelation::find()
+        .filter(follow_relation::Column::FollowerInboxGoneAt.is_not_null())
+        .all(db)
+        .await?;
+    let followers = prelude::User::find()
+        .filter(user::Column::Id.is_in(gone.iter().map(|relation| relation.follower_id.clone())))
+        .all(db)
+        .await?;
+    *GONE.lock().unwrap() = gone
+        .into_iter()
+        .filter_map(|relation| relation.follower_inbox)
+        .chain(followers.into_iter().filter_map(|user| user.shared_inbox))
+        .collect();
+    Ok(())
+}
+
+/// Until when deliveries to `host` are held back, while it is paused
+pub fn paused_until(host: &str) -> Option<DateTime<Utc>> {
+    HEALTH
+        .lock()
+        .unwrap()
+        .get(host)
+        .and_then(|health| health.paused_until)
+}
+
+async fn find_or_new(domain: &str) -> Result<(instance::ActiveModel, bool), DbErr> {
+    let existing = prelude::Instance::find_by_id(domain)
+        .one(DB.get().unwrap())
+        .await?;
+    Ok(match existing {
+        Some(existing) => (existing.into(), true),
+        None => (
+            instance::ActiveModel {
+                domain: Set(domain.to_string()),
+                consecutive_failures: Set(0),
+                created_at: Set(Utc::now()),
+                ..Default::default()
+            },
+            false,
+        ),
+    })
+}
+
+async fn save(model: instance::ActiveModel, exists: bool) -> Result<(), DbErr> {
+    let db = DB.get().unwrap();
+    match exists {
+        true => model.update(db).await?,
+        false => model.insert(db).await?,
+    };
+    Ok(())
+}
+
+/// Records that `host` answered, which ends any pause
+pub async fn record_success(host: &str) -> Result<(), DbErr> {
+    {
+        let mut health = HEALTH.lock().unwrap();
+        let entry = health.entry(host.to_string()).or_default();
+        let recently_written = entry
+            .success_written
+            .is_some_and(|written| written.elapsed() < SUCCESS_WRITE_INTERVAL);
+        if entry.failures == 0 && recently_written {
+            return Ok(());
+        }
+        if entry.paused_until.is_some() {
+            info!("{} is reachable again, resuming deliveries", host);
+        }
+        *entry = Health {
+            success_written: Some(Instant::now()),
+            ..Default::default()
+        };
+    }
+    let (mut model, exists) = find_or_new(host).await?;
+    model.last_success_at = Set(Some(Utc::now()));
+    model.consecutive_failures = Set(0);
+    model.paused_until = Set(None);
+    save(model, exists).await
+}
+
+/// Stores the signature scheme `host` accepts, so it is known after a restart
+pub async fn record_signature_scheme(host: &str, scheme: &str) -> Result<(), DbErr> {
+    let (mut model, exists) = find_or_new(host).await?;
+    model.signature_scheme = Set(Some(scheme.to_string()));
+    save(model, exists).await
+}
+
+/// Records that `host` could not be reached, pausing it once that keeps happening
+pub async fn record_failure(host: &str) -> Result<(), DbErr> {
+    let (mut model, exists) = find_or_new(host).await?;
+    let failures = model.consecutive_failures.as_ref() + 1;
+    let paused_until = pause_for(failures, *INSTANCE_FAILURE_THRESHOLD)
+        .map(|pause| Utc::now() + chrono::Duration::from_std(pause).unwrap());
+    if let Some(until) = paused_until {
+        warn!(
+            "{} failed {} times in a row, pausing deliveries until {}",
+            host, failures, until
+        );
+    }
+    HEALTH.lock().unwrap().insert(
+        host.to_string(),
+        Health {
+            failures,
+            paused_until,
+            success_written: None,
+        },
+    );
+    model.last_failure_at = Set(Some(Utc::now()));
+    model.consecutive_failures = Set(failures);
+    model.paused_until = Set(paused_until);
+    save(model, exists).await
+}
+
+/// Feeds the outcome of a delivery to `inbox` into the health of its instance and the
+/// gone state of the inbox
+pub async fn record_delivery(inbox: &Url, result: &Result<(), DeliveryError>) {
+    let Some(host) = inbox.host_str() else {
+        return;
+    };
+    let recorded = match result {
+        Ok(()) => {
+            clear_gone(inbox).await;
+            record_success(host).await
+        }
+        Err(DeliveryError::Status(_, status)) if status.is_server_error() => {
+            record_failure(host).await
+        }
+        Err(DeliveryError::Status(_, status)) => {
+            if *status == reqwest::StatusCode::GONE {
+                mark_gone(inbox).await;
+            }
+            record_success(host).await
+        }
+        Err(DeliveryError::Fetch(FetchError::Request(_) | FetchError::Timeout)) => {
+            record_failure(host).await
+        }
+        Err(_) => Ok(()),
+    };
+    if let Err(err) = recorded {
+        warn!("Could not record the health of {}: {}", host, err);
+    }
+}
+
+/// The follows delivered to `inbox`, either the follower's own inbox or the shared inbox
+/// of their server, which most deliveries go to
+fn delivered_to(inbox: &Url) -> Condition {
+    Condition::any()
+        .add(follow_relation::Column::FollowerInbox.eq(inbox.as_str()))
+        .add(
+            follow_relation::Column::FollowerId.in_subquery(
+                Query::select()
+                    .column(user::Column::Id)
+                    .from(user::Entity)
+                    .and_where(user::Column::SharedInbox.eq(inbox.as_str()))
+                    .to_owned(),
+            ),
+        )
+}
+
+async fn mark_gone(inbox: &Url) {
+    if !GONE.lock().unwrap().insert(inbox.to_string()) {
+        return;
+    }
+    info!(
+        "{} is gone, dropping its followers after the grace period",
+        inbox
+    );
+    let marked = prelude::FollowRelation::update_many()
+        .col_expr(
+            follow_relation::Column::FollowerInboxGoneAt,
+            Expr::value(Utc::now()),
+        )
+        .filter(delivered_to(inbox))
+        .filter(follow_relation::Column::FollowerInboxGoneAt.is_null())
+        .exec(DB.get().unwrap())
+        .await;
+    if let Err(err) = marked {
+        warn!("Could not mark {} as gone: {}", inbox, err);
+    }
+}
+
+async fn clear_gone(inbox: &Url) {
+    if !GONE.lock().unwrap().remove(inbox.as_str()) {
+        return;
+    }
+    let cleared = prelude::FollowRelation::update_many()
+        .col_expr(
+            follow_relation::Column::FollowerInboxGoneAt,
+            Expr::value(Option::<DateTime<Utc>>::None),
+        )
+        .filter(delivered_to(inbox))
+        .exec(DB.get().unwrap())
+        .await;
+    if let Err(err) = cleared {
+        warn!("Could not clear the gone state of {}: {}", inbox, err);
+    }
+}
+
+#[derive(Deserialize)]
+struct NodeinfoLinks {
+    links: Vec<NodeinfoLink>,
+}
+
+#[derive(Deserialize)]
+struct NodeinfoLink {
+    rel: String,
+    href: Url,
+}
+
+#[derive(Deserialize)]
+struct Nodeinfo {
+    software: NodeinfoSoftware,
+}
+
+#[derive(Deserialize)]
+struct NodeinfoSoftware {
+    name: String,
+    version: Option<String>,
+}
+
+async fn fetch_nodeinfo(host: &str) -> Result<NodeinfoSoftware, FetchError> {
+    let well_known = Url::parse(&format!("https://{host}/.well-known/nodeinfo"))
+        .map_err(|_| FetchError::Unresolvable(host.to_string()))?;
+    let links: NodeinfoLinks = fetcher().get_json(&well_known).await?;
+    let link = links
+        .links
+        .into_iter()
+        .filter(|link| {
+            link.rel
+                .starts_with("http://nodeinfo.diaspora.software/ns/schema/")
+        })
+        .max_by(|a, b| a.rel.cmp(&b.rel))
+        .ok_or(FetchError::Status(reqwest::StatusCode::NOT_FOUND))?;
+    let nodeinfo: Nodeinfo = fetcher().get_json(&link.href).await?;
+    Ok(nodeinfo.software)
+}
+
+/// Looks up the software of an instance, which doubles as the probe of a paused one:
+/// any answer short of a server error means it is back
+async fn probe(instance: instance::Model) -> Result<(), DbErr> {
+    let host = instance.domain.clone();
+    let paused = instance.paused_until.is_some();
+    let result = fetch_nodeinfo(&host).await;
+    let reachable = match &result {
+        Ok(_) => true,
+        Err(FetchError::Status(status)) => !status.is_server_error(),
+        Err(FetchError::Json(_) | FetchError::BodyTooLarge(_)) => true,
+        Err(_) => false,
+    };
+    let mut model: instance::ActiveModel = instance.into();
+    model.nodeinfo_fetched_at = Set(Some(Utc::now()));
+    if let Ok(software) = result {
+        model.software = Set(Some(software.name));
+        model.version = Set(software.version);
+    }
+    model.update(DB.get().unwrap()).await?;
+    match (paused, reachable) {
+        (true, true) => record_success(&host).await,
+        (true, false) => record_failure(&host).await,
+        (false, _) => Ok(()),
+    }
+}
+
+async fn probe_round() -> Result<(), DbErr> {
+    let now = Utc::now();
+    let due = prelude::Instance::find()
+        .filter(
+            Condition::any()
+                .add(instance::Column::PausedUntil.lte(now))
+                .add(instance::Column::NodeinfoFetchedAt.is_null())
+                .add(instance::Column::NodeinfoFetchedAt.lt(now - NODEINFO_TTL)),
+        )
+        .order_by_asc(instance::Column::PausedUntil)
+        .limit(PROBES_PER_ROUND)
+        .all(DB.get().unwrap())
+        .await?;
+    for instance in due {
+        probe(instance).await?;
+    }
+    Ok(())
+}
+
+/// Probes paused instances once their pause is over, and keeps software and version of
+/// all of them current
+pub fn spawn_prober() {
+    tokio::spawn(async {
+        loop {
+            if let Err(err) = probe_round().await {
+                warn!("Could not probe instances: {}", err);
+            }
+            tokio::time::sleep(PROBE_INTERVAL).await;
+        }
+    });
+}
+
+/// Drops the follows of inboxes that stayed gone for the grace period
+pub fn spawn_gone_cleanup() {
+    tokio::spawn(async {
+        loop {
+            let grace = chrono::Duration::days(*FOLLOWER_GONE_GRACE_DAYS);
+            let deleted = prelude::FollowRelation::delete_many()
+                .filter(follow_relation::Column::FollowerInboxGoneAt.lt(Utc::now() - grace))
+                .exec(DB.get().unwrap())
+                .await;
+            match deleted {
+                Ok(deleted) if deleted.rows_affected > 0 => {
+                    info!("Dropped {} follows of gone inboxes", deleted.rows_affected)
+                }
+                Ok(_) => {}
+                Err(err) => warn!("Could not drop follows of gone inboxes: {}", err),
+            }
+            tokio::time::sleep(GONE_CLEANUP_INTERVAL).await;
+        }
+    });
+}
+
+pub async fn list_instances() -> Result<Vec<instance::Model>, DbErr> {
+    prelude::Instance::find()
+        .order_by_asc(instance::Column::Domain)
+        .all(DB.get().unwrap())
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_instance_pause() {
+        use super::pause_for;
+        use std::time::Duration;
+
+        assert_eq!(pause_for(0, 5), None);
+        assert_eq!(pause_for(4, 5), None);
+        assert_eq!(pause_for(5, 5), Some(Duration::from_secs(10 * 60)));
+        assert_eq!(pause_for(6, 5), Some(Duration::from_secs(20 * 60)));
+        assert_eq!(pause_for(100, 5), Some(Duration::from_secs(24 * 60 * 60)));
+    }
+
+    #[tokio::test]
+    async fn test_gone_shared_inbox() {
+        use super::{mark_gone, record_delivery};
+        use crate::{
+            entities::{prelude, user},
+            testing,
+        };
+        use sea_orm::{ActiveModelTrait, EntityTrait, Set};
+        use url::Url;
+
+        let db = testing::db().await;
+        let followee = testing::user("gone-followee", true).await;
+        let follower = testing::user("gone-follower", false).await;
+        let shared_inbox = Url::parse("https://remote.example/gone/inbox").unwrap();
+        user::ActiveModel {
+            id: Set(follower.id.clone()),
+            shared_inbox: Set(Some(shared_inbox.to_string())),
+            ..Default::default()
+        }
+        .update(db)
+        .await
+        .unwrap();
+        let relation = testing::follow(&follower, &followee).await;
+        let gone_at = || async {
+            prelude::FollowRelation::find_by_id(relation.id.as_str())
+                .one(db)
+                .await
+                .unwrap()
+                .unwrap()
+                .follower_inbox_gone_at
+        };
+
+        mark_gone(&shared_inbox).await;
+        assert!(gone_at().await.is_some());
+        record_delivery(&shared_inbox, &Ok(())).await;
+        assert!(gone_at().await.is_none());
+
+        // other inboxes of the server don't count
+        mark_gone(&Url::parse("https://remote.example/other/inbox").unwrap()).await;
+        assert!(gone_at().await.is_none());
+    }
+}
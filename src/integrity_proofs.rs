@@ -0,0 +1,565 @@
+//! FEP-8b32 object integrity proofs: `eddsa-jcs-2022` Data Integrity proofs made with the
+//! ed25519 keys bridged users already sign Versia requests with.
+
+use activitypub_federation::{config::Data, fetch::object_id::ObjectId};
+use chrono::{SecondsFormat, Utc};
+use ed25519_dalek::{
+    pkcs8::DecodePublicKey, Signature, Signer, SigningKey, Verifier, VerifyingKey,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::{
+    database::StateHandle,
+    entities::user,
+    error::Error,
+    http::local_db_user_from_url,
+    objects::person::Person,
+    versia::keys::{ensure_versia_keys, versia_signing_key},
+};
+
+pub const PROOF_TYPE: &str = "DataIntegrityProof";
+pub const CRYPTOSUITE: &str = "eddsa-jcs-2022";
+pub const PROOF_PURPOSE: &str = "assertionMethod";
+pub const DATA_INTEGRITY_CONTEXT: &str = "https://w3id.org/security/data-integrity/v1";
+pub const MULTIKEY_CONTEXT: &str = "https://w3id.org/security/multikey/v1";
+
+/// Multicodec prefix of an ed25519 public key
+const ED25519_MULTICODEC: [u8; 2] = [0xed, 0x01];
+
+const BASE58_ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+#[derive(Debug, Error)]
+pub enum ProofError {
+    #[error("Document has no {CRYPTOSUITE} proof")]
+    Missing,
+    #[error("Malformed proof: {0}")]
+    Malformed(&'static str),
+    #[error("Unsupported proof {0}")]
+    Unsupported(String),
+    #[error("Malformed multibase value")]
+    Multibase,
+    #[error("Verification method {0} is not controlled by {1}")]
+    Controller(String, String),
+    #[error("Unknown verification method {0}")]
+    UnknownKey(Url),
+    #[error("Proof does not match")]
+    Invalid,
+}
+
+/// A FEP-521a `assertionMethod` entry of an actor
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Multikey {
+    pub id: Url,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub controller: Url,
+    pub public_key_multibase: String,
+}
+
+impl Multikey {
+    pub fn new(controller: &Url, key: &VerifyingKey) -> Multikey {
+        Multikey {
+            id: multikey_id(controller),
+            kind: "Multikey".to_string(),
+            controller: controller.clone(),
+            public_key_multibase: encode_multikey(key),
+        }
+    }
+
+    pub fn verifying_key(&self) -> Result<VerifyingKey, ProofError> {
+        decode_multikey(&self.public_key_multibase)
+    }
+}
+
+/// The `@context` of actors publishing a Multikey
+pub fn actor_context() -> Value {
+    Value::Array(vec![
+        "https://www.w3.org/ns/activitystreams".into(),
+        "https://w3id.org/security/v1".into(),
+        MULTIKEY_CONTEXT.into(),
+    ])
+}
+
+/// The id the Multikey of an actor is published under
+pub fn multikey_id(actor: &Url) -> Url {
+    let mut id = actor.clone();
+    id.set_fragment(Some("ed25519-key"));
+    id
+}
+
+/// The Multikey of a local user, made from the Versia key stored as base64 SPKI
+pub fn local_multikey(controller: &Url, user: &user::Model) -> anyhow::Result<Option<Multikey>> {
+    let Some(encoded) = user.versia_public_key.as_ref() else {
+        return Ok(None);
+    };
+    let der = base64::Engine::decode(&base64::engine::general_purpose::STANDARD, encoded)?;
+    let key = VerifyingKey::from_public_key_der(&der)
+        .map_err(|e| anyhow::anyhow!("invalid Versia key of {}: {e}", user.id))?;
+    Ok(Some(Multikey::new(controller, &key)))
+}
+
+pub fn base58_encode(bytes: &[u8]) -> String {
+    let mut digits: Vec<u8> = vec![];
+    for &byte in bytes {
+        let mut carry = u32::from(byte);
+        for digit in digits.iter_mut() {
+            carry += u32::from(*digit) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    let zeros = bytes.iter().take_while(|&&byte| byte == 0).count();
+    std::iter::repeat_n(b'1', zeros)
+        .chain(
+            digits
+                .iter()
+                .rev()
+                .map(|&digit| BASE58_ALPHABET[digit as usize]),
+        )
+        .map(char::from)
+        .collect()
+}
+
+pub fn base58_decode(encoded: &str) -> Result<Vec<u8>, ProofError> {
+    let mut bytes: Vec<u8> = vec![];
+    for character in encoded.bytes() {
+        let mut carry = BASE58_ALPHABET
+            .iter()
+            .position(|&c| c == character)
+            .ok_or(ProofError::Multibase)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += u32::from(*byte) * 58;
+            *byte = (carry & 0xff) as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push((carry & 0xff) as u8);
+            carry >>= 8;
+        }
+    }
+    let zeros = encoded.bytes().take_while(|&c| c == b'1').count();
+    Ok(std::iter::repeat_n(0, zeros)
+        .chain(bytes.into_iter().rev())
+        .collect())
+}
+
+/// `z` + base58btc of the multicodec prefixed key
+pub fn encode_multikey(key: &VerifyingKey) -> String {
+    let mut bytes = ED25519_MULTICODEC.to_vec();
+    bytes.extend_from_slice(key.as_bytes());
+    format!("z{}", base58_encode(&bytes))
+}
+
+pub fn decode_multikey(encoded: &str) -> Result<VerifyingKey, ProofError> {
+    let bytes = base58_decode(encoded.strip_prefix('z').ok_or(ProofError::Multibase)?)?;
+    let key: [u8; 32] = bytes
+        .strip_prefix(&ED25519_MULTICODEC)
+        .and_then(|key| key.try_into().ok())
+        .ok_or(ProofError::Multibase)?;
+    VerifyingKey::from_bytes(&key).map_err(|_| ProofError::Multibase)
+}
+
+/// RFC 8785 JSON canonicalization: object keys sorted by their UTF-16 code units and no
+/// whitespace. Numbers are written the way serde_json writes them, which matches the
+/// ECMAScript form for the integers activities carry.
+pub fn canonicalize(value: &Value) -> String {
+    let mut out = String::new();
+    write_canonical(value, &mut out);
+    out
+}
+
+fn write_canonical(value: &Value, out: &mut String) {
+    match value {
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(item, out);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut entries: Vec<(&String, &Value)> = map.iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.encode_utf16().cmp(b.encode_utf16()));
+            out.push('{');
+            for (i, (key, item)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(key.clone()).to_string());
+                out.push(':');
+                write_canonical(item, out);
+            }
+            out.push('}');
+        }
+        scalar => out.push_str(&scalar.to_string()),
+    }
+}
+
+/// The data signed by an `eddsa-jcs-2022` proof: the hash of the proof options followed by
+/// the hash of the document without its proof
+fn hash_data(document: &Value, proof_config: &Map<String, Value>) -> Vec<u8> {
+    let mut proof_config = proof_config.clone();
+    if let Some(context) = document.get("@context") {
+        proof_config.insert("@context".to_string(), context.clone());
+    }
+    let mut hash = Sha256::digest(canonicalize(&Value::Object(proof_config)).as_bytes()).to_vec();
+    hash.extend_from_slice(&Sha256::digest(canonicalize(document).as_bytes()));
+    hash
+}
+
+/// Adds the Data Integrity context to the document's `@context`
+fn add_context(document: &mut Map<String, Value>) {
+    let integrity = Value::String(DATA_INTEGRITY_CONTEXT.to_string());
+    let context = match document.remove("@context") {
+        None => integrity,
+        Some(Value::Array(mut contexts)) => {
+            if !contexts.contains(&integrity) {
+                contexts.push(integrity);
+            }
+            Value::Array(contexts)
+        }
+        Some(context) if context == integrity => context,
+        Some(context) => Value::Array(vec![context, integrity]),
+    };
+    document.insert("@context".to_string(), context);
+}
+
+/// Attaches a proof made with `key` to `document`, replacing any it had
+pub fn sign_document(
+    document: Value,
+    key: &SigningKey,
+    verification_method: &Url,
+    created: chrono::DateTime<Utc>,
+) -> Result<Value, ProofError> {
+    let Value::Object(mut document) = document else {
+        return Err(ProofError::Malformed("document is not an object"));
+    };
+    document.remove("proof");
+    add_context(&mut document);
+    let mut proof = Map::new();
+    proof.insert("type".to_string(), PROOF_TYPE.into());
+    proof.insert("cryptosuite".to_string(), CRYPTOSUITE.into());
+    proof.insert(
+        "verificationMethod".to_string(),
+        verification_method.as_str().into(),
+    );
+    proof.insert("proofPurpose".to_string(), PROOF_PURPOSE.into());
+    proof.insert(
+        "created".to_string(),
+        created.to_rfc3339_opts(SecondsFormat::Secs, true).into(),
+    );
+    let document = Value::Object(document);
+    let signature = key.sign(&hash_data(&document, &proof));
+    proof.insert(
+        "proofValue".to_string(),
+        format!("z{}", base58_encode(&signature.to_bytes())).into(),
+    );
+    let Value::Object(mut document) = document else {
+        unreachable!()
+    };
+    document.insert("proof".to_string(), Value::Object(proof));
+    Ok(Value::Object(document))
+}
+
+pub fn has_proof(document: &Value) -> bool {
+    document.get("proof").is_some()
+}
+
+/// A proof whose key still has to be looked up
+pub struct UnverifiedProof {
+    pub verification_method: Url,
+    hash: Vec<u8>,
+    signature: Signature,
+}
+
+impl UnverifiedProof {
+    pub fn verify(&self, key: &VerifyingKey) -> Result<(), ProofError> {
+        key.verify(&self.hash, &self.signature)
+            .map_err(|_| ProofError::Invalid)
+    }
+}
+
+/// Checks the shape of the `eddsa-jcs-2022` proof on `document` and computes what it signs
+pub fn prepare_proof(document: &Value) -> Result<UnverifiedProof, ProofError> {
+    let Value::Object(document) = document else {
+        return Err(ProofError::Malformed("document is not an object"));
+    };
+    let mut unsecured = document.clone();
+    let proof = match unsecured.remove("proof") {
+        Some(Value::Object(proof)) => proof,
+        // a proof set, of which we only need the one we understand
+        Some(Value::Array(proofs)) => proofs
+            .into_iter()
+            .filter_map(|proof| match proof {
+                Value::Object(proof) => Some(proof),
+                _ => None,
+            })
+            .find(|proof| proof.get("cryptosuite").and_then(Value::as_str) == Some(CRYPTOSUITE))
+            .ok_or(ProofError::Missing)?,
+        _ => return Err(ProofError::Missing),
+    };
+    let field = |name: &str| proof.get(name).and_then(Value::as_str);
+    if field("type") != Some(PROOF_TYPE) || field("cryptosuite") != Some(CRYPTOSUITE) {
+        return Err(ProofError::Unsupported(format!(
+            "{} {}",
+            field("type").unwrap_or_default(),
+            field("cryptosuite").unwrap_or_default()
+        )));
+    }
+    if field("proofPurpose") != Some(PROOF_PURPOSE) {
+        return Err(ProofError::Malformed("proofPurpose is not assertionMethod"));
+    }
+    let verification_method = field("verificationMethod")
+        .and_then(|method| Url::parse(method).ok())
+        .ok_or(ProofError::Malformed("verificationMethod is missing"))?;
+    let signature: [u8; 64] = field("proofValue")
+        .and_then(|value| value.strip_prefix('z'))
+        .ok_or(ProofError::Malformed("proofValue is missing"))
+        .and_then(base58_decode)?
+        .try_into()
+        .map_err(|_| ProofError::Malformed("proofValue is not an ed25519 signature"))?;
+    let mut proof_config = proof;
+    proof_config.remove("proofValue");
+    Ok(UnverifiedProof {
+        verification_method,
+        hash: hash_data(&Value::Object(unsecured), &proof_config),
+        signature: Signature::from_bytes(&signature),
+    })
+}
+
+impl ProofError {
+    /// Whether the proof was checked and found wrong, rather than being one we can't check.
+    /// Only then is the activity refused instead of authenticated by its HTTP signature.
+    pub fn is_conclusive(&self) -> bool {
+        matches!(self, ProofError::Invalid)
+    }
+}
+
+/// Verifies the proof on an activity against the `assertionMethod` of its actor and
+/// returns the actor. Proofs stay valid wherever the activity travels, so forwarded
+/// activities don't need their HTTP signature or a refetch to be trusted.
+///
+/// Proofs in another cryptosuite or by a key the actor doesn't list give `None`, and the
+/// activity is left to its HTTP signature.
+pub async fn verify_activity_proof(
+    activity: &Value,
+    data: &Data<StateHandle>,
+) -> Result<Option<user::Model>, Error> {
+    match check_activity_proof(activity, data).await {
+        Ok(actor) => Ok(Some(actor)),
+        Err(err) => match err.0.downcast_ref::<ProofError>() {
+            Some(proof_err) if proof_err.is_conclusive() => Err(err),
+            _ => {
+                info!("Not relying on the proof of an activity: {}", err);
+                Ok(None)
+            }
+        },
+    }
+}
+
+async fn check_activity_proof(
+    activity: &Value,
+    data: &Data<StateHandle>,
+) -> Result<user::Model, Error> {
+    let actor_id = activity
+        .get("actor")
+        .and_then(Value::as_str)
+        .and_then(|actor| Url::parse(actor).ok())
+        .ok_or(ProofError::Malformed("activity has no actor"))?;
+    let unverified = prepare_proof(activity)?;
+    let mut controller = unverified.verification_method.clone();
+    controller.set_fragment(None);
+    if controller != actor_id {
+        return Err(ProofError::Controller(
+            unverified.verification_method.to_string(),
+            actor_id.to_string(),
+        )
+        .into());
+    }
+    let actor = ObjectId::<user::Model>::from(actor_id.clone())
+        .dereference(data)
+        .await?;
+    let person: Person = serde_json::from_str(actor.ap_json.as_deref().unwrap_or("{}"))?;
+    let multikey = person
+        .assertion_method
+        .unwrap_or_default()
+        .into_iter()
+        .find(|key| key.id == unverified.verification_method)
+        .ok_or(ProofError::UnknownKey(
+            unverified.verification_method.clone(),
+        ))?;
+    if multikey.controller != actor_id {
+        return Err(ProofError::Controller(multikey.id.to_string(), actor_id.to_string()).into());
+    }
+    unverified.verify(&multikey.verifying_key()?)?;
+    Ok(actor)
+}
+
+/// Signs an outbound activity with the Versia key of its actor when that is one of our
+/// bridged users. Activities of other actors go out without a proof.
+pub async fn sign_activity(activity: Value) -> Value {
+    let Some(actor_id) = activity
+        .get("actor")
+        .and_then(Value::as_str)
+        .and_then(|actor| Url::parse(actor).ok())
+    else {
+        return activity;
+    };
+    let signed = async {
+        let user = ensure_versia_keys(local_db_user_from_url(&actor_id).await?).await?;
+        let key = versia_signing_key(&user)?;
+        Ok::<_, anyhow::Error>(sign_document(
+            activity.clone(),
+            &key,
+            &multikey_id(&actor_id),
+            Utc::now(),
+        )?)
+    };
+    match signed.await {
+        Ok(signed) => signed,
+        Err(err) => {
+            warn!("Not attaching a proof for {}: {}", actor_id, err);
+            activity
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_integrity_proof_roundtrip() {
+        use super::{
+            base58_decode, base58_encode, canonicalize, decode_multikey, encode_multikey,
+            multikey_id, prepare_proof, sign_document,
+        };
+        use ed25519_dalek::SigningKey;
+        use rand::rngs::OsRng;
+        use serde_json::json;
+        use url::Url;
+
+        assert_eq!(base58_encode(b"Hello World!"), "2NEpo7TZRRrLZSi2U");
+        assert_eq!(base58_decode("2NEpo7TZRRrLZSi2U").unwrap(), b"Hello World!");
+        assert_eq!(
+            base58_decode(&base58_encode(&[0, 0, 1])).unwrap(),
+            [0, 0, 1]
+        );
+        assert_eq!(
+            canonicalize(&json!({"b": [1, "\u{e9}"], "a": {"d": null, "c": true}})),
+            r#"{"a":{"c":true,"d":null},"b":[1,"é"]}"#
+        );
+
+        let key = SigningKey::generate(&mut OsRng);
+        let multikey = encode_multikey(&key.verifying_key());
+        assert!(multikey.starts_with("z6Mk"));
+        assert_eq!(decode_multikey(&multikey).unwrap(), key.verifying_key());
+
+        let actor = Url::parse("https://bridge.example/apbridge/user/alice").unwrap();
+        let activity = json!({
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "type": "Follow",
+            "id": "https://bridge.example/follow/1",
+            "actor": actor,
+            "object": "https://remote.example/users/bob",
+        });
+        let signed =
+            sign_document(activity, &key, &multikey_id(&actor), chrono::Utc::now()).unwrap();
+        assert_eq!(signed["proof"]["cryptosuite"], "eddsa-jcs-2022");
+        assert_eq!(
+            signed["proof"]["verificationMethod"],
+            "https://bridge.example/apbridge/user/alice#ed25519-key"
+        );
+
+        // key order doesn't matter after canonicalization
+        let reordered: serde_json::Value = serde_json::from_str(&canonicalize(&signed)).unwrap();
+        let unverified = prepare_proof(&reordered).unwrap();
+        assert_eq!(unverified.verification_method, multikey_id(&actor));
+        assert!(unverified.verify(&key.verifying_key()).is_ok());
+
+        let mut tampered = signed.clone();
+        tampered["object"] = json!("https://remote.example/users/eve");
+        assert!(prepare_proof(&tampered)
+            .unwrap()
+            .verify(&key.verifying_key())
+            .is_err());
+        let other = SigningKey::generate(&mut OsRng);
+        assert!(prepare_proof(&signed)
+            .unwrap()
+            .verify(&other.verifying_key())
+            .is_err());
+    }
+
+    #[test]
+    fn test_unusable_proofs_fall_back() {
+        use super::{multikey_id, prepare_proof, sign_document, Multikey};
+        use crate::objects::person::Person;
+        use ed25519_dalek::SigningKey;
+        use rand::rngs::OsRng;
+        use serde_json::json;
+        use url::Url;
+
+        let actor = Url::parse("https://remote.example/users/bob").unwrap();
+        let key = SigningKey::generate(&mut OsRng);
+        let activity = json!({
+            "type": "Like",
+            "id": "https://remote.example/likes/1",
+            "actor": actor,
+            "object": "https://bridge.example/apbridge/object/1",
+        });
+
+        let mut other_suite = activity.clone();
+        other_suite["proof"] = json!({
+            "type": "DataIntegrityProof",
+            "cryptosuite": "eddsa-rdfc-2022",
+            "proofPurpose": "assertionMethod",
+            "verificationMethod": "https://remote.example/users/bob#ed25519-key",
+            "proofValue": "z3FXQ",
+        });
+        assert!(prepare_proof(&other_suite).is_err_and(|err| !err.is_conclusive()));
+
+        let signed =
+            sign_document(activity, &key, &multikey_id(&actor), chrono::Utc::now()).unwrap();
+        let mut tampered = signed.clone();
+        tampered["object"] = json!("https://bridge.example/apbridge/object/2");
+        assert!(prepare_proof(&tampered)
+            .unwrap()
+            .verify(&key.verifying_key())
+            .unwrap_err()
+            .is_conclusive());
+
+        // a single key, next to an entry we don't understand
+        let person = |assertion_method: serde_json::Value| {
+            serde_json::from_value::<Person>(json!({
+                "type": "Person",
+                "id": actor,
+                "url": actor,
+                "preferredUsername": "bob",
+                "inbox": "https://remote.example/users/bob/inbox",
+                "publicKey": {
+                    "id": "https://remote.example/users/bob#main-key",
+                    "owner": actor,
+                    "publicKeyPem": "",
+                },
+                "assertionMethod": assertion_method,
+            }))
+            .unwrap()
+            .assertion_method
+            .unwrap_or_default()
+        };
+        let multikey = serde_json::to_value(Multikey::new(&actor, &key.verifying_key())).unwrap();
+        assert_eq!(person(multikey.clone()).len(), 1);
+        assert_eq!(person(json!([{ "type": "JsonWebKey" }, multikey])).len(), 1);
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    http::header::{CONTENT_LENGTH, RETRY_AFTER},
+    web::{self, Bytes},
+    HttpRequest, HttpResponse,
+};
+use once_cell::sync::Lazy;
+use prometheus::{IntCounterVec, Opts};
+use tracing::info;
+use url::Url;
+
+use crate::{
+    fetcher::is_upstream_host, INBOX_BURST, INBOX_MAX_BODY_SIZE, INBOX_RATE_LIMIT, TRUSTED_PROXIES,
+};
+
+/// Buckets kept before full ones are dropped, so one-off senders don't pile up
+const MAX_TRACKED_DOMAINS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets per remote domain, refilled at `per_minute` up to `burst` tokens
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32, burst: u32) -> RateLimiter {
+        RateLimiter {
+            per_second: f64::from(per_minute) / 60.0,
+            burst: f64::from(burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for `domain`, or tells how long until the next one is available
+    pub fn check(&self, domain: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_DOMAINS {
+            buckets.retain(|_, bucket| self.refill(bucket, now) < self.burst);
+        }
+        let bucket = buckets.entry(domain.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = self.refill(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        if self.per_second <= 0.0 {
+            return Err(Duration::from_secs(60));
+        }
+        Err(Duration::from_secs_f64(
+            (1.0 - bucket.tokens) / self.per_second,
+        ))
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.per_second).min(self.burst)
+    }
+}
+
+/// Counts requests by where they come from, before anything about them is verified
+static ADDRESS_LIMITER: Lazy<RateLimiter> =
+    Lazy::new(|| RateLimiter::new(*INBOX_RATE_LIMIT, *INBOX_BURST));
+
+/// Counts requests by the domain that signed them, once the signature checks out
+static DOMAIN_LIMITER: Lazy<RateLimiter> =
+    Lazy::new(|| RateLimiter::new(*INBOX_RATE_LIMIT, *INBOX_BURST));
+
+/// Inbox requests refused for going over a limit, by inbox and limit
+pub static LIMITED_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(
+        Opts::new(
+            "inbox_limited_requests_total",
+            "Inbox requests refused for exceeding a rate or size limit",
+        )
+        .namespace("activitypub_bridge"),
+        &["inbox", "limit"],
+    )
+    .unwrap()
+});
+
+/// The address a request came from. The key id it claims is not used before the signature
+/// is verified, since anyone could put someone else's there and use up their limit.
+fn client_address(request: &HttpRequest) -> String {
+    client_address_behind(request, &TRUSTED_PROXIES)
+}
+
+/// Forwarding headers are only believed when they were set by one of `proxies`, anyone
+/// else could make up a fresh address for every request
+fn client_address_behind(request: &HttpRequest, proxies: &[IpAddr]) -> String {
+    let Some(peer) = request.peer_addr().map(|addr| addr.ip()) else {
+        return "unknown".to_string();
+    };
+    if !proxies.contains(&peer) {
+        return peer.to_string();
+    }
+    request
+        .connection_info()
+        .realip_remote_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or(peer.to_string())
+}
+
+fn too_many_requests(retry_after: Duration) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((
+            RETRY_AFTER,
+            (retry_after.as_secs_f64().ceil() as u64).max(1).to_string(),
+        ))
+        .finish()
+}
+
+/// Applies the per-address rate limit of the inboxes, answering 429 when it is exhausted
+pub fn limit_inbox_rate(request: &HttpRequest, inbox: &str) -> Option<HttpResponse> {
+    let address = client_address(request);
+    let retry_after = ADDRESS_LIMITER.check(&address, Instant::now()).err()?;
+    info!("Rate limiting {} on the {} inbox", address, inbox);
+    LIMITED_REQUESTS.with_label_values(&[inbox, "rate"]).inc();
+    Some(too_many_requests(retry_after))
+}
+
+/// Applies the per-domain rate limit of the inboxes to a request `signer` has been verified
+/// to have signed
+pub fn limit_signer_rate(signer: &Url, inbox: &str) -> Option<HttpResponse> {
+    let domain = signer.host_str()?.to_lowercase();
+    // everything bridged from Versia comes from the one server
+    if is_upstream_host(&domain) {
+        return None;
+    }
+    let retry_after = DOMAIN_LIMITER.check(&domain, Instant::now()).err()?;
+    info!("Rate limiting {} on the {} inbox", domain, inbox);
+    LIMITED_REQUESTS
+        .with_label_values(&[inbox, "domain_rate"])
+        .inc();
+    Some(too_many_requests(retry_after))
+}
+
+/// Reads an inbox request body, refusing anything over the configured size
+pub async fn read_inbox_body(
+    request: &HttpRequest,
+    payload: web::Payload,
+    inbox: &str,
+) -> Result<Bytes, HttpResponse> {
+    let limit = *INBOX_MAX_BODY_SIZE;
+    let too_large = || {
+        info!(
+            "Refusing body over {} bytes from {} on the {} inbox",
+            limit,
+            client_address(request),
+            inbox
+        );
+        LIMITED_REQUESTS
+            .with_label_values(&[inbox, "body_size"])
+            .inc();
+        HttpResponse::PayloadTooLarge().finish()
+    };
+    // no need to wait for the body when it announces its size
+    let announced = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if announced.is_some_and(|length| length > limit) {
+        return Err(too_large());
+    }
+    match payload.to_bytes_limited(limit).await {
+        Ok(Ok(body)) => Ok(body),
+        Ok(Err(err)) => Err(HttpResponse::BadRequest().body(err.to_string())),
+        Err(_) => Err(too_large()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_rate_limiter_buckets() {
+        use super::RateLimiter;
+        use std::time::{Duration, Instant};
+
+        // one token per second, three in a burst
+        let limiter = RateLimiter::new(60, 3);
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check("busy.example", start).is_ok());
+        }
+        let retry_after = limiter.check("busy.example", start).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(1));
+        assert!(limiter.check("quiet.example", start).is_ok());
+
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.check("busy.example", later).is_ok());
+        assert!(limiter.check("busy.example", later).is_err());
+    }
+
+    #[test]
+    fn test_limits_ignore_claimed_signer() {
+        use super::{limit_inbox_rate, limit_signer_rate};
+        use crate::{testing, INBOX_BURST};
+        use actix_web::test::TestRequest;
+        use url::Url;
+
+        testing::env();
+        // claims to be signed by victim.example, which it can't prove yet
+        let request = |peer: &str| {
+            TestRequest::post()
+                .peer_addr(peer.parse().unwrap())
+                .insert_header((
+                    "Signature",
+                    r#"keyId="https://victim.example/users/a#main-key",signature="x""#,
+                ))
+                .to_http_request()
+        };
+        for _ in 0..*INBOX_BURST {
+            assert!(limit_inbox_rate(&request("192.0.2.1:1234"), "test").is_none());
+        }
+        let limited = limit_inbox_rate(&request("192.0.2.1:1234"), "test").unwrap();
+        assert_eq!(limited.status(), 429);
+        assert!(limit_inbox_rate(&request("192.0.2.2:1234"), "test").is_none());
+
+        // only verified signers use up their domain's requests
+        let victim = Url::parse("https://victim.example/users/a").unwrap();
+        assert!(limit_signer_rate(&victim, "test").is_none());
+        let sender = Url::parse("https://busy.example/users/b").unwrap();
+        for _ in 0..*INBOX_BURST {
+            assert!(limit_signer_rate(&sender, "test").is_none());
+        }
+        assert!(limit_signer_rate(&sender, "test").is_some());
+        assert!(limit_signer_rate(&victim, "test").is_none());
+
+        // the Versia server sends everything of its users, it is never held to one domain's share
+        let upstream = Url::parse("https://versia.example/users/c").unwrap();
+        for _ in 0..=*INBOX_BURST {
+            assert!(limit_signer_rate(&upstream, "test").is_none());
+        }
+    }
+
+    #[test]
+    fn test_client_address_behind_proxy() {
+        use super::client_address_behind;
+        use actix_web::test::TestRequest;
+        use std::net::IpAddr;
+
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let request = |peer: &str| {
+            TestRequest::post()
+                .peer_addr(peer.parse().unwrap())
+                .insert_header(("X-Forwarded-For", "198.51.100.7"))
+                .to_http_request()
+        };
+
+        assert_eq!(
+            client_address_behind(&request("10.0.0.1:1234"), &[proxy]),
+            "198.51.100.7"
+        );
+        // a header anyone else sets is ignored
+        assert_eq!(
+            client_address_behind(&request("192.0.2.1:1234"), &[proxy]),
+            "192.0.2.1"
+        );
+        assert_eq!(
+            client_address_behind(&request("10.0.0.1:1234"), &[]),
+            "10.0.0.1"
+        );
+    }
+}
@@ -1,7 +1,6 @@
 use activitypub_federation::{
     config::{Data, FederationConfig, FederationMiddleware},
     fetch::{object_id::ObjectId, webfinger::webfinger_resolve_actor},
-    http_signatures::generate_actor_keypair,
     traits::Actor,
 };
 use activitystreams_kinds::public;
@@ -11,27 +10,31 @@ use actix_web::{
 use actix_web_prom::PrometheusMetricsBuilder;
 use async_once::AsyncOnce;
 use chrono::{DateTime, Utc};
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use crypto::{master_key, reseal_stored_keys, seal_plaintext_keys, MasterKey};
 use database::Database;
 use entities::post;
-use http::{http_get_user, http_post_user_inbox, webfinger};
-use objects::person::{DbUser, Person};
+use fetcher::{fetcher, FetchPolicy, PublicUrlVerifier};
+use http::{host_meta, host_meta_json, http_get_user, http_post_user_inbox, webfinger};
+use objects::person::{ActorKind, DbUser};
+use policy::{list_policies, remove_policy, set_policy, DomainPolicy};
 use sea_orm::{ActiveModelTrait, DatabaseConnection, Set};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     env,
-    net::ToSocketAddrs,
+    net::{IpAddr, ToSocketAddrs},
     sync::{Arc, Mutex, OnceLock},
+    time::Duration,
 };
-use tokio::signal;
-use tracing::{info, instrument::WithSubscriber};
+use tracing::{info, instrument::WithSubscriber, warn};
 use url::Url;
 use utils::generate_object_id;
-use uuid::Uuid;
 use versia::http::{
-    create_activity, fetch_post, fetch_user, fetch_versia_post, query_post, versia_inbox,
+    create_activity, fetch_context, fetch_followers, fetch_post, fetch_replies,
+    fetch_service_actor, fetch_user, fetch_versia_post, query_post, versia_inbox, versia_metadata,
 };
+use versia::inbox_queue;
 
 use crate::{
     activities::create_post::CreatePost,
@@ -43,11 +46,28 @@ use dotenv::dotenv;
 use lazy_static::lazy_static;
 
 mod activities;
+mod crypto;
 mod database;
+mod dedup;
+mod delivery;
 mod entities;
 mod error;
+mod fetcher;
+mod forwarding;
 mod http;
+mod http_signatures;
+mod instances;
+mod integrity_proofs;
+mod limits;
+mod message_signatures;
 mod objects;
+mod policy;
+mod queue;
+mod relays;
+mod service_actor;
+mod shutdown;
+#[cfg(test)]
+mod testing;
 mod utils;
 mod versia;
 
@@ -67,6 +87,266 @@ struct Args {
     /// optional parse arg for config file
     #[arg()]
     config_file: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage the master key private keys are encrypted with
+    #[command(subcommand)]
+    Keys(KeysCommand),
+    /// Manage the federation policies of remote domains
+    #[command(subcommand)]
+    Domain(DomainCommand),
+    /// Inspect the outbound delivery queue
+    #[command(subcommand)]
+    Queue(QueueCommand),
+    /// Inspect the queue of received Versia entities
+    #[command(subcommand)]
+    Inbox(QueueCommand),
+    /// List the remote instances delivered to and their health
+    Instances,
+    /// Manage the relays the service actor is subscribed to
+    #[command(subcommand)]
+    Relay(RelayCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum KeysCommand {
+    /// Print a new random master key
+    Generate,
+    /// Encrypt all private keys still stored in plaintext with the configured master key,
+    /// which the bridge also does when it starts
+    Encrypt,
+    /// Re-encrypt all private keys for a new master key, read from a file
+    Rotate {
+        #[arg(long)]
+        new_key_file: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum DomainCommand {
+    /// List all domain policies
+    List,
+    /// Set the policy of a domain and its subdomains, replacing any existing one
+    Set {
+        domain: String,
+        /// Refuse everything from the domain and deliver nothing to it
+        #[arg(long)]
+        reject: bool,
+        /// Only bridge posts of the domain to Versia users following their author
+        #[arg(long)]
+        silence: bool,
+        /// Drop avatars, headers and emojis of the domain's users
+        #[arg(long)]
+        strip_media: bool,
+        /// Drop reports coming from the domain
+        #[arg(long)]
+        reject_reports: bool,
+        /// Put the domain on the allowlist used with FEDERATION_MODE=allowlist
+        #[arg(long)]
+        allow: bool,
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Remove the policy of a domain
+    Remove { domain: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum RelayCommand {
+    /// List the relays and whether they accepted the subscription
+    List,
+    /// Subscribe to a relay, given by its actor (LitePub) or its inbox (Mastodon)
+    Add { url: Url },
+    /// Unsubscribe from a relay
+    Remove { url: Url },
+}
+
+#[derive(Subcommand, Debug)]
+enum QueueCommand {
+    /// Show how many jobs are pending and dead
+    Status,
+    /// List the jobs that were given up on
+    Dead,
+    /// Queue dead jobs again, all of them unless an id is given
+    Retry { id: Option<String> },
+    /// Delete all dead jobs
+    Purge,
+}
+
+async fn run_queue_command(command: QueueCommand) -> anyhow::Result<()> {
+    match command {
+        QueueCommand::Status => {
+            let (pending, dead) = queue::counts().await?;
+            println!("{} pending, {} dead", pending, dead);
+        }
+        QueueCommand::Dead => {
+            for job in queue::dead_jobs().await? {
+                println!(
+                    "{}\t{}\t{}\t{} attempts\t{}",
+                    job.id,
+                    job.protocol,
+                    job.inbox,
+                    job.attempts,
+                    job.last_error.unwrap_or_default()
+                );
+            }
+        }
+        QueueCommand::Retry { id } => {
+            println!("Queued {} deliveries again", queue::retry_dead(id).await?)
+        }
+        QueueCommand::Purge => println!("Deleted {} dead deliveries", queue::purge_dead().await?),
+    }
+    Ok(())
+}
+
+async fn run_inbox_command(command: QueueCommand) -> anyhow::Result<()> {
+    match command {
+        QueueCommand::Status => {
+            let (pending, dead) = inbox_queue::counts().await?;
+            println!("{} pending, {} dead", pending, dead);
+        }
+        QueueCommand::Dead => {
+            for job in inbox_queue::dead_jobs().await? {
+                println!(
+                    "{}\t{}\t{} attempts\t{}",
+                    job.id,
+                    job.entity_id.unwrap_or_default(),
+                    job.attempts,
+                    job.last_error.unwrap_or_default()
+                );
+            }
+        }
+        QueueCommand::Retry { id } => {
+            println!(
+                "Queued {} entities again",
+                inbox_queue::retry_dead(id).await?
+            )
+        }
+        QueueCommand::Purge => {
+            println!("Deleted {} dead entities", inbox_queue::purge_dead().await?)
+        }
+    }
+    Ok(())
+}
+
+async fn run_relay_command(command: RelayCommand) -> anyhow::Result<()> {
+    match command {
+        RelayCommand::List => {
+            for relay in relays::list().await? {
+                println!("{}\t{}", relay.url, relay.status);
+            }
+        }
+        RelayCommand::Add { url } => {
+            let relay = relays::subscribe(url).await?;
+            println!("Subscribed to {}, waiting for it to accept", relay.url);
+        }
+        RelayCommand::Remove { url } => {
+            relays::unsubscribe(url.clone()).await?;
+            println!("Unsubscribed from {}", url);
+        }
+    }
+    Ok(())
+}
+
+async fn list_instances() -> anyhow::Result<()> {
+    for instance in instances::list_instances().await? {
+        let last_success = instance
+            .last_success_at
+            .map(|at| at.to_rfc3339())
+            .unwrap_or("never".to_string());
+        let state = match instance.paused_until {
+            Some(until) => format!("paused until {}", until.to_rfc3339()),
+            None => "active".to_string(),
+        };
+        println!(
+            "{}\t{} {}\t{}\tlast success {}\t{} failures",
+            instance.domain,
+            instance.software.unwrap_or("unknown".to_string()),
+            instance.version.unwrap_or_default(),
+            state,
+            last_success,
+            instance.consecutive_failures
+        );
+    }
+    Ok(())
+}
+
+async fn run_domain_command(command: DomainCommand) -> anyhow::Result<()> {
+    match command {
+        DomainCommand::List => {
+            for policy in list_policies().await? {
+                let actions = [
+                    (policy.reject, "reject"),
+                    (policy.silence, "silence"),
+                    (policy.strip_media, "strip-media"),
+                    (policy.reject_reports, "reject-reports"),
+                    (policy.allow, "allow"),
+                ]
+                .iter()
+                .filter(|(set, _)| *set)
+                .map(|(_, action)| *action)
+                .collect::<Vec<_>>();
+                println!(
+                    "{}\t{}\t{}",
+                    policy.domain,
+                    actions.join(","),
+                    policy.reason.unwrap_or_default()
+                );
+            }
+        }
+        DomainCommand::Set {
+            domain,
+            reject,
+            silence,
+            strip_media,
+            reject_reports,
+            allow,
+            reason,
+        } => {
+            let policy = DomainPolicy {
+                reject,
+                silence,
+                strip_media,
+                reject_reports,
+                allow,
+            };
+            let stored = set_policy(&domain, &policy, reason).await?;
+            println!("Set the policy of {}", stored.domain);
+        }
+        DomainCommand::Remove { domain } => match remove_policy(&domain).await? {
+            true => println!("Removed the policy of {}", domain),
+            false => println!("{} has no policy", domain),
+        },
+    }
+    Ok(())
+}
+
+async fn run_keys_command(command: KeysCommand) -> anyhow::Result<()> {
+    match command {
+        KeysCommand::Generate => println!("{}", MasterKey::generate().to_base64()),
+        KeysCommand::Encrypt => {
+            let master_key = master_key().ok_or(anyhow::anyhow!(
+                "Set MASTER_KEY or MASTER_KEY_FILE to encrypt private keys"
+            ))?;
+            let updated = reseal_stored_keys(Some(master_key), master_key).await?;
+            println!("Encrypted the keys of {} users", updated);
+        }
+        KeysCommand::Rotate { new_key_file } => {
+            let new_key = MasterKey::from_base64(&std::fs::read_to_string(new_key_file)?)?;
+            let updated = reseal_stored_keys(master_key(), &new_key).await?;
+            println!(
+                "Re-encrypted the keys of {} users for master key {}, configure it before restarting",
+                updated,
+                new_key.id()
+            );
+        }
+    }
+    Ok(())
 }
 
 #[get("/")]
@@ -102,6 +382,8 @@ async fn post_manually(
         tag: vec![mention],
         in_reply_to: None,
         cc: vec![].into(),
+        replies: None,
+        context: None,
     };
 
     let post = entities::post::ActiveModel {
@@ -171,10 +453,81 @@ lazy_static! {
     static ref USERNAME: String =
         env::var("LOCAL_USER_NAME").unwrap_or(LOCAL_USER_NAME.to_string());
     static ref API_DOMAIN: String = env::var("API_DOMAIN").expect("not set API_DOMAIN");
-    static ref AUTH: String = env::var("AUTH").expect("not set AUTH");
     static ref LYSAND_DOMAIN: String = env::var("LYSAND_DOMAIN").expect("not set LYSAND_DOMAIN");
     static ref FEDERATED_DOMAIN: String =
         env::var("FEDERATED_DOMAIN").unwrap_or(API_DOMAIN.to_string());
+    static ref INSTANCE_NAME: String =
+        env::var("INSTANCE_NAME").unwrap_or("Versia ActivityPub Bridge".to_string());
+    static ref INSTANCE_DESCRIPTION: Option<String> = env::var("INSTANCE_DESCRIPTION").ok();
+    static ref SERVICE_ACTOR_NAME: String =
+        env::var("SERVICE_ACTOR_NAME").unwrap_or(INSTANCE_NAME.to_string());
+    static ref SERVICE_ACTOR_SUMMARY: String = env::var("SERVICE_ACTOR_SUMMARY")
+        .ok()
+        .or(INSTANCE_DESCRIPTION.clone())
+        .unwrap_or("Relays posts between Versia and the fediverse".to_string());
+    static ref SERVICE_ACTOR_TYPE: ActorKind = match env::var("SERVICE_ACTOR_TYPE").as_deref() {
+        Ok("Service") => ActorKind::Service,
+        _ => ActorKind::Application,
+    };
+    static ref SUBSCRIBE_TEMPLATE: String = env::var("SUBSCRIBE_TEMPLATE").unwrap_or(format!(
+        "https://{}/authorize_interaction?uri={{uri}}",
+        LYSAND_DOMAIN.as_str()
+    ));
+    static ref AUTHORIZED_FETCH: bool = env::var("AUTHORIZED_FETCH")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false);
+    static ref BLOCKED_DOMAINS: Vec<String> = env::var("BLOCKED_DOMAINS")
+        .map(|domains| {
+            domains
+                .split(',')
+                .map(|domain| domain.trim().to_lowercase())
+                .filter(|domain| !domain.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+    static ref TRUSTED_PROXIES: Vec<IpAddr> = env::var("TRUSTED_PROXIES")
+        .map(|proxies| {
+            proxies
+                .split(',')
+                .map(str::trim)
+                .filter(|proxy| !proxy.is_empty())
+                .map(|proxy| {
+                    proxy
+                        .parse()
+                        .unwrap_or_else(|_| panic!("TRUSTED_PROXIES has invalid address {proxy}"))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    static ref INBOX_RATE_LIMIT: u32 = env_number("INBOX_RATE_LIMIT", 300);
+    static ref INBOX_BURST: u32 = env_number("INBOX_BURST", 60);
+    static ref INBOX_MAX_BODY_SIZE: usize = env_number("INBOX_MAX_BODY_SIZE", 1024 * 1024);
+    static ref MAX_FETCHES_PER_ACTIVITY: u32 = env_number("MAX_FETCHES_PER_ACTIVITY", 20);
+    static ref BACKFILL_CONCURRENCY: usize = env_number("BACKFILL_CONCURRENCY", 4);
+    static ref DELIVERY_CONCURRENCY: usize = env_number("DELIVERY_CONCURRENCY", 32);
+    static ref DELIVERY_HOST_CONCURRENCY: usize = env_number("DELIVERY_HOST_CONCURRENCY", 4);
+    static ref DELIVERY_MAX_ATTEMPTS: i32 = env_number("DELIVERY_MAX_ATTEMPTS", 10);
+    static ref INSTANCE_FAILURE_THRESHOLD: i32 = env_number("INSTANCE_FAILURE_THRESHOLD", 5);
+    static ref FOLLOWER_GONE_GRACE_DAYS: i64 = env_number("FOLLOWER_GONE_GRACE_DAYS", 7);
+    static ref INBOX_JOB_MAX_ATTEMPTS: i32 = env_number("INBOX_JOB_MAX_ATTEMPTS", 10);
+    static ref INBOX_CONCURRENCY: usize = env_number("INBOX_CONCURRENCY", 4);
+    static ref RELAY_PUBLISH: bool = env::var("RELAY_PUBLISH")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false);
+    static ref SHUTDOWN_TIMEOUT: u64 = env_number("SHUTDOWN_TIMEOUT", 30);
+    static ref PROCESSED_ACTIVITY_TTL: i64 = env_number("PROCESSED_ACTIVITY_TTL_HOURS", 7 * 24);
+    static ref FEDERATION_ALLOWLIST: bool = env::var("FEDERATION_MODE")
+        .map(|mode| mode.eq_ignore_ascii_case("allowlist"))
+        .unwrap_or(false);
+}
+
+fn env_number<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{name} must be a number")),
+        Err(_) => default,
+    }
 }
 
 static DB: OnceLock<DatabaseConnection> = OnceLock::new();
@@ -184,66 +537,7 @@ static FEDERATION_CONFIG: OnceLock<FederationConfig<State>> = OnceLock::new();
 async fn main() -> actix_web::Result<(), anyhow::Error> {
     dotenv().ok();
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
-
-    let uuid = Uuid::parse_str("019116ea-3bf6-7ba3-b437-2cd7aaf40f80")?;
-
-    let ap_id = Url::parse(&format!(
-        "https://{}/apbridge/user/{}",
-        API_DOMAIN.to_string(),
-        &uuid.to_string()
-    ))?;
-    let inbox = Url::parse(&format!(
-        "https://{}/{}/inbox",
-        API_DOMAIN.to_string(),
-        &USERNAME.to_string()
-    ))?;
-    let keypair = generate_actor_keypair()?;
-
-    let ap_json = Person {
-        id: ap_id.clone().into(),
-        preferred_username: USERNAME.to_string(),
-        name: "Test account <3".to_string(),
-        inbox: inbox.clone(),
-        public_key: activitypub_federation::protocol::public_key::PublicKey {
-            owner: ap_id.clone(),
-            public_key_pem: keypair.public_key.clone(),
-            id: format!("{}#main-key", ap_id.clone()),
-        },
-        summary: Some("Test account <3".to_string()),
-        url: ap_id.clone(),
-        kind: Default::default(),
-        indexable: Some(false),
-        discoverable: Some(false),
-        icon: None,
-        image: None,
-        attachment: None,
-        tag: None,
-        endpoints: None,
-        followers: None,
-        following: None,
-        featured: None,
-        outbox: None,
-        also_known_as: None,
-        featured_tags: None,
-        manually_approves_followers: Some(false),
-    };
-
-    let user = entities::user::ActiveModel {
-        id: Set(uuid.to_string()),
-        username: Set(USERNAME.to_string()),
-        name: Set("Test account <3".to_string()),
-        inbox: Set(inbox.to_string()),
-        public_key: Set(keypair.public_key.clone()),
-        private_key: Set(Some(keypair.private_key.clone())),
-        last_refreshed_at: Set(Utc::now()),
-        follower_count: Set(0),
-        following_count: Set(0),
-        url: Set(ap_id.to_string()),
-        local: Set(true),
-        created_at: Set(Utc::now()),
-        ap_json: Set(Some(serde_json::to_string(&ap_json).unwrap())),
-        ..Default::default()
-    };
+    let args = Args::parse();
 
     let db = sea_orm::Database::connect(DATABASE_URL.to_string()).await?;
 
@@ -252,16 +546,42 @@ async fn main() -> actix_web::Result<(), anyhow::Error> {
     DB.set(db)
         .expect("We were not able to save the DB conn into memory");
 
-    let db = DB.get().unwrap();
-
-    let user = user.insert(db).await;
+    match args.command {
+        Some(Command::Keys(command)) => return run_keys_command(command).await,
+        Some(Command::Domain(command)) => return run_domain_command(command).await,
+        Some(Command::Queue(command)) => return run_queue_command(command).await,
+        Some(Command::Inbox(command)) => return run_inbox_command(command).await,
+        Some(Command::Instances) => return list_instances().await,
+        Some(Command::Relay(command)) => return run_relay_command(command).await,
+        None => {}
+    }
+    match master_key() {
+        Some(master_key) => {
+            let sealed = seal_plaintext_keys(master_key).await?;
+            if sealed > 0 {
+                info!("Encrypted the private keys of {} users", sealed);
+            }
+        }
+        None => warn!("No MASTER_KEY configured, private keys are stored unencrypted"),
+    }
 
-    if let Err(err) = user {
-        eprintln!("Error inserting user: {:?}", err);
-    } else {
-        info!("User inserted: {:?}", user.unwrap());
+    policy::reload().await?;
+    policy::spawn_reloader();
+    dedup::spawn_cleanup();
+    instances::load().await?;
+    delivery::load_schemes().await?;
+    instances::spawn_prober();
+    instances::spawn_gone_cleanup();
+    queue::spawn_workers();
+    inbox_queue::spawn_worker();
+    if *FEDERATION_ALLOWLIST {
+        info!("Allowlist mode, only federating with allowed domains");
     }
 
+    let db = DB.get().unwrap();
+    let service_actor = service_actor::ensure().await?;
+    versia::keys::load_instance_key(service_actor.clone()).await?;
+
     let state: State = State {
         database_connection: Arc::new(db.clone()),
     };
@@ -270,7 +590,11 @@ async fn main() -> actix_web::Result<(), anyhow::Error> {
         .domain(FEDERATED_DOMAIN.to_string())
         .app_data(state.clone())
         .http_signature_compat(true)
-        .signed_fetch_actor(&state.local_user().await.unwrap())
+        .client(fetcher().federation_client())
+        .url_verifier(Box::new(PublicUrlVerifier))
+        .request_timeout(FetchPolicy::default().timeout)
+        .http_fetch_limit(*MAX_FETCHES_PER_ACTIVITY)
+        .signed_fetch_actor(&service_actor)
         .build()
         .await?;
 
@@ -286,6 +610,15 @@ async fn main() -> actix_web::Result<(), anyhow::Error> {
         .const_labels(labels)
         .build()
         .unwrap();
+    prometheus
+        .registry
+        .register(Box::new(limits::LIMITED_REQUESTS.clone()))?;
+    prometheus
+        .registry
+        .register(Box::new(queue::QUEUE_DEPTH.clone()))?;
+    prometheus
+        .registry
+        .register(Box::new(inbox_queue::INBOX_QUEUE_DEPTH.clone()))?;
 
     let http_server = HttpServer::new(move || {
         App::new()
@@ -296,6 +629,8 @@ async fn main() -> actix_web::Result<(), anyhow::Error> {
             .service(post_manually)
             .service(versia_inbox)
             .service(follow_manually)
+            // before /{user}, which would take it for a username
+            .service(fetch_service_actor)
             .route("/{user}", web::get().to(http_get_user))
             .route("/{user}/inbox", web::post().to(http_post_user_inbox))
             .route(
@@ -303,27 +638,43 @@ async fn main() -> actix_web::Result<(), anyhow::Error> {
                 web::post().to(http_post_user_inbox),
             )
             .route("/.well-known/webfinger", web::get().to(webfinger))
+            .route("/.well-known/host-meta", web::get().to(host_meta))
+            .route("/.well-known/host-meta.json", web::get().to(host_meta_json))
             .service(index)
             .service(fetch_post)
+            .service(fetch_replies)
+            .service(fetch_context)
             .service(fetch_user)
+            .service(fetch_followers)
             .service(create_activity)
             .service(query_post)
             .service(fetch_versia_post)
+            .service(versia_metadata)
     })
     .bind(SERVER_URL.to_string())?
     .workers(num_cpus::get())
-    .shutdown_timeout(20)
+    .shutdown_timeout(*SHUTDOWN_TIMEOUT)
+    .disable_signals()
     .keep_alive(KeepAlive::Os)
     .run();
-
+    let server = http_server.handle();
     tokio::spawn(http_server);
 
-    match signal::ctrl_c().await {
-        Ok(()) => {}
-        Err(err) => {
-            eprintln!("Unable to listen for shutdown signal: {}", err);
-            // we also shut down in case of error
-        }
+    shutdown::signal().await;
+    info!(
+        "Shutting down, waiting up to {}s for in-flight work",
+        *SHUTDOWN_TIMEOUT
+    );
+    shutdown::begin();
+    let drained = tokio::time::timeout(Duration::from_secs(*SHUTDOWN_TIMEOUT), async {
+        // requests still being answered may queue more work, so the queues are only drained
+        // once the server has stopped; anything left is kept for the restart
+        server.stop(true).await;
+        tokio::join!(queue::drain(), inbox_queue::drain());
+    })
+    .await;
+    if drained.is_err() {
+        warn!("Shutdown timed out, unfinished work is picked up again after the restart");
     }
 
     info!("Main thread shutdown..");
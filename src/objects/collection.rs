@@ -0,0 +1,98 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use url::Url;
+
+/// ActivityStreams allows most references to be either a bare link or the embedded object
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum LinkOrObject<T> {
+    Link(Url),
+    Object(Box<T>),
+}
+
+/// Any object we only care about the id of
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct IdOnly {
+    pub id: Url,
+}
+
+impl LinkOrObject<IdOnly> {
+    pub fn id(&self) -> &Url {
+        match self {
+            LinkOrObject::Link(url) => url,
+            LinkOrObject::Object(object) => &object.id,
+        }
+    }
+}
+
+/// Collections, ordered collections and their pages
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Collection {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Url>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_items: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ordered_items: Vec<LinkOrObject<IdOnly>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub items: Vec<LinkOrObject<IdOnly>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first: Option<LinkOrObject<Collection>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<LinkOrObject<Collection>>,
+}
+
+impl Collection {
+    pub fn ordered(id: Url, items: Vec<Url>) -> Collection {
+        Collection {
+            kind: "OrderedCollection".to_string(),
+            id: Some(id),
+            total_items: Some(items.len() as u64),
+            ordered_items: items.into_iter().map(LinkOrObject::Link).collect(),
+            items: vec![],
+            first: None,
+            next: None,
+        }
+    }
+
+    /// Item ids of this page, regardless of whether the collection is ordered
+    pub fn item_ids(&self) -> impl Iterator<Item = &Url> {
+        self.ordered_items
+            .iter()
+            .chain(self.items.iter())
+            .map(|item| item.id())
+    }
+}
+
+/// Deserializes an optional reference, dropping values we don't understand instead of
+/// rejecting the whole object. Remote software is creative with `replies` and `context`.
+pub fn deserialize_lenient<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: serde::de::DeserializeOwned,
+{
+    let value = serde_json::Value::deserialize(deserializer)?;
+    Ok(serde_json::from_value(value).ok())
+}
+
+/// Deserializes one value or a list of them, skipping the entries we don't understand
+pub fn deserialize_one_or_many_lenient<'de, D, T>(
+    deserializer: D,
+) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: serde::de::DeserializeOwned,
+{
+    let values = match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Array(values) => values,
+        value => vec![value],
+    };
+    Ok(Some(
+        values
+            .into_iter()
+            .filter_map(|value| serde_json::from_value(value).ok())
+            .collect(),
+    ))
+}
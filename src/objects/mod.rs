@@ -1,2 +1,3 @@
+pub mod collection;
 pub mod person;
 pub mod post;
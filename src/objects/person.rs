@@ -1,18 +1,24 @@
 use crate::{
     activities::{
+        announce::Announce,
         create_post::CreatePost,
         follow::{self, Follow},
     },
+    crypto,
     database::{State, StateHandle},
     entities::{self, user},
     error::Error,
+    integrity_proofs::{local_multikey, Multikey},
+    objects::collection::deserialize_one_or_many_lenient,
+    service_actor::actor_url,
+    utils::generate_followers_id,
+    versia::keys::{ensure_versia_keys, generate_versia_keypair},
     API_DOMAIN,
 };
 use activitypub_federation::{
     config::Data,
     fetch::object_id::ObjectId,
     http_signatures::generate_actor_keypair,
-    kinds::actor::PersonType,
     protocol::{public_key::PublicKey, verification::verify_domains_match},
     traits::{ActivityHandler, Actor, Object},
 };
@@ -22,7 +28,7 @@ use entities::prelude::User;
 use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, Set};
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
-use tracing::info;
+use tracing::{error, info};
 use url::Url;
 use uuid::Uuid;
 
@@ -48,6 +54,7 @@ pub enum PersonAcceptedActivities {
     CreateNote(CreatePost),
     Follow(Follow),
     Accept(follow::Accept),
+    Announce(Announce),
 }
 
 impl DbUser {
@@ -68,12 +75,24 @@ impl DbUser {
     }
 }
 
+/// Actor types we accept. Relays usually present themselves as a Service or Application.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum ActorKind {
+    #[default]
+    Person,
+    Service,
+    Application,
+    Group,
+    Organization,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Person {
     #[serde(rename = "type")]
-    pub kind: PersonType,
+    pub kind: ActorKind,
     pub preferred_username: String,
+    #[serde(default)]
     pub name: String,
     pub summary: Option<String>,
     pub url: Url,
@@ -108,6 +127,13 @@ pub struct Person {
     pub attachment: Option<Vec<AttachmentType>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub also_known_as: Option<Vec<Url>>,
+    /// FEP-521a keys the actor signs object integrity proofs with
+    #[serde(
+        default,
+        deserialize_with = "deserialize_one_or_many_lenient",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub assertion_method: Option<Vec<Multikey>>,
 }
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct TagType {
@@ -175,7 +201,15 @@ impl Object for user::Model {
     }
 
     async fn into_json(self, _data: &Data<Self::DataType>) -> Result<Self::Kind, Self::Error> {
-        let serialized = serde_json::from_str(self.ap_json.as_ref().unwrap().as_str())?;
+        let mut serialized: Person = serde_json::from_str(self.ap_json.as_ref().unwrap().as_str())?;
+        // bridged users publish the key their activities' integrity proofs are made with
+        if self.local {
+            let user = ensure_versia_keys(self).await?;
+            serialized.assertion_method =
+                local_multikey(serialized.id.inner(), &user)?.map(|key| vec![key]);
+            // replies addressed to it are forwarded to the followers by `forwarding`
+            serialized.followers = Some(generate_followers_id(&API_DOMAIN, &user.id)?);
+        }
         Ok(serialized)
     }
 
@@ -193,15 +227,33 @@ impl Object for user::Model {
             .filter(user::Column::Url.eq(json.id.inner().as_str()))
             .one(data.database_connection.as_ref())
             .await?;
+        let shared_inbox = json
+            .endpoints
+            .as_ref()
+            .map(|endpoints| endpoints.shared_inbox.to_string());
         if let Some(user) = query {
-            return Ok(user);
+            if user.local {
+                return Ok(user);
+            }
+            // a refetch, keep what deliveries depend on current
+            let model = user::ActiveModel {
+                id: Set(user.id),
+                inbox: Set(json.inbox.to_string()),
+                shared_inbox: Set(shared_inbox),
+                last_refreshed_at: Set(Utc::now()),
+                ap_json: Set(Some(serde_json::to_string(&json)?)),
+                ..Default::default()
+            };
+            return Ok(model.update(data.database_connection.as_ref()).await?);
         }
         let copied_json = json.clone();
+        let (versia_public_key, versia_private_key) = generate_versia_keypair()?;
         let model = user::ActiveModel {
             id: Set(Uuid::now_v7().to_string()),
             username: Set(json.preferred_username),
             name: Set(json.name),
             inbox: Set(json.inbox.to_string()),
+            shared_inbox: Set(shared_inbox),
             public_key: Set(json.public_key.public_key_pem),
             local: Set(false),
             summary: Set(json.summary),
@@ -211,6 +263,8 @@ impl Object for user::Model {
             created_at: Set(Utc::now()),
             last_refreshed_at: Set(Utc::now()),
             ap_json: Set(Some(serde_json::to_string(&copied_json).unwrap())),
+            versia_public_key: Set(Some(versia_public_key)),
+            versia_private_key: Set(Some(versia_private_key)),
             ..Default::default()
         };
         let model = model.insert(data.database_connection.as_ref()).await;
@@ -226,6 +280,11 @@ impl Object for user::Model {
 
 impl Actor for user::Model {
     fn id(&self) -> Url {
+        // the service actor has a stable url of its own
+        let service_actor = actor_url();
+        if self.local && self.url == service_actor.as_str() {
+            return service_actor;
+        }
         Url::parse(&format!(
             "https://{}/apbridge/user/{}",
             API_DOMAIN.to_string(),
@@ -239,15 +298,23 @@ impl Actor for user::Model {
     }
 
     fn private_key_pem(&self) -> Option<String> {
-        self.private_key.clone()
+        let stored = self.private_key.as_ref()?;
+        match crypto::open(stored) {
+            Ok(private_key) => Some(private_key),
+            Err(err) => {
+                error!("Could not unseal private key of {}: {}", self.id, err);
+                None
+            }
+        }
     }
 
     fn inbox(&self) -> Url {
         Url::parse(&self.inbox).unwrap()
     }
 
-    //TODO: Differenciate shared inbox
     fn shared_inbox(&self) -> Option<Url> {
-        None
+        self.shared_inbox
+            .as_ref()
+            .and_then(|shared_inbox| Url::parse(shared_inbox).ok())
     }
 }
@@ -3,21 +3,31 @@ use crate::{
     database::StateHandle,
     entities::{post, prelude::Post, user},
     error::Error,
-    objects::person::DbUser,
-    utils::generate_object_id,
+    objects::{
+        collection::{deserialize_lenient, Collection, LinkOrObject},
+        person::DbUser,
+    },
+    utils::{generate_followers_id, generate_object_id, generate_replies_id},
     versia::conversion::db_user_from_url,
+    API_DOMAIN, BACKFILL_CONCURRENCY, FEDERATED_DOMAIN,
 };
 use activitypub_federation::{
     config::Data,
-    fetch::object_id::ObjectId,
+    fetch::{fetch_object_http, object_id::ObjectId},
     kinds::{object::NoteType, public},
     protocol::{helpers::deserialize_one_or_many, verification::verify_domains_match},
     traits::{Actor, Object},
 };
 use activitystreams_kinds::link::MentionType;
+use once_cell::sync::Lazy;
 use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, Set};
 use serde::{Deserialize, Serialize};
-use tracing::info;
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{info, warn};
 use url::Url;
 use uuid::Uuid;
 
@@ -46,12 +56,155 @@ pub struct Note {
     pub(crate) sensitive: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) cc: Option<Vec<Url>>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_lenient",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) replies: Option<Box<LinkOrObject<Collection>>>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_lenient",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) context: Option<Box<LinkOrObject<Collection>>>,
 }
 
 impl Note {
     pub fn from_db(post: &post::Model) -> Self {
-        serde_json::from_str(&post.ap_json.as_ref().unwrap()).unwrap()
+        let mut note: Note = serde_json::from_str(&post.ap_json.as_ref().unwrap()).unwrap();
+        // bridged posts stored before replies collections existed
+        if note.replies.is_none() && post.local {
+            note.replies = generate_replies_id(&API_DOMAIN, &post.id)
+                .ok()
+                .map(|url| Box::new(LinkOrObject::Link(url)));
+        }
+        note
+    }
+
+    /// The conversation this note belongs to, if it is one we can serve
+    pub fn context_id(&self) -> Option<Url> {
+        match self.context.as_deref()? {
+            LinkOrObject::Link(url) => Some(url.clone()),
+            LinkOrObject::Object(collection) => collection.id.clone(),
+        }
+    }
+}
+
+/// The ActivityPub id of a stored post, which differs from `post.url` for bridged posts
+pub fn ap_id_of(post: &post::Model) -> Result<Url, Error> {
+    let note = post
+        .ap_json
+        .as_ref()
+        .and_then(|json| serde_json::from_str::<Note>(json).ok());
+    match note {
+        Some(note) => Ok(note.id.into_inner()),
+        None => Ok(generate_object_id(&API_DOMAIN, &post.id)?),
+    }
+}
+
+/// Most items we look at when backfilling a thread from `context` or `replies`
+const BACKFILL_MAX_ITEMS: usize = 50;
+/// Most collection pages we follow when backfilling
+const BACKFILL_MAX_PAGES: usize = 5;
+
+/// Backfills running at once. Threads beyond that are skipped rather than queued, the next
+/// reply to them gets another chance.
+static BACKFILLS: Lazy<Arc<Semaphore>> =
+    Lazy::new(|| Arc::new(Semaphore::new((*BACKFILL_CONCURRENCY).max(1))));
+
+/// Threads being backfilled, by their context or the note that started them
+static BACKFILLING: Lazy<Mutex<HashSet<Url>>> = Lazy::new(Default::default);
+
+/// A backfill slot for one thread, given back when dropped
+struct Backfill {
+    thread: Url,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Backfill {
+    fn start(thread: Url) -> Option<Backfill> {
+        let permit = BACKFILLS.clone().try_acquire_owned().ok()?;
+        if !BACKFILLING.lock().unwrap().insert(thread.clone()) {
+            return None;
+        }
+        Some(Backfill {
+            thread,
+            _permit: permit,
+        })
+    }
+}
+
+impl Drop for Backfill {
+    fn drop(&mut self) {
+        BACKFILLING.lock().unwrap().remove(&self.thread);
+    }
+}
+
+/// Backfills the thread of `note` in the background, unless it is being backfilled already
+/// or too many threads are
+pub fn spawn_backfill(note: Note, data: Data<StateHandle>) {
+    let thread = note.context_id().unwrap_or(note.id.inner().clone());
+    let Some(backfill) = Backfill::start(thread) else {
+        info!("Not backfilling the thread of {} now", note.id);
+        return;
+    };
+    tokio::spawn(async move {
+        backfill_thread(note, data).await;
+        drop(backfill);
+    });
+}
+
+/// Fetches the members of a remote thread we don't know yet into the `post` table
+async fn backfill_thread(note: Note, data: Data<StateHandle>) {
+    for collection in [note.context, note.replies].into_iter().flatten() {
+        if let Err(err) = backfill_collection(*collection, &data).await {
+            warn!("Failed to backfill thread of {}: {}", note.id, err);
+        }
+    }
+}
+
+async fn backfill_collection(
+    collection: LinkOrObject<Collection>,
+    data: &Data<StateHandle>,
+) -> Result<(), Error> {
+    let mut next_page = Some(collection);
+    let mut pages = 0;
+    let mut items = 0;
+    while let Some(page) = next_page.take() {
+        if pages >= BACKFILL_MAX_PAGES || items >= BACKFILL_MAX_ITEMS {
+            break;
+        }
+        pages += 1;
+        let page = match page {
+            LinkOrObject::Link(url) => {
+                if is_bridge_url(&url) {
+                    break;
+                }
+                fetch_object_http::<_, Collection>(&url, data).await?.object
+            }
+            LinkOrObject::Object(page) => *page,
+        };
+        for id in page.item_ids() {
+            if items >= BACKFILL_MAX_ITEMS {
+                break;
+            }
+            items += 1;
+            if let Err(err) = ObjectId::<post::Model>::from(id.clone())
+                .dereference(data)
+                .await
+            {
+                info!("Skipping thread member {}: {}", id, err);
+            }
+        }
+        next_page = page.first.or(page.next);
     }
+    Ok(())
+}
+
+fn is_bridge_url(url: &Url) -> bool {
+    url.host_str()
+        .is_some_and(|host| host == API_DOMAIN.as_str() || host == FEDERATED_DOMAIN.as_str())
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -71,26 +224,37 @@ impl Object for post::Model {
         object_id: Url,
         data: &Data<Self::DataType>,
     ) -> Result<Option<Self>, Self::Error> {
-        let post = crate::entities::prelude::Post::find()
-            .filter(post::Column::Id.eq(object_id.to_string()))
+        // bridged posts are stored under their Versia url, but federated as /apbridge/object/{id}
+        let bridged_id = if is_bridge_url(&object_id) {
+            match object_id.path_segments().map(|s| s.collect::<Vec<_>>()) {
+                Some(segments)
+                    if segments.len() == 3 && segments[..2] == ["apbridge", "object"] =>
+                {
+                    Some(segments[2].to_string())
+                }
+                _ => None,
+            }
+        } else {
+            None
+        };
+        let query = match bridged_id {
+            Some(id) => Post::find_by_id(id),
+            None => Post::find().filter(post::Column::Url.eq(object_id.to_string())),
+        };
+        let post = query
             .one(data.app_data().database_connection.clone().as_ref())
-            .await;
-        Ok(post.unwrap())
+            .await?;
+        Ok(post)
     }
 
     async fn into_json(self, _data: &Data<Self::DataType>) -> Result<Self::Kind, Self::Error> {
         let creator = db_user_from_url(Url::parse(self.creator.as_str()).unwrap()).await?;
+        let followers = generate_followers_id(&API_DOMAIN, &creator.id)?;
         let to = match self.visibility.as_str() {
-            "public" => vec![
-                public(),
-                Url::parse(creator.followers.unwrap().as_str()).unwrap(),
-            ],
-            "followers" => vec![Url::parse(creator.followers.unwrap().as_str()).unwrap()],
+            "public" => vec![public(), followers],
+            "followers" => vec![followers],
             "direct" => vec![], //TODO: implement this
-            "unlisted" => vec![
-                Url::parse(creator.followers.unwrap().as_str()).unwrap(),
-                public(),
-            ],
+            "unlisted" => vec![followers, public()],
             _ => vec![public()],
         };
         Ok(Note {
@@ -103,6 +267,11 @@ impl Object for post::Model {
             tag: vec![],
             sensitive: Some(self.sensitive),
             cc: Some(to),
+            replies: Some(Box::new(LinkOrObject::Link(generate_replies_id(
+                &API_DOMAIN,
+                &self.id,
+            )?))),
+            context: None,
         })
     }
 
@@ -128,6 +297,16 @@ impl Object for post::Model {
             return Ok(post);
         }
         let creator = json.attributed_to.dereference(data).await?;
+        let reply_id = match &json.in_reply_to {
+            Some(parent) => match parent.dereference(data).await {
+                Ok(parent) => Some(parent.id),
+                Err(err) => {
+                    warn!("Could not fetch parent {} of {}: {}", parent, json.id, err);
+                    None
+                }
+            },
+            None => None,
+        };
         let post: post::ActiveModel = post::ActiveModel {
             content: Set(json.content.clone()),
             id: Set(Uuid::now_v7().to_string()),
@@ -138,6 +317,7 @@ impl Object for post::Model {
             visibility: Set("public".to_string()), // TODO: make this use the real visibility
             sensitive: Set(json.sensitive.clone().unwrap_or_default()),
             url: Set(json.id.clone().to_string()),
+            reply_id: Set(reply_id),
             ap_json: Set(Some(serde_json::to_string(&json).unwrap())),
             ..Default::default()
         };
@@ -154,3 +334,28 @@ impl Object for post::Model {
         Ok(post.unwrap())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_backfill_slots() {
+        use super::Backfill;
+        use url::Url;
+
+        let thread = |n: usize| Url::parse(&format!("https://remote.example/context/{n}")).unwrap();
+        let first = Backfill::start(thread(0)).unwrap();
+        // one backfill per thread at a time
+        assert!(Backfill::start(thread(0)).is_none());
+        drop(first);
+        let first = Backfill::start(thread(0)).unwrap();
+
+        // four at once by default
+        let mut running: Vec<_> = (1..4)
+            .map(|n| Backfill::start(thread(n)).unwrap())
+            .collect();
+        assert!(Backfill::start(thread(4)).is_none());
+        running.pop();
+        assert!(Backfill::start(thread(4)).is_some());
+        drop(first);
+    }
+}
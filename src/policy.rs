@@ -0,0 +1,233 @@
+use std::{sync::RwLock, time::Duration};
+
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use sea_orm::{ActiveModelTrait, EntityTrait, QueryOrder, Set};
+use tracing::{info, warn};
+use url::Url;
+
+use crate::{
+    entities::{domain_policy, prelude},
+    API_DOMAIN, BLOCKED_DOMAINS, DB, FEDERATED_DOMAIN, FEDERATION_ALLOWLIST, LYSAND_DOMAIN,
+};
+
+/// How often policies changed from the command line are picked up by a running bridge
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What the bridge does with a remote domain, merged from every policy covering it.
+/// A policy for `example.com` also covers all of its subdomains.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DomainPolicy {
+    /// Refuse all activities and fetches, and deliver nothing to the domain
+    pub reject: bool,
+    /// Only bridge the domain's posts to Versia users following their author
+    pub silence: bool,
+    /// Drop avatars, headers and emojis of the domain's users
+    pub strip_media: bool,
+    /// Drop reports (`Flag` activities) coming from the domain
+    pub reject_reports: bool,
+    /// Listed on the allowlist, the only domains federated with in allowlist mode
+    pub allow: bool,
+}
+
+impl DomainPolicy {
+    /// Merges the stored policies of a host, its parent domains and the global settings
+    pub fn merge<'a>(
+        host: &str,
+        policies: impl IntoIterator<Item = &'a domain_policy::Model>,
+        blocked_domains: &[String],
+        allowlist_mode: bool,
+    ) -> DomainPolicy {
+        let host = host.trim_end_matches('.').to_lowercase();
+        let mut merged = DomainPolicy {
+            reject: blocked_domains.iter().any(|blocked| covers(blocked, &host)),
+            ..Default::default()
+        };
+        for policy in policies
+            .into_iter()
+            .filter(|policy| covers(&policy.domain, &host))
+        {
+            merged.reject |= policy.reject;
+            merged.silence |= policy.silence;
+            merged.strip_media |= policy.strip_media;
+            merged.reject_reports |= policy.reject_reports;
+            merged.allow |= policy.allow;
+        }
+        if allowlist_mode && !merged.allow {
+            merged.reject = true;
+        }
+        merged
+    }
+}
+
+/// Whether a policy for `domain` applies to `host`
+fn covers(domain: &str, host: &str) -> bool {
+    host == domain || host.ends_with(&format!(".{domain}"))
+}
+
+static POLICIES: Lazy<RwLock<Vec<domain_policy::Model>>> = Lazy::new(|| RwLock::new(vec![]));
+
+/// Loads all stored policies into memory, where every lookup is served from
+pub async fn reload() -> anyhow::Result<()> {
+    let policies = prelude::DomainPolicy::find().all(DB.get().unwrap()).await?;
+    *POLICIES.write().unwrap() = policies;
+    Ok(())
+}
+
+/// Keeps the in-memory policies in sync with the database
+pub fn spawn_reloader() {
+    tokio::spawn(async {
+        loop {
+            tokio::time::sleep(RELOAD_INTERVAL).await;
+            if let Err(err) = reload().await {
+                warn!("Could not reload domain policies: {}", err);
+            }
+        }
+    });
+}
+
+/// Our own domains and the Versia instance we bridge for are never subject to a policy
+fn is_own_host(host: &str) -> bool {
+    [
+        API_DOMAIN.as_str(),
+        FEDERATED_DOMAIN.as_str(),
+        LYSAND_DOMAIN.as_str(),
+    ]
+    .iter()
+    .any(|own| host.eq_ignore_ascii_case(own))
+}
+
+pub fn policy_for(host: &str) -> DomainPolicy {
+    if is_own_host(host) {
+        return DomainPolicy {
+            allow: true,
+            ..Default::default()
+        };
+    }
+    DomainPolicy::merge(
+        host,
+        POLICIES.read().unwrap().iter(),
+        &BLOCKED_DOMAINS,
+        *FEDERATION_ALLOWLIST,
+    )
+}
+
+/// The policy of the host of `url`, urls without one are rejected
+pub fn policy_for_url(url: &Url) -> DomainPolicy {
+    match url.host_str() {
+        Some(host) => policy_for(host),
+        None => DomainPolicy {
+            reject: true,
+            ..Default::default()
+        },
+    }
+}
+
+pub fn federates_with(url: &Url) -> bool {
+    !policy_for_url(url).reject
+}
+
+/// Drops the inboxes on domains we don't deliver to
+pub fn deliverable(inboxes: Vec<Url>) -> Vec<Url> {
+    inboxes
+        .into_iter()
+        .filter(|inbox| {
+            let allowed = federates_with(inbox);
+            if !allowed {
+                info!("Not delivering to {}, its domain is rejected", inbox);
+            }
+            allowed
+        })
+        .collect()
+}
+
+pub async fn list_policies() -> anyhow::Result<Vec<domain_policy::Model>> {
+    Ok(prelude::DomainPolicy::find()
+        .order_by_asc(domain_policy::Column::Domain)
+        .all(DB.get().unwrap())
+        .await?)
+}
+
+/// Creates or replaces the policy of `domain`
+pub async fn set_policy(
+    domain: &str,
+    policy: &DomainPolicy,
+    reason: Option<String>,
+) -> anyhow::Result<domain_policy::Model> {
+    let db = DB.get().unwrap();
+    let domain = domain.trim().trim_end_matches('.').to_lowercase();
+    let existing = prelude::DomainPolicy::find_by_id(domain.as_str())
+        .one(db)
+        .await?;
+    let model = domain_policy::ActiveModel {
+        domain: Set(domain),
+        reject: Set(policy.reject),
+        silence: Set(policy.silence),
+        strip_media: Set(policy.strip_media),
+        reject_reports: Set(policy.reject_reports),
+        allow: Set(policy.allow),
+        reason: Set(reason),
+        created_at: Set(existing
+            .as_ref()
+            .map(|existing| existing.created_at)
+            .unwrap_or(Utc::now())),
+    };
+    Ok(match existing {
+        Some(_) => model.update(db).await?,
+        None => model.insert(db).await?,
+    })
+}
+
+/// Returns whether there was a policy to remove
+pub async fn remove_policy(domain: &str) -> anyhow::Result<bool> {
+    let result = prelude::DomainPolicy::delete_by_id(domain.trim().to_lowercase())
+        .exec(DB.get().unwrap())
+        .await?;
+    Ok(result.rows_affected > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_domain_policy_merge() {
+        use super::DomainPolicy;
+        use crate::entities::domain_policy;
+
+        let stored =
+            |domain: &str, reject: bool, silence: bool, allow: bool| domain_policy::Model {
+                domain: domain.to_string(),
+                reject,
+                silence,
+                strip_media: false,
+                reject_reports: false,
+                allow,
+                reason: None,
+                created_at: chrono::Utc::now(),
+            };
+        let policies = vec![
+            stored("bad.example", true, false, false),
+            stored("loud.example", false, true, false),
+            stored("friend.example", false, false, true),
+        ];
+        let merge = |host: &str, allowlist_mode: bool| {
+            DomainPolicy::merge(
+                host,
+                &policies,
+                &["blocked.example".to_string()],
+                allowlist_mode,
+            )
+        };
+
+        assert!(merge("bad.example", false).reject);
+        assert!(merge("social.BAD.example.", false).reject);
+        assert!(!merge("notbad.example", false).reject);
+        assert!(merge("blocked.example", false).reject);
+        let silenced = merge("loud.example", false);
+        assert!(silenced.silence && !silenced.reject);
+
+        assert!(!merge("friend.example", true).reject);
+        assert!(!merge("mastodon.friend.example", true).reject);
+        assert!(merge("stranger.example", true).reject);
+        assert!(!merge("stranger.example", false).reject);
+    }
+}
@@ -0,0 +1,541 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use prometheus::{IntGaugeVec, Opts};
+use rand::Rng;
+use reqwest::StatusCode;
+use sea_orm::{
+    sea_query::Expr, ColumnTrait, Condition, DbErr, EntityTrait, PaginatorTrait, QueryFilter,
+    QueryOrder, QuerySelect, Set,
+};
+use tokio::sync::{Notify, Semaphore};
+use tracing::{info, warn};
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    delivery::{deliver_to, DeliveryError},
+    entities::{delivery_job, prelude, user},
+    fetcher::FetchError,
+    instances::{paused_until, record_delivery},
+    shutdown,
+    versia::signatures::signed_versia_body,
+    DB, DELIVERY_CONCURRENCY, DELIVERY_HOST_CONCURRENCY, DELIVERY_MAX_ATTEMPTS,
+};
+
+const PENDING: &str = "pending";
+const DEAD: &str = "dead";
+
+/// How long a claimed job is left to its worker before it is up for grabs again. This is
+/// also how jobs interrupted by a restart get picked up again.
+const LEASE: Duration = Duration::from_secs(5 * 60);
+
+/// How often workers look for due jobs when nothing new was queued
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+const BATCH_SIZE: u64 = 200;
+
+/// How often the queue depth metric is updated
+const DEPTH_INTERVAL: Duration = Duration::from_secs(15);
+
+const FIRST_RETRY: Duration = Duration::from_secs(30);
+const MAX_RETRY: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Wakes the workers when jobs are queued, so deliveries don't wait for the next poll
+static QUEUED: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// What the target inbox speaks, which decides how a job is signed and sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    ActivityPub,
+    Versia,
+}
+
+impl Protocol {
+    fn as_str(self) -> &'static str {
+        match self {
+            Protocol::ActivityPub => "activitypub",
+            Protocol::Versia => "versia",
+        }
+    }
+
+    fn parse(protocol: &str) -> Option<Protocol> {
+        match protocol {
+            "activitypub" => Some(Protocol::ActivityPub),
+            "versia" => Some(Protocol::Versia),
+            _ => None,
+        }
+    }
+}
+
+/// The delay before retrying a job that failed `attempts` times: doubling from 30 seconds
+/// up to 12 hours, with up to 10% jitter so retries to one host spread out
+pub fn backoff(attempts: i32) -> Duration {
+    let doublings = attempts.saturating_sub(1).clamp(0, 20) as u32;
+    let delay = FIRST_RETRY
+        .saturating_mul(2u32.pow(doublings))
+        .min(MAX_RETRY);
+    delay.mul_f64(1.0 + rand::thread_rng().gen_range(0.0..0.1))
+}
+
+/// Queues `body` for delivery to every inbox, signed as `signer`
+pub async fn enqueue(
+    protocol: Protocol,
+    inboxes: Vec<Url>,
+    body: String,
+    signer: &user::Model,
+) -> Result<(), DbErr> {
+    if inboxes.is_empty() {
+        return Ok(());
+    }
+    let now = Utc::now();
+    let jobs = inboxes.into_iter().map(|inbox| delivery_job::ActiveModel {
+        id: Set(Uuid::now_v7().to_string()),
+        protocol: Set(protocol.as_str().to_string()),
+        inbox: Set(inbox.to_string()),
+        signer_id: Set(signer.id.clone()),
+        body: Set(body.clone()),
+        status: Set(PENDING.to_string()),
+        attempts: Set(0),
+        next_attempt_at: Set(now),
+        last_error: Set(None),
+        created_at: Set(now),
+        host: Set(inbox.host_str().map(str::to_string)),
+    });
+    prelude::DeliveryJob::insert_many(jobs)
+        .exec_without_returning(DB.get().unwrap())
+        .await?;
+    QUEUED.notify_one();
+    Ok(())
+}
+
+/// Takes a job for this worker by moving its next attempt past the lease. Only one worker
+/// can move it from the time it was read with.
+async fn claim(job: &delivery_job::Model) -> Result<bool, DbErr> {
+    let leased_until = Utc::now() + chrono::Duration::from_std(LEASE).unwrap();
+    let result = prelude::DeliveryJob::update_many()
+        .col_expr(
+            delivery_job::Column::NextAttemptAt,
+            Expr::value(leased_until),
+        )
+        .filter(delivery_job::Column::Id.eq(job.id.as_str()))
+        .filter(delivery_job::Column::Status.eq(PENDING))
+        .filter(delivery_job::Column::NextAttemptAt.eq(job.next_attempt_at))
+        .exec(DB.get().unwrap())
+        .await?;
+    Ok(result.rows_affected == 1)
+}
+
+async fn attempt(job: &delivery_job::Model) -> Result<(), DeliveryError> {
+    let inbox = Url::parse(&job.inbox).map_err(anyhow::Error::from)?;
+    let host = inbox.host_str().unwrap_or_default();
+    if paused_until(host).is_some() {
+        return Err(DeliveryError::Paused(host.to_string()));
+    }
+    let signer = prelude::User::find_by_id(job.signer_id.as_str())
+        .one(DB.get().unwrap())
+        .await
+        .map_err(anyhow::Error::from)?
+        .ok_or(anyhow::anyhow!("Signer {} no longer exists", job.signer_id))?;
+    match Protocol::parse(&job.protocol) {
+        Some(Protocol::ActivityPub) => deliver_to(&inbox, job.body.as_bytes(), &signer).await,
+        Some(Protocol::Versia) => {
+            let response = signed_versia_body(&inbox, job.body.clone().into_bytes(), signer)
+                .await?
+                .send()
+                .await
+                .map_err(FetchError::from)?;
+            match response.status().is_success() {
+                true => Ok(()),
+                false => Err(DeliveryError::Status(inbox, response.status())),
+            }
+        }
+        None => Err(anyhow::anyhow!("Unknown protocol {}", job.protocol).into()),
+    }
+}
+
+/// Whether retrying can't help: the inbox refused the request itself
+fn is_permanent(err: &DeliveryError) -> bool {
+    match err {
+        DeliveryError::Status(_, status) => {
+            status.is_client_error()
+                && ![
+                    StatusCode::UNAUTHORIZED,
+                    StatusCode::REQUEST_TIMEOUT,
+                    StatusCode::TOO_MANY_REQUESTS,
+                ]
+                .contains(status)
+        }
+        _ => false,
+    }
+}
+
+/// Deletes a delivered job, or schedules its retry. Jobs out of attempts, or refused for
+/// good, are kept as dead for an operator to look at.
+async fn finish(job: delivery_job::Model, result: Result<(), DeliveryError>) -> Result<(), DbErr> {
+    let db = DB.get().unwrap();
+    let err = match result {
+        Ok(()) => {
+            prelude::DeliveryJob::delete_by_id(job.id).exec(db).await?;
+            return Ok(());
+        }
+        Err(err) => err,
+    };
+    // the job wasn't tried, so it keeps its attempts until the instance is probed again
+    if let DeliveryError::Paused(host) = &err {
+        let retry_at = paused_until(host).unwrap_or(Utc::now());
+        prelude::DeliveryJob::update(delivery_job::ActiveModel {
+            id: Set(job.id),
+            next_attempt_at: Set(retry_at),
+            ..Default::default()
+        })
+        .exec(db)
+        .await?;
+        return Ok(());
+    }
+    let attempts = job.attempts + 1;
+    let mut model = delivery_job::ActiveModel {
+        id: Set(job.id.clone()),
+        attempts: Set(attempts),
+        last_error: Set(Some(err.to_string())),
+        ..Default::default()
+    };
+    if is_permanent(&err) || attempts >= *DELIVERY_MAX_ATTEMPTS {
+        warn!(
+            "Giving up on delivering to {} after {} attempts: {}",
+            job.inbox, attempts, err
+        );
+        model.status = Set(DEAD.to_string());
+    } else {
+        let retry_at = Utc::now() + chrono::Duration::from_std(backoff(attempts)).unwrap();
+        info!(
+            "Delivery to {} failed, retrying at {}: {}",
+            job.inbox, retry_at, err
+        );
+        model.next_attempt_at = Set(retry_at);
+    }
+    prelude::DeliveryJob::update(model).exec(db).await?;
+    Ok(())
+}
+
+/// Orders jobs so hosts take turns: the first job of every host, then the second of every
+/// host, and so on. Jobs of one host keep their order.
+pub fn round_robin<T>(jobs: Vec<T>, host: impl Fn(&T) -> String) -> Vec<T> {
+    let mut hosts: Vec<(String, Vec<T>)> = Vec::new();
+    for job in jobs {
+        let key = host(&job);
+        match hosts.iter_mut().find(|(host, _)| *host == key) {
+            Some((_, queue)) => queue.push(job),
+            None => hosts.push((key, vec![job])),
+        }
+    }
+    let mut queues: Vec<_> = hosts
+        .into_iter()
+        .map(|(_, queue)| queue.into_iter())
+        .collect();
+    let mut ordered = Vec::new();
+    loop {
+        let before = ordered.len();
+        ordered.extend(queues.iter_mut().filter_map(|queue| queue.next()));
+        if ordered.len() == before {
+            return ordered;
+        }
+    }
+}
+
+fn job_host(job: &delivery_job::Model) -> String {
+    job.host
+        .clone()
+        .or_else(|| {
+            Url::parse(&job.inbox)
+                .ok()
+                .and_then(|inbox| inbox.host_str().map(str::to_string))
+        })
+        .unwrap_or_default()
+}
+
+/// Deliveries in flight per host
+static IN_FLIGHT: Lazy<Mutex<HashMap<String, usize>>> = Lazy::new(Default::default);
+
+/// Limits deliveries in flight overall
+static WORKERS: Lazy<Arc<Semaphore>> =
+    Lazy::new(|| Arc::new(Semaphore::new((*DELIVERY_CONCURRENCY).max(1))));
+
+/// Queued deliveries by status, and how many are in flight
+pub static QUEUE_DEPTH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    IntGaugeVec::new(
+        Opts::new("delivery_queue_depth", "Outbound deliveries by status")
+            .namespace("activitypub_bridge"),
+        &["status"],
+    )
+    .unwrap()
+});
+
+/// A delivery's share of the per-host limit, given back when it ends
+struct HostSlot(String);
+
+impl HostSlot {
+    fn take(host: String) -> Option<HostSlot> {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        let count = in_flight.entry(host.clone()).or_default();
+        if *count >= (*DELIVERY_HOST_CONCURRENCY).max(1) {
+            return None;
+        }
+        *count += 1;
+        Some(HostSlot(host))
+    }
+}
+
+impl Drop for HostSlot {
+    fn drop(&mut self) {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.0) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.0);
+            }
+        }
+        // the host may have due jobs that were held back
+        QUEUED.notify_one();
+    }
+}
+
+/// Hosts that have as many deliveries in flight as they may
+fn busy_hosts() -> Vec<String> {
+    IN_FLIGHT
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, count)| **count >= (*DELIVERY_HOST_CONCURRENCY).max(1))
+        .map(|(host, _)| host.clone())
+        .collect()
+}
+
+async fn due_jobs() -> Result<Vec<delivery_job::Model>, DbErr> {
+    let mut query = prelude::DeliveryJob::find()
+        .filter(delivery_job::Column::Status.eq(PENDING))
+        .filter(delivery_job::Column::NextAttemptAt.lte(Utc::now()));
+    // a big backlog for a busy host must not hide the jobs of everyone else
+    let busy = busy_hosts();
+    if !busy.is_empty() {
+        query = query.filter(
+            Condition::any()
+                .add(delivery_job::Column::Host.is_null())
+                .add(delivery_job::Column::Host.is_not_in(busy)),
+        );
+    }
+    query
+        .order_by_asc(delivery_job::Column::NextAttemptAt)
+        .limit(BATCH_SIZE)
+        .all(DB.get().unwrap())
+        .await
+}
+
+async fn run(job: delivery_job::Model) {
+    let result = attempt(&job).await;
+    if let Ok(inbox) = Url::parse(&job.inbox) {
+        record_delivery(&inbox, &result).await;
+    }
+    let id = job.id.clone();
+    if let Err(err) = finish(job, result).await {
+        warn!("Could not update delivery {}: {}", id, err);
+    }
+}
+
+/// Hands the due jobs to workers, hosts taking turns, returning how many were started
+pub async fn dispatch() -> Result<usize, DbErr> {
+    let mut started = 0;
+    for job in round_robin(due_jobs().await?, job_host) {
+        let Some(slot) = HostSlot::take(job_host(&job)) else {
+            continue;
+        };
+        let permit = WORKERS.clone().acquire_owned().await.unwrap();
+        // the rest stay queued for after the restart
+        if shutdown::stopping() {
+            break;
+        }
+        if !claim(&job).await? {
+            continue;
+        }
+        tokio::spawn(async move {
+            run(job).await;
+            drop(permit);
+            drop(slot);
+        });
+        started += 1;
+    }
+    Ok(started)
+}
+
+async fn update_queue_depth() -> Result<(), DbErr> {
+    let (pending, dead) = counts().await?;
+    let in_flight = (*DELIVERY_CONCURRENCY).max(1) - WORKERS.available_permits();
+    QUEUE_DEPTH
+        .with_label_values(&["pending"])
+        .set(pending as i64);
+    QUEUE_DEPTH.with_label_values(&["dead"]).set(dead as i64);
+    QUEUE_DEPTH
+        .with_label_values(&["in_flight"])
+        .set(in_flight as i64);
+    Ok(())
+}
+
+/// Delivers queued jobs in the background, including those left over from before a restart
+pub fn spawn_workers() {
+    tokio::spawn(async {
+        while !shutdown::stopping() {
+            match dispatch().await {
+                Ok(started) if started > 0 => continue,
+                Ok(_) => {}
+                Err(err) => warn!("Could not work off the delivery queue: {}", err),
+            }
+            tokio::select! {
+                _ = QUEUED.notified() => {}
+                _ = shutdown::stopped() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    });
+    tokio::spawn(async {
+        loop {
+            if let Err(err) = update_queue_depth().await {
+                warn!("Could not measure the delivery queue: {}", err);
+            }
+            tokio::time::sleep(DEPTH_INTERVAL).await;
+        }
+    });
+}
+
+/// Waits for the deliveries in flight once shutdown has begun. Deliveries still running
+/// when the process exits keep their lease and are retried after a restart.
+pub async fn drain() {
+    let workers = (*DELIVERY_CONCURRENCY).max(1) as u32;
+    let _ = WORKERS.acquire_many(workers).await;
+}
+
+/// Number of pending and dead jobs
+pub async fn counts() -> Result<(u64, u64), DbErr> {
+    let db = DB.get().unwrap();
+    let count = |status: &'static str| {
+        prelude::DeliveryJob::find()
+            .filter(delivery_job::Column::Status.eq(status))
+            .count(db)
+    };
+    Ok((count(PENDING).await?, count(DEAD).await?))
+}
+
+pub async fn dead_jobs() -> Result<Vec<delivery_job::Model>, DbErr> {
+    prelude::DeliveryJob::find()
+        .filter(delivery_job::Column::Status.eq(DEAD))
+        .order_by_asc(delivery_job::Column::CreatedAt)
+        .all(DB.get().unwrap())
+        .await
+}
+
+/// Gives dead jobs, or only the one with `id`, a fresh set of attempts
+pub async fn retry_dead(id: Option<String>) -> Result<u64, DbErr> {
+    let mut query = prelude::DeliveryJob::update_many()
+        .col_expr(delivery_job::Column::Status, Expr::value(PENDING))
+        .col_expr(delivery_job::Column::Attempts, Expr::value(0))
+        .col_expr(delivery_job::Column::NextAttemptAt, Expr::value(Utc::now()))
+        .filter(delivery_job::Column::Status.eq(DEAD));
+    if let Some(id) = id {
+        query = query.filter(delivery_job::Column::Id.eq(id));
+    }
+    Ok(query.exec(DB.get().unwrap()).await?.rows_affected)
+}
+
+pub async fn purge_dead() -> Result<u64, DbErr> {
+    Ok(prelude::DeliveryJob::delete_many()
+        .filter(delivery_job::Column::Status.eq(DEAD))
+        .exec(DB.get().unwrap())
+        .await?
+        .rows_affected)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_delivery_backoff() {
+        use super::backoff;
+        use std::time::Duration;
+
+        let within = |delay: Duration, expected: u64| {
+            delay >= Duration::from_secs(expected)
+                && delay <= Duration::from_secs(expected * 11 / 10)
+        };
+        assert!(within(backoff(1), 30));
+        assert!(within(backoff(2), 60));
+        assert!(within(backoff(5), 480));
+        assert!(within(backoff(30), 12 * 60 * 60));
+        assert!(within(backoff(i32::MAX), 12 * 60 * 60));
+    }
+
+    #[test]
+    fn test_delivery_round_robin() {
+        use super::round_robin;
+
+        let jobs = vec![
+            ("big.example", 1),
+            ("big.example", 2),
+            ("big.example", 3),
+            ("small.example", 4),
+            ("other.example", 5),
+            ("small.example", 6),
+        ];
+        let ordered: Vec<i32> = round_robin(jobs, |(host, _)| host.to_string())
+            .into_iter()
+            .map(|(_, job)| job)
+            .collect();
+        assert_eq!(ordered, vec![1, 4, 5, 2, 6, 3]);
+        assert!(round_robin(Vec::<(&str, i32)>::new(), |(host, _)| host.to_string()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_paused_keeps_attempts() {
+        use super::{attempt, enqueue, finish, Protocol};
+        use crate::{
+            entities::{delivery_job, prelude},
+            instances::{paused_until, record_failure},
+            testing,
+        };
+        use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+        use url::Url;
+
+        let db = testing::db().await;
+        let signer = testing::user("paused-signer", true).await;
+        for _ in 0..5 {
+            record_failure("paused.example").await.unwrap();
+        }
+        let until = paused_until("paused.example").unwrap();
+        let inbox = Url::parse("https://paused.example/inbox").unwrap();
+        enqueue(
+            Protocol::ActivityPub,
+            vec![inbox],
+            "{}".to_string(),
+            &signer,
+        )
+        .await
+        .unwrap();
+        let job = || async {
+            prelude::DeliveryJob::find()
+                .filter(delivery_job::Column::SignerId.eq(signer.id.as_str()))
+                .one(db)
+                .await
+                .unwrap()
+                .unwrap()
+        };
+
+        let result = attempt(&job().await).await;
+        assert!(matches!(result, Err(super::DeliveryError::Paused(_))));
+        finish(job().await, result).await.unwrap();
+        let job = job().await;
+        assert_eq!(job.attempts, 0);
+        assert!((job.next_attempt_at - until).num_seconds().abs() < 1);
+    }
+}
@@ -0,0 +1,246 @@
+//! Relay subscriptions. The service actor follows relays, which then announce the public
+//! posts of everyone subscribed. Those are bridged to the Versia instance's federated
+//! timeline, and with RELAY_PUBLISH our public posts go to the relays in turn.
+
+use activitypub_federation::{
+    config::Data, fetch::object_id::ObjectId, protocol::context::WithContext,
+};
+use activitystreams_kinds::{
+    activity::{AcceptType, FollowType, UndoType},
+    public,
+};
+use anyhow::anyhow;
+use chrono::Utc;
+use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, QueryOrder, Set};
+use serde::Deserialize;
+use tracing::{info, warn};
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    activities::follow::{Accept, Follow, Undo},
+    database::StateHandle,
+    delivery::deliver,
+    entities::{post, prelude, relay},
+    fetcher::fetcher,
+    policy::policy_for_url,
+    queue::{enqueue, Protocol},
+    service_actor,
+    utils::generate_random_object_id,
+    versia::{
+        conversion::versia_post_from_db, funcs::versia_shared_inbox, objects::SortAlphabetically,
+    },
+    API_DOMAIN, DB, LYSAND_DOMAIN, RELAY_PUBLISH,
+};
+
+const PENDING: &str = "pending";
+const ACCEPTED: &str = "accepted";
+
+#[derive(Deserialize)]
+struct RelayActor {
+    id: Url,
+    inbox: Url,
+}
+
+/// Follows a relay. LitePub relays are given by their actor and followed directly, Mastodon
+/// relays by their inbox and followed through the public collection.
+pub async fn subscribe(url: Url) -> anyhow::Result<relay::Model> {
+    let db = DB.get().unwrap();
+    if let Some(relay) = prelude::Relay::find()
+        .filter(relay::Column::Url.eq(url.as_str()))
+        .one(db)
+        .await?
+    {
+        return Ok(relay);
+    }
+    let host = url
+        .host_str()
+        .ok_or(anyhow!("{url} has no host"))?
+        .to_string();
+    let (actor, inbox, object) = if url.path().trim_end_matches('/').ends_with("/inbox") {
+        (None, url.clone(), public())
+    } else {
+        let actor = fetcher().get_json::<RelayActor>(&url).await?;
+        (Some(actor.id.clone()), actor.inbox, actor.id)
+    };
+
+    let service_actor = service_actor::find().await?;
+    let follow = Follow {
+        actor: ObjectId::from(Url::parse(&service_actor.url)?),
+        object: ObjectId::from(object),
+        kind: FollowType::Follow,
+        id: generate_random_object_id(&API_DOMAIN)?,
+    };
+    let relay = relay::ActiveModel {
+        id: Set(Uuid::now_v7().to_string()),
+        url: Set(url.to_string()),
+        actor: Set(actor.map(|actor| actor.to_string())),
+        host: Set(host),
+        inbox: Set(inbox.to_string()),
+        follow_id: Set(follow.id.to_string()),
+        status: Set(PENDING.to_string()),
+        created_at: Set(Utc::now()),
+        accepted_at: Set(None),
+    }
+    .insert(db)
+    .await?;
+    deliver(
+        &WithContext::new_default(follow),
+        &service_actor,
+        vec![inbox],
+    )
+    .await?;
+    Ok(relay)
+}
+
+/// Stops following a relay and forgets it
+pub async fn unsubscribe(url: Url) -> anyhow::Result<()> {
+    let db = DB.get().unwrap();
+    let relay = prelude::Relay::find()
+        .filter(relay::Column::Url.eq(url.as_str()))
+        .one(db)
+        .await?
+        .ok_or(anyhow!("Not subscribed to {url}"))?;
+    let service_actor = service_actor::find().await?;
+    let object = match &relay.actor {
+        Some(actor) => Url::parse(actor)?,
+        None => public(),
+    };
+    let undo = Undo {
+        actor: ObjectId::from(Url::parse(&service_actor.url)?),
+        object: Follow {
+            actor: ObjectId::from(Url::parse(&service_actor.url)?),
+            object: ObjectId::from(object),
+            kind: FollowType::Follow,
+            id: Url::parse(&relay.follow_id)?,
+        },
+        kind: UndoType::Undo,
+        id: generate_random_object_id(&API_DOMAIN)?,
+    };
+    deliver(
+        &WithContext::new_default(undo),
+        &service_actor,
+        vec![Url::parse(&relay.inbox)?],
+    )
+    .await?;
+    prelude::Relay::delete_by_id(relay.id).exec(db).await?;
+    Ok(())
+}
+
+pub async fn list() -> anyhow::Result<Vec<relay::Model>> {
+    Ok(prelude::Relay::find()
+        .order_by_asc(relay::Column::CreatedAt)
+        .all(DB.get().unwrap())
+        .await?)
+}
+
+/// The relay `actor` belongs to, if any. Mastodon relays don't tell us their actor up front,
+/// so they are recognized by their host.
+pub async fn relay_of(actor: &Url) -> anyhow::Result<Option<relay::Model>> {
+    let Some(host) = actor.host_str() else {
+        return Ok(None);
+    };
+    let relays = prelude::Relay::find()
+        .filter(relay::Column::Host.eq(host))
+        .all(DB.get().unwrap())
+        .await?;
+    Ok(relays
+        .into_iter()
+        .find(|relay| relay.actor.is_none() || relay.actor.as_deref() == Some(actor.as_str())))
+}
+
+/// Marks the relay our follow `follow_id` went to as accepted. Returns false if the follow
+/// was not one of ours to a relay.
+pub async fn accepted(follow_id: &Url) -> anyhow::Result<bool> {
+    let db = DB.get().unwrap();
+    let Some(relay) = prelude::Relay::find()
+        .filter(relay::Column::FollowId.eq(follow_id.as_str()))
+        .one(db)
+        .await?
+    else {
+        return Ok(false);
+    };
+    info!("Relay {} accepted our subscription", relay.url);
+    relay::ActiveModel {
+        id: Set(relay.id),
+        status: Set(ACCEPTED.to_string()),
+        accepted_at: Set(Some(Utc::now())),
+        ..Default::default()
+    }
+    .update(db)
+    .await?;
+    Ok(true)
+}
+
+/// LitePub relays follow the service actor back to receive what it publishes
+pub async fn accept_follow(
+    relay: relay::Model,
+    follow: Follow,
+    data: &Data<StateHandle>,
+) -> anyhow::Result<()> {
+    let service_actor = data.local_user().await.map_err(|err| err.0)?;
+    if follow.object.inner().as_str() != service_actor.url {
+        return Err(anyhow!("Relay {} followed someone else", relay.url));
+    }
+    let accept = Accept {
+        actor: follow.object.clone(),
+        object: follow,
+        kind: AcceptType::Accept,
+        id: generate_random_object_id(data.domain())?,
+    };
+    deliver(
+        &WithContext::new_default(accept),
+        &service_actor,
+        vec![Url::parse(&relay.inbox)?],
+    )
+    .await
+}
+
+/// Bridges a post a relay announced to the Versia instance, whose federated timeline it
+/// ends up on
+pub async fn federate_relayed(note: post::Model) -> anyhow::Result<()> {
+    if note.visibility != "public" {
+        return Ok(());
+    }
+    let creator = prelude::User::find_by_id(note.creator.as_str())
+        .one(DB.get().unwrap())
+        .await?
+        .ok_or(anyhow!("Unknown author {}", note.creator))?;
+    let policy = policy_for_url(&Url::parse(&creator.url)?);
+    // silenced domains don't make it onto public timelines
+    if policy.reject || policy.silence {
+        return Ok(());
+    }
+    let Some(shared_inbox) = versia_shared_inbox(&LYSAND_DOMAIN).await else {
+        warn!(
+            "Not bridging {}, {} has no shared inbox",
+            note.url, *LYSAND_DOMAIN
+        );
+        return Ok(());
+    };
+    let versia_post = versia_post_from_db(note).await?;
+    let body = serde_json::to_string(&SortAlphabetically(&versia_post))?;
+    enqueue(Protocol::Versia, vec![shared_inbox], body, &creator).await?;
+    Ok(())
+}
+
+/// The relays a public post is published to, if RELAY_PUBLISH is on
+pub async fn publish_inboxes(to: &[Url]) -> Vec<Url> {
+    if !*RELAY_PUBLISH || !to.contains(&public()) {
+        return vec![];
+    }
+    let relays = prelude::Relay::find()
+        .filter(relay::Column::Status.eq(ACCEPTED))
+        .all(DB.get().unwrap())
+        .await;
+    match relays {
+        Ok(relays) => relays
+            .iter()
+            .filter_map(|relay| Url::parse(&relay.inbox).ok())
+            .collect(),
+        Err(err) => {
+            warn!("Could not look up relays: {}", err);
+            vec![]
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! The bridge's own actor. It signs our fetches and follows relays, and lives at `/actor`.
+
+use activitypub_federation::{
+    http_signatures::generate_actor_keypair, protocol::public_key::PublicKey,
+};
+use anyhow::anyhow;
+use chrono::Utc;
+use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, Set};
+use tracing::info;
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    crypto,
+    entities::{prelude, user},
+    objects::person::{EndpointType, Person},
+    API_DOMAIN, DB, SERVICE_ACTOR_NAME, SERVICE_ACTOR_SUMMARY, SERVICE_ACTOR_TYPE, USERNAME,
+};
+
+pub fn actor_url() -> Url {
+    Url::parse(&format!("https://{}/actor", *API_DOMAIN)).unwrap()
+}
+
+fn person(public_key_pem: String) -> anyhow::Result<Person> {
+    let id = actor_url();
+    let inbox = Url::parse(&format!("https://{}/{}/inbox", *API_DOMAIN, *USERNAME))?;
+    Ok(Person {
+        kind: SERVICE_ACTOR_TYPE.clone(),
+        id: id.clone().into(),
+        preferred_username: USERNAME.to_string(),
+        name: SERVICE_ACTOR_NAME.to_string(),
+        summary: Some(SERVICE_ACTOR_SUMMARY.to_string()),
+        url: id.clone(),
+        inbox: inbox.clone(),
+        public_key: PublicKey {
+            owner: id.clone(),
+            public_key_pem,
+            id: format!("{}#main-key", id),
+        },
+        indexable: Some(false),
+        discoverable: Some(false),
+        manually_approves_followers: Some(false),
+        endpoints: Some(EndpointType {
+            shared_inbox: inbox,
+        }),
+        icon: None,
+        image: None,
+        attachment: None,
+        tag: None,
+        followers: None,
+        following: None,
+        featured: None,
+        outbox: None,
+        also_known_as: None,
+        assertion_method: None,
+        featured_tags: None,
+    })
+}
+
+pub async fn find() -> anyhow::Result<user::Model> {
+    prelude::User::find()
+        .filter(user::Column::Username.eq(USERNAME.as_str()))
+        .filter(user::Column::Local.eq(true))
+        .one(DB.get().unwrap())
+        .await?
+        .ok_or(anyhow!(
+            "The service actor does not exist yet, start the bridge once first"
+        ))
+}
+
+/// Creates the service actor on the first start and keeps its profile in line with the
+/// configuration afterwards. Its keys are generated once and kept.
+pub async fn ensure() -> anyhow::Result<user::Model> {
+    let db = DB.get().unwrap();
+    let existing = prelude::User::find()
+        .filter(user::Column::Username.eq(USERNAME.as_str()))
+        .filter(user::Column::Local.eq(true))
+        .one(db)
+        .await?;
+    if let Some(actor) = existing {
+        let person = person(actor.public_key.clone())?;
+        let actor = user::ActiveModel {
+            id: Set(actor.id),
+            name: Set(person.name.clone()),
+            summary: Set(person.summary.clone()),
+            inbox: Set(person.inbox.to_string()),
+            url: Set(person.id.inner().to_string()),
+            ap_json: Set(Some(serde_json::to_string(&person)?)),
+            updated_at: Set(Some(Utc::now())),
+            ..Default::default()
+        }
+        .update(db)
+        .await?;
+        return Ok(actor);
+    }
+
+    let keypair = generate_actor_keypair()?;
+    let person = person(keypair.public_key.clone())?;
+    let actor = user::ActiveModel {
+        id: Set(Uuid::now_v7().to_string()),
+        username: Set(USERNAME.to_string()),
+        name: Set(person.name.clone()),
+        inbox: Set(person.inbox.to_string()),
+        public_key: Set(keypair.public_key),
+        private_key: Set(Some(crypto::seal(&keypair.private_key))),
+        last_refreshed_at: Set(Utc::now()),
+        follower_count: Set(0),
+        following_count: Set(0),
+        url: Set(person.id.inner().to_string()),
+        local: Set(true),
+        created_at: Set(Utc::now()),
+        summary: Set(person.summary.clone()),
+        ap_json: Set(Some(serde_json::to_string(&person)?)),
+        ..Default::default()
+    }
+    .insert(db)
+    .await?;
+    info!("Created the service actor {}", actor.url);
+    Ok(actor)
+}
@@ -0,0 +1,57 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use once_cell::sync::Lazy;
+use tokio::sync::Notify;
+use tracing::warn;
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+static STOPPED: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Tells the background workers to stop picking up new work
+pub fn begin() {
+    STOPPING.store(true, Ordering::SeqCst);
+    STOPPED.notify_waiters();
+}
+
+pub fn stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+/// Resolves once shutdown has begun
+pub async fn stopped() {
+    let notified = STOPPED.notified();
+    if stopping() {
+        return;
+    }
+    notified.await;
+}
+
+/// Waits for SIGINT, or SIGTERM as sent by container runtimes
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            // we also shut down in case of error
+            warn!("Unable to listen for shutdown signal: {}", err);
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                warn!("Unable to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
@@ -0,0 +1,140 @@
+//! Shared setup for tests that need the configuration or the database
+
+use std::{
+    env,
+    sync::{Arc, Once},
+};
+
+use activitypub_federation::{config::FederationConfig, traits::Actor};
+
+use chrono::Utc;
+use sea_orm::{
+    ActiveModelTrait, ConnectOptions, ConnectionTrait, DatabaseConnection, DbBackend, Schema, Set,
+};
+use tokio::sync::OnceCell;
+use uuid::Uuid;
+
+use crate::{database::State, entities::*, DB};
+
+pub const API_DOMAIN: &str = "bridge.example";
+pub const LYSAND_DOMAIN: &str = "versia.example";
+/// Differs from the api domain, as in setups that serve only webfinger on the bare domain
+pub const FEDERATED_DOMAIN: &str = "social.example";
+
+static ENV: Once = Once::new();
+
+/// Sets the configuration the lazy statics read, before any of them is touched
+pub fn env() {
+    ENV.call_once(|| {
+        for (name, value) in [
+            ("API_DOMAIN", API_DOMAIN),
+            ("FEDERATED_DOMAIN", FEDERATED_DOMAIN),
+            ("LYSAND_DOMAIN", LYSAND_DOMAIN),
+            ("DATABASE_URL", "sqlite::memory:"),
+        ] {
+            env::set_var(name, value);
+        }
+    });
+}
+
+static SCHEMA: OnceCell<()> = OnceCell::const_new();
+
+/// A scratch database with every table, shared by all tests. Tests use ids of their own
+/// instead of cleaning up.
+pub async fn db() -> &'static DatabaseConnection {
+    env();
+    SCHEMA
+        .get_or_init(|| async {
+            // a file rather than memory, since each test's runtime drops the connections it
+            // opened and an in-memory database goes with its last connection
+            let path = env::temp_dir().join(format!("bridge-test-{}.db", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            let options = ConnectOptions::new(format!("sqlite://{}?mode=rwc", path.display()))
+                .max_connections(1)
+                .min_connections(1)
+                .sqlx_logging(false)
+                .to_owned();
+            let db = sea_orm::Database::connect(options).await.unwrap();
+            let schema = Schema::new(DbBackend::Sqlite);
+            for table in [
+                schema.create_table_from_entity(user::Entity),
+                schema.create_table_from_entity(post::Entity),
+                schema.create_table_from_entity(follow_relation::Entity),
+                schema.create_table_from_entity(delivery_job::Entity),
+                schema.create_table_from_entity(inbox_job::Entity),
+                schema.create_table_from_entity(instance::Entity),
+                schema.create_table_from_entity(domain_policy::Entity),
+                schema.create_table_from_entity(processed_activity::Entity),
+                schema.create_table_from_entity(relay::Entity),
+            ] {
+                db.execute(db.get_database_backend().build(&table))
+                    .await
+                    .unwrap();
+            }
+            DB.set(db).unwrap();
+        })
+        .await;
+    DB.get().unwrap()
+}
+
+/// The federation config the handlers get their `Data` from
+pub async fn federation() -> FederationConfig<State> {
+    let db = db().await;
+    FederationConfig::builder()
+        .domain(FEDERATED_DOMAIN)
+        .app_data(State {
+            database_connection: Arc::new(db.clone()),
+        })
+        .debug(true)
+        .build()
+        .await
+        .unwrap()
+}
+
+/// Stores a user, bridged from Versia if `local`
+pub async fn user(id: &str, local: bool) -> user::Model {
+    let host = match local {
+        true => LYSAND_DOMAIN,
+        false => "remote.example",
+    };
+    user::ActiveModel {
+        id: Set(id.to_string()),
+        username: Set(id.to_string()),
+        name: Set(id.to_string()),
+        inbox: Set(format!("https://{host}/users/{id}/inbox")),
+        public_key: Set(String::new()),
+        last_refreshed_at: Set(Utc::now()),
+        follower_count: Set(0),
+        following_count: Set(0),
+        url: Set(format!("https://{host}/users/{id}")),
+        local: Set(local),
+        created_at: Set(Utc::now()),
+        ..Default::default()
+    }
+    .insert(db().await)
+    .await
+    .unwrap()
+}
+
+/// Stores an accepted follow of `followee` by `follower`
+pub async fn follow(follower: &user::Model, followee: &user::Model) -> follow_relation::Model {
+    let id = Uuid::now_v7().to_string();
+    follow_relation::ActiveModel {
+        id: Set(id.clone()),
+        followee_id: Set(followee.id.clone()),
+        follower_id: Set(follower.id.clone()),
+        follower_inbox: Set(Some(follower.shared_inbox_or_inbox().to_string())),
+        follower_host: Set(follower
+            .shared_inbox_or_inbox()
+            .host_str()
+            .map(str::to_string)),
+        ap_id: Set(Some(id.clone())),
+        accept_id: Set(Some(Uuid::now_v7().to_string())),
+        ap_json: Set(String::new()),
+        remote: Set(!follower.local),
+        ..Default::default()
+    }
+    .insert(db().await)
+    .await
+    .unwrap()
+}
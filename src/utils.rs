@@ -4,10 +4,33 @@ pub fn generate_object_id(domain: &str, uuid: &str) -> Result<Url, ParseError> {
     Url::parse(&format!("https://{}/apbridge/object/{}", domain, uuid))
 }
 
+pub fn generate_replies_id(domain: &str, uuid: &str) -> Result<Url, ParseError> {
+    Url::parse(&format!(
+        "https://{}/apbridge/object/{}/replies",
+        domain, uuid
+    ))
+}
+
+/// FEP-7888 conversation context, named after the post that started the thread
+pub fn generate_context_id(domain: &str, root_uuid: &str) -> Result<Url, ParseError> {
+    Url::parse(&format!(
+        "https://{}/apbridge/context/{}",
+        domain, root_uuid
+    ))
+}
+
 pub fn generate_user_id(domain: &str, uuid: &str) -> Result<Url, ParseError> {
     Url::parse(&format!("https://{}/apbridge/user/{}", domain, uuid))
 }
 
+/// The followers collection a bridged user publishes, which the bridge forwards to
+pub fn generate_followers_id(domain: &str, uuid: &str) -> Result<Url, ParseError> {
+    Url::parse(&format!(
+        "https://{}/apbridge/user/{}/followers",
+        domain, uuid
+    ))
+}
+
 pub fn generate_random_object_id(domain: &str) -> Result<Url, ParseError> {
     let id: String = uuid::Uuid::new_v4().to_string();
     generate_object_id(domain, &id)
@@ -18,6 +41,13 @@ pub fn generate_follow_accept_id(domain: &str, db_id: &str) -> Result<Url, Parse
     Url::parse(&format!("https://{}/apbridge/follow/{}", domain, db_id))
 }
 
+pub fn generate_follow_reject_id(domain: &str, db_id: &str) -> Result<Url, ParseError> {
+    Url::parse(&format!(
+        "https://{}/apbridge/followreject/{}",
+        domain, db_id
+    ))
+}
+
 pub fn generate_follow_req_id(domain: &str, db_id: &str) -> Result<Url, ParseError> {
     Url::parse(&format!("https://{}/apbridge/followreq/{}", domain, db_id))
 }
@@ -29,6 +59,11 @@ pub fn generate_versia_post_url(domain: &str, db_id: &str) -> Result<Url, ParseE
     ))
 }
 
+/// Web profile of a Versia user, for humans ending up on a bridged actor
+pub fn generate_versia_profile_url(domain: &str, username: &str) -> Result<Url, ParseError> {
+    Url::parse(&format!("https://{}/@{}", domain, username))
+}
+
 // TODO for later aprl: needs to be base64url!!!
 pub fn generate_create_id(
     domain: &str,
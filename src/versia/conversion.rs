@@ -7,7 +7,6 @@ use activitystreams_kinds::public;
 use anyhow::{anyhow, Ok};
 use async_recursion::async_recursion;
 use chrono::{DateTime, TimeZone, Utc};
-use reqwest::header::{self, CONTENT_TYPE};
 use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, Set};
 use serde::{Deserialize, Serialize};
 use serde_json::to_string;
@@ -16,26 +15,31 @@ use tracing::info;
 use url::Url;
 
 use crate::{
+    crypto,
     database::State,
     entities::{self, post, prelude, user},
+    fetcher::fetcher,
     objects::{
         self,
+        collection::LinkOrObject,
         person::{AttachmentType, EndpointType, IconType, Person, TagType},
         post::Mention,
     },
-    utils::{generate_object_id, generate_user_id, generate_versia_post_url},
+    policy::policy_for_url,
+    utils::{
+        generate_context_id, generate_object_id, generate_replies_id, generate_user_id,
+        generate_versia_post_url,
+    },
     API_DOMAIN, DB, FEDERATION_CONFIG, LOCAL_USER_NAME, LYSAND_DOMAIN, USERNAME,
 };
 
 use super::{
+    keys::ensure_versia_keys,
     objects::{CategoryType, ContentEntry, ContentFormat, Note, PublicKey, UserCollections},
-    superx::request_client,
 };
 
 pub async fn fetch_user_from_url(url: Url) -> anyhow::Result<super::objects::User> {
-    let req_client = request_client();
-    let request = req_client.get(url).send().await?;
-    Ok(request.json::<super::objects::User>().await?)
+    Ok(fetcher().get_json::<super::objects::User>(&url).await?)
 }
 
 pub async fn versia_post_from_db(
@@ -120,7 +124,10 @@ pub async fn versia_post_from_db(
 pub async fn versia_user_from_db(
     user: entities::user::Model,
 ) -> anyhow::Result<super::objects::User> {
+    let user = ensure_versia_keys(user).await?;
+    let versia_public_key = user.versia_public_key.clone().unwrap();
     let url = Url::parse(&user.url)?;
+    let strip_media = !user.local && policy_for_url(&url).strip_media;
     let ap = user.ap_json.unwrap();
     let serialized_ap: crate::objects::person::Person = serde_json::from_str(&ap)?;
     let inbox_url;
@@ -169,33 +176,33 @@ pub async fn versia_user_from_db(
         "text/html".to_string(),
         ContentEntry::from_string(user.summary.unwrap_or_default()),
     );
-    let avatar = match serialized_ap.icon {
+    let avatar = match serialized_ap.icon.filter(|_| !strip_media) {
         Some(icon) => {
             let mut content_format = ContentFormat::default();
             let content_entry = ContentEntry::from_string(icon.url.to_string());
-            let media_type = icon.media_type.unwrap_or({
-                let req = request_client().get(icon.url.clone()).build()?;
-                let res = request_client().execute(req).await?;
-                let headers = res.headers();
-                let content_type_header = headers.get(CONTENT_TYPE);
-                content_type_header.unwrap().to_str().unwrap().to_string()
-            });
+            let media_type = match icon.media_type {
+                Some(media_type) => media_type,
+                None => fetcher()
+                    .media_type(&icon.url)
+                    .await?
+                    .ok_or(anyhow!("{} has no content type", icon.url))?,
+            };
             content_format.x.insert(media_type, content_entry);
             Some(content_format)
         }
         None => None,
     };
-    let header = match serialized_ap.image {
+    let header = match serialized_ap.image.filter(|_| !strip_media) {
         Some(image) => {
             let mut content_format = ContentFormat::default();
             let content_entry = ContentEntry::from_string(image.url.to_string());
-            let media_type = image.media_type.unwrap_or({
-                let req = request_client().get(image.url.clone()).build()?;
-                let res = request_client().execute(req).await?;
-                let headers = res.headers();
-                let content_type_header = headers.get(CONTENT_TYPE);
-                content_type_header.unwrap().to_str().unwrap().to_string()
-            });
+            let media_type = match image.media_type {
+                Some(media_type) => media_type,
+                None => fetcher()
+                    .media_type(&image.url)
+                    .await?
+                    .ok_or(anyhow!("{} has no content type", image.url))?,
+            };
             content_format.x.insert(media_type, content_entry);
             Some(content_format)
         }
@@ -217,7 +224,7 @@ pub async fn versia_user_from_db(
             fields.push(super::objects::FieldKV { key, value });
         }
     }
-    let emojis = match serialized_ap.tag {
+    let emojis = match serialized_ap.tag.filter(|_| !strip_media) {
         Some(tags) => {
             let mut emojis = Vec::new();
             for tag in tags {
@@ -228,16 +235,13 @@ pub async fn versia_user_from_db(
                 let content_entry =
                     ContentEntry::from_string(tag.icon.clone().unwrap().url.to_string());
                 let icon = tag.icon.unwrap();
-                let media_type = icon.media_type.unwrap_or({
-                    let req = request_client().get(icon.url.clone()).build()?;
-                    let res = request_client().execute(req).await?;
-                    let headers = res.headers();
-                    let content_type_header = headers.get(CONTENT_TYPE);
-                    if content_type_header.is_none() {
-                        continue;
-                    }
-                    content_type_header.unwrap().to_str().unwrap().to_string()
-                });
+                let media_type = match icon.media_type {
+                    Some(media_type) => media_type,
+                    None => match fetcher().media_type(&icon.url).await {
+                        std::result::Result::Ok(Some(media_type)) => media_type,
+                        _ => continue,
+                    },
+                };
                 content_format.x.insert(media_type, content_entry);
                 let name = tag.name;
                 emojis.push(super::objects::CustomEmoji {
@@ -276,7 +280,7 @@ pub async fn versia_user_from_db(
         created_at: OffsetDateTime::from_unix_timestamp(user.created_at.timestamp()).unwrap(),
         public_key: PublicKey {
             actor: url.clone(),
-            key: "AAAAC3NzaC1lZDI1NTE5AAAAIMxsX+lEWkHZt9NOvn9yYFP0Z++186LY4b97C4mwj/f2".to_string(), // dummy key
+            key: versia_public_key,
             algorithm: "ed25519".to_string(),
         },
         extensions: Some(extensions),
@@ -327,14 +331,12 @@ pub async fn local_db_user_from_name(name: String) -> anyhow::Result<entities::u
     if let Some(user) = user_res {
         Ok(user)
     } else {
-        let client = request_client();
         let api_url = Url::parse(&format!(
             "https://{}/api/v1/accounts/id?username={}",
             LYSAND_DOMAIN.to_string(),
             name
         ))?;
-        let request = client.get(api_url).send().await?;
-        let user_json = request.json::<ApiUser>().await?;
+        let user_json = fetcher().get_json::<ApiUser>(&api_url).await?;
         Ok(db_user_from_url(user_json.uri).await?)
     }
 }
@@ -430,12 +432,13 @@ pub async fn db_user_from_url(url: Url) -> anyhow::Result<entities::user::Model>
             url: ls_user.uri.clone(),
             indexable: Some(ls_user.indexable),
             discoverable: Some(true),
-            manually_approves_followers: Some(false),
+            manually_approves_followers: Some(ls_user.manually_approves_followers),
             followers: None,
             following: None,
             featured: None,
             featured_tags: None,
             also_known_as: None,
+            assertion_method: None,
             outbox: None,
             endpoints: Some(EndpointType {
                 shared_inbox: Url::parse(
@@ -459,7 +462,7 @@ pub async fn db_user_from_url(url: Url) -> anyhow::Result<entities::user::Model>
             name: Set(ls_user.display_name.unwrap_or(ls_user.username)),
             inbox: Set(ls_user.inbox.to_string()),
             public_key: Set(keypair.public_key.clone()),
-            private_key: Set(Some(keypair.private_key.clone())),
+            private_key: Set(Some(crypto::seal(&keypair.private_key))),
             last_refreshed_at: Set(Utc::now()),
             follower_count: Set(0),
             following_count: Set(0),
@@ -480,10 +483,39 @@ pub async fn db_user_from_url(url: Url) -> anyhow::Result<entities::user::Model>
     }
 }
 
+/// Most parents looked at when searching the conversation of a reply
+const MAX_THREAD_DEPTH: usize = 50;
+
+/// The conversation `post` belongs to: the context stored with it or the nearest parent
+/// that has one, otherwise the one started by the root of the thread
+pub async fn thread_context(mut post: entities::post::Model) -> anyhow::Result<Url> {
+    for _ in 0..MAX_THREAD_DEPTH {
+        let context = post
+            .ap_json
+            .as_ref()
+            .and_then(|json| serde_json::from_str::<crate::objects::post::Note>(json).ok())
+            .and_then(|note| note.context_id());
+        if let Some(context) = context {
+            return Ok(context);
+        }
+        let parent = match post.reply_id.as_deref() {
+            Some(parent) => {
+                prelude::Post::find_by_id(parent)
+                    .one(DB.get().unwrap())
+                    .await?
+            }
+            None => None,
+        };
+        match parent {
+            Some(parent) => post = parent,
+            None => break,
+        }
+    }
+    Ok(generate_context_id(&API_DOMAIN, &post.id)?)
+}
+
 pub async fn fetch_note_from_url(url: Url) -> anyhow::Result<super::objects::Note> {
-    let req_client = request_client();
-    let request = req_client.get(url).send().await?;
-    Ok(request.json::<super::objects::Note>().await?)
+    Ok(fetcher().get_json::<super::objects::Note>(&url).await?)
 }
 #[async_recursion]
 pub async fn receive_versia_note(
@@ -608,11 +640,17 @@ pub async fn receive_versia_note(
         } else {
             None
         };
-        let reply_uuid: Option<String> = if let Some(rep) = note.replies_to.clone() {
-            Some(db_post_from_url(rep).await?.id)
+        let reply_post = if let Some(rep) = note.replies_to.clone() {
+            Some(db_post_from_url(rep).await?)
         } else {
             None
         };
+        let reply_uuid: Option<String> = reply_post.as_ref().map(|post| post.id.clone());
+        // replies join the conversation of their parent, everything else starts a new one
+        let context = match reply_post.as_ref() {
+            Some(parent) => thread_context(parent.clone()).await?,
+            None => generate_context_id(&API_DOMAIN, &note.id.to_string())?,
+        };
         let quote_uuid: Option<String> = if let Some(rep) = note.quotes.clone() {
             Some(db_post_from_url(rep).await?.id)
         } else {
@@ -636,6 +674,11 @@ pub async fn receive_versia_note(
                 .await
                 .unwrap_or_default(),
             in_reply_to: reply.clone(),
+            replies: Some(Box::new(LinkOrObject::Link(generate_replies_id(
+                &API_DOMAIN,
+                &note.id.to_string(),
+            )?))),
+            context: Some(Box::new(LinkOrObject::Link(context))),
         };
 
         let visibility = match note.group.clone().unwrap_or("nothing".to_string()).as_str() {
@@ -676,3 +719,65 @@ pub async fn receive_versia_note(
         Err(anyhow!("User not found"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn test_thread_context() {
+        use super::thread_context;
+        use crate::{entities::post, testing};
+        use chrono::Utc;
+        use sea_orm::{ActiveModelTrait, Set};
+        use serde_json::json;
+
+        let db = testing::db().await;
+        let author = testing::user("context-author", true).await;
+        let post = |id: &str, reply_id: Option<&str>, context: Option<&str>| post::ActiveModel {
+            id: Set(id.to_string()),
+            content: Set(String::new()),
+            local: Set(true),
+            created_at: Set(Utc::now()),
+            content_type: Set("text/html".to_string()),
+            visibility: Set("public".to_string()),
+            sensitive: Set(false),
+            creator: Set(author.id.clone()),
+            url: Set(format!("https://versia.example/notes/{id}")),
+            reply_id: Set(reply_id.map(str::to_string)),
+            ap_json: Set(context.map(|context| {
+                json!({
+                    "type": "Note",
+                    "id": format!("https://bridge.example/apbridge/object/{id}"),
+                    "attributedTo": author.url,
+                    "to": [],
+                    "content": "",
+                    "tag": [],
+                    "context": context,
+                })
+                .to_string()
+            })),
+            ..Default::default()
+        };
+
+        // stored before contexts existed
+        post("context-root", None, None).insert(db).await.unwrap();
+        let reply = post("context-reply", Some("context-root"), None)
+            .insert(db)
+            .await
+            .unwrap();
+        assert_eq!(
+            thread_context(reply).await.unwrap().as_str(),
+            "https://bridge.example/apbridge/context/context-root"
+        );
+
+        let remote = "https://remote.example/contexts/1";
+        post("context-remote", None, Some(remote))
+            .insert(db)
+            .await
+            .unwrap();
+        let reply = post("context-remote-reply", Some("context-remote"), None)
+            .insert(db)
+            .await
+            .unwrap();
+        assert_eq!(thread_context(reply).await.unwrap().as_str(), remote);
+    }
+}
@@ -1,21 +1,26 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::anyhow;
+use once_cell::sync::Lazy;
 use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
 use time::OffsetDateTime;
+use tracing::warn;
 use url::Url;
 
 use crate::{
     entities::{follow_relation, prelude, user},
-    utils::generate_follow_accept_id,
+    fetcher::fetcher,
+    queue::{enqueue, Protocol},
+    utils::{generate_follow_accept_id, generate_follow_req_id},
     API_DOMAIN, DB,
 };
 
 use super::{
     conversion::{fetch_user_from_url, versia_user_from_db},
-    objects::FollowResult,
-    superx::request_client,
+    objects::{Follow, FollowResult, InstanceMetadata, SortAlphabetically},
 };
 
 pub async fn send_follow_accept_to_versia(model: follow_relation::Model) -> anyhow::Result<()> {
-    let request_client = request_client();
     let db = DB.get().unwrap();
 
     let id_raw = model.accept_id.unwrap();
@@ -34,7 +39,7 @@ pub async fn send_follow_accept_to_versia(model: follow_relation::Model) -> anyh
         .one(db)
         .await?
         .unwrap();
-    let versia_followee = versia_user_from_db(followee_model).await?;
+    let versia_followee = versia_user_from_db(followee_model.clone()).await?;
 
     let entity = FollowResult {
         rtype: "FollowAccept".to_string(),
@@ -45,18 +50,86 @@ pub async fn send_follow_accept_to_versia(model: follow_relation::Model) -> anyh
         follower: versia_follower.uri,
     };
 
-    let request = request_client
-        .post(versia_follower.inbox.as_str())
-        .header("Content-Type", "application/json; charset=utf-8")
-        .header("Accept", "application/json")
-        .header("Date", entity.created_at.clone().to_string())
-        .json(&entity);
+    let body = serde_json::to_string(&SortAlphabetically(&entity))?;
+    enqueue(
+        Protocol::Versia,
+        vec![versia_follower.inbox],
+        body,
+        &followee_model,
+    )
+    .await?;
+    Ok(())
+}
 
-    let response = request.send().await?;
+/// Tells the Versia instance that an ActivityPub user follows one of its users, once the
+/// bridge has accepted the follow
+pub async fn send_follow_to_versia(model: follow_relation::Model) -> anyhow::Result<()> {
+    let db = DB.get().unwrap();
+    let follower_model = prelude::User::find_by_id(model.follower_id.as_str())
+        .one(db)
+        .await?
+        .ok_or(anyhow!("Unknown follower {}", model.follower_id))?;
+    let versia_follower = versia_user_from_db(follower_model.clone()).await?;
+    let followee_model = prelude::User::find_by_id(model.followee_id.as_str())
+        .one(db)
+        .await?
+        .ok_or(anyhow!("Unknown followee {}", model.followee_id))?;
+
+    let entity = Follow {
+        rtype: "Follow".to_string(),
+        id: uuid::Uuid::parse_str(&model.id)?,
+        uri: generate_follow_req_id(API_DOMAIN.as_str(), &model.id)?,
+        author: versia_follower.uri,
+        created_at: OffsetDateTime::now_utc(),
+        followee: Url::parse(&followee_model.url)?,
+    };
+
+    let body = serde_json::to_string(&SortAlphabetically(&entity))?;
+    enqueue(
+        Protocol::Versia,
+        vec![Url::parse(&followee_model.inbox)?],
+        body,
+        &follower_model,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Shared inboxes of the Versia instances delivered to, `None` for those without one
+static SHARED_INBOXES: Lazy<Mutex<HashMap<String, Option<Url>>>> = Lazy::new(Default::default);
+
+pub async fn versia_shared_inbox(host: &str) -> Option<Url> {
+    if let Some(shared_inbox) = SHARED_INBOXES.lock().unwrap().get(host) {
+        return shared_inbox.clone();
+    }
+    let metadata_url = Url::parse(&format!("https://{host}/.well-known/versia")).ok()?;
+    match fetcher().get_json::<InstanceMetadata>(&metadata_url).await {
+        Ok(metadata) => {
+            SHARED_INBOXES
+                .lock()
+                .unwrap()
+                .insert(host.to_string(), metadata.shared_inbox.clone());
+            metadata.shared_inbox
+        }
+        Err(err) => {
+            warn!("Could not look up the shared inbox of {}: {}", host, err);
+            None
+        }
+    }
+}
 
-    if response.status().is_success() {
-        Ok(())
-    } else {
-        Err(anyhow::anyhow!("Failed to send follow accept to Versia"))
+/// The inboxes an entity for `inboxes` goes to: the shared inbox of each instance that has
+/// one, so every instance gets it once, and the given inbox everywhere else
+pub async fn versia_delivery_inboxes(inboxes: Vec<Url>) -> Vec<Url> {
+    let mut delivery_inboxes = Vec::new();
+    for inbox in inboxes {
+        let shared_inbox = match inbox.host_str() {
+            Some(host) => versia_shared_inbox(host).await,
+            None => None,
+        };
+        delivery_inboxes.push(shared_inbox.unwrap_or(inbox));
     }
+    delivery_inboxes.sort();
+    delivery_inboxes.dedup();
+    delivery_inboxes
 }
@@ -1,28 +1,43 @@
 use activitypub_federation::{
+    config::Data,
     fetch::{object_id::ObjectId, webfinger::webfinger_resolve_actor},
     protocol::{context::WithContext, public_key::PublicKey},
-    traits::Object,
+    traits::{Actor, Object},
     FEDERATION_CONTENT_TYPE,
 };
 use activitystreams_kinds::{activity::CreateType, object};
-use actix_web::{get, post, web, HttpResponse};
-use sea_orm::{query, ColumnTrait, EntityTrait, QueryFilter};
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use sea_orm::{query, ColumnTrait, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder};
+use time::OffsetDateTime;
 use url::Url;
 
 use crate::{
     database::State,
     entities::{
+        follow_relation,
         post::{self, Entity},
         prelude, user,
     },
     error,
-    objects::{self, person::Person},
-    utils::{base_url_decode, generate_create_id, generate_user_id},
+    http::fetch_signer,
+    integrity_proofs::actor_context,
+    limits::{limit_inbox_rate, limit_signer_rate, read_inbox_body},
+    objects::{self, collection::Collection, post::ap_id_of},
+    utils::{
+        base_url_decode, generate_context_id, generate_create_id, generate_followers_id,
+        generate_replies_id, generate_user_id,
+    },
     versia::{
         conversion::{versia_post_from_db, versia_user_from_db},
-        inbox::inbox_entry,
+        inbox_queue::{self, entity_id},
+        keys::{encode_public_key, instance_signing_key},
+        objects::{
+            InstanceCompatibility, InstanceMetadata, InstancePublicKey, InstanceSoftware,
+            SortAlphabetically, VersiaExtensions,
+        },
+        signatures::{is_instance_signer, signer_may_act_for, verify_request, SIGNATURE_HEADER},
     },
-    Response, API_DOMAIN, DB, FEDERATION_CONFIG,
+    Response, API_DOMAIN, DB, FEDERATION_CONFIG, INSTANCE_DESCRIPTION, INSTANCE_NAME,
 };
 
 use super::conversion::db_user_from_url;
@@ -91,19 +106,94 @@ async fn query_post(
 
 #[post("/apbridge/versia/inbox")]
 async fn versia_inbox(
-    body: web::Bytes,
+    request: HttpRequest,
+    payload: web::Payload,
     state: web::Data<State>,
 ) -> actix_web::Result<HttpResponse, error::Error> {
-    let string = String::from_utf8(body.to_vec())?;
-    inbox_entry(&string).await?;
-    Ok(HttpResponse::Created().finish())
+    if let Some(limited) = limit_inbox_rate(&request, "versia") {
+        return Ok(limited);
+    }
+    let body = match read_inbox_body(&request, payload, "versia").await {
+        Ok(body) => body,
+        Err(refused) => return Ok(refused),
+    };
+    let signer = match verify_request(&request, &body).await {
+        Ok(signer) => signer,
+        Err(err) => return Ok(HttpResponse::Unauthorized().body(err.to_string())),
+    };
+    if let Some(limited) = limit_signer_rate(&signer, "versia") {
+        return Ok(limited);
+    }
+    let Ok(string) = String::from_utf8(body.to_vec()) else {
+        return Ok(HttpResponse::BadRequest().body("Body is not UTF-8"));
+    };
+    let value: serde_json::Value = match serde_json::from_str(&string) {
+        Ok(value) => value,
+        Err(err) => return Ok(HttpResponse::BadRequest().body(err.to_string())),
+    };
+    if value.get("type").and_then(|kind| kind.as_str()).is_none() {
+        return Ok(HttpResponse::BadRequest().body("Entity has no type"));
+    }
+    let author = value.get("author").and_then(|author| author.as_str());
+    if !signer_may_act_for(&signer, author) {
+        return Ok(HttpResponse::Unauthorized().body(format!(
+            "Signer {} may not deliver entities of {}",
+            signer,
+            author.unwrap_or("nobody")
+        )));
+    }
+    // processing may fetch from slow remote servers, so it happens after we answer
+    inbox_queue::enqueue(string, entity_id(&value)).await?;
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[get("/.well-known/versia")]
+async fn versia_metadata(state: web::Data<State>) -> actix_web::Result<HttpResponse, error::Error> {
+    let service_user = state.local_user().await?;
+
+    let metadata = InstanceMetadata {
+        rtype: "InstanceMetadata".to_string(),
+        name: INSTANCE_NAME.to_string(),
+        software: InstanceSoftware {
+            name: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        },
+        compatibility: InstanceCompatibility {
+            versions: vec!["0.4.0".to_string()],
+            extensions: vec![VersiaExtensions::CustomEmojis],
+        },
+        description: INSTANCE_DESCRIPTION.clone(),
+        host: API_DOMAIN.to_string(),
+        shared_inbox: Some(Url::parse(&format!(
+            "https://{}/apbridge/versia/inbox",
+            API_DOMAIN.to_string()
+        ))?),
+        public_key: InstancePublicKey {
+            key: encode_public_key(&instance_signing_key().verifying_key())?,
+            algorithm: "ed25519".to_string(),
+        },
+        moderators: None,
+        admins: None,
+        logo: None,
+        banner: None,
+        created_at: OffsetDateTime::from_unix_timestamp(service_user.created_at.timestamp())?,
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(SortAlphabetically(&metadata)))
 }
 
 #[get("/apbridge/object/{post}")]
 async fn fetch_post(
+    request: HttpRequest,
     path: web::Path<String>,
-    state: web::Data<State>,
+    data: Data<State>,
 ) -> actix_web::Result<HttpResponse, error::Error> {
+    let viewer = match fetch_signer(&request, &data).await {
+        Ok(viewer) => viewer,
+        Err(refusal) => return Ok(refusal.response()),
+    };
     let db = DB.get().unwrap();
 
     let post = prelude::Post::find()
@@ -112,8 +202,8 @@ async fn fetch_post(
         .await?;
 
     let post = match post {
-        Some(post) => post,
-        None => return Ok(HttpResponse::NotFound().finish()),
+        Some(post) if may_view(&post, viewer.as_ref()).await? => post,
+        _ => return Ok(HttpResponse::NotFound().finish()),
     };
 
     Ok(HttpResponse::Ok()
@@ -121,10 +211,144 @@ async fn fetch_post(
         .json(crate::objects::post::Note::from_db(&post)))
 }
 
+/// Visibilities of posts that may be listed in public collections
+const LISTED_VISIBILITIES: [&str; 2] = ["public", "unlisted"];
+
+/// Whether `viewer` may see `post`. Listed posts are visible to anyone, followers-only posts
+/// to accepted followers, and every post to its author and the users it mentions.
+async fn may_view(post: &post::Model, viewer: Option<&user::Model>) -> Result<bool, error::Error> {
+    if LISTED_VISIBILITIES.contains(&post.visibility.as_str()) {
+        return Ok(true);
+    }
+    let Some(viewer) = viewer else {
+        return Ok(false);
+    };
+    if viewer.id == post.creator {
+        return Ok(true);
+    }
+
+    let mentions = post
+        .ap_json
+        .as_deref()
+        .and_then(|json| serde_json::from_str::<objects::post::Note>(json).ok())
+        .map(|note| note.tag)
+        .unwrap_or_default();
+    // bridged Versia users are mentioned by their ActivityPub id
+    let viewer_id = Actor::id(viewer);
+    if mentions
+        .iter()
+        .any(|mention| mention.href.as_str() == viewer.url || mention.href == viewer_id)
+    {
+        return Ok(true);
+    }
+
+    if post.visibility != "followers" {
+        return Ok(false);
+    }
+    let follow = prelude::FollowRelation::find()
+        .filter(follow_relation::Column::FolloweeId.eq(post.creator.as_str()))
+        .filter(follow_relation::Column::FollowerId.eq(viewer.id.as_str()))
+        .filter(follow_relation::Column::AcceptId.is_not_null())
+        .one(DB.get().unwrap())
+        .await?;
+    Ok(follow.is_some())
+}
+
+/// Most posts listed in a single conversation context
+const CONTEXT_MAX_ITEMS: usize = 500;
+
+#[get("/apbridge/object/{post}/replies")]
+async fn fetch_replies(
+    request: HttpRequest,
+    path: web::Path<String>,
+    data: Data<State>,
+) -> actix_web::Result<HttpResponse, error::Error> {
+    if let Err(refusal) = fetch_signer(&request, &data).await {
+        return Ok(refusal.response());
+    }
+    let db = DB.get().unwrap();
+
+    let post = prelude::Post::find_by_id(path.as_str()).one(db).await?;
+    if post.is_none() {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let replies = prelude::Post::find()
+        .filter(post::Column::ReplyId.eq(path.as_str()))
+        .filter(post::Column::Visibility.is_in(LISTED_VISIBILITIES))
+        .order_by_asc(post::Column::CreatedAt)
+        .all(db)
+        .await?;
+    let items = replies
+        .iter()
+        .map(ap_id_of)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let collection = Collection::ordered(generate_replies_id(&API_DOMAIN, &path)?, items);
+
+    Ok(HttpResponse::Ok()
+        .content_type(FEDERATION_CONTENT_TYPE)
+        .json(WithContext::new_default(collection)))
+}
+
+/// FEP-7888 context collection, listing every post of the conversation started by `root`
+#[get("/apbridge/context/{root}")]
+async fn fetch_context(
+    request: HttpRequest,
+    path: web::Path<String>,
+    data: Data<State>,
+) -> actix_web::Result<HttpResponse, error::Error> {
+    if let Err(refusal) = fetch_signer(&request, &data).await {
+        return Ok(refusal.response());
+    }
+    let db = DB.get().unwrap();
+
+    let root = prelude::Post::find_by_id(path.as_str()).one(db).await?;
+    let root = match root {
+        Some(root) => root,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    let mut thread = vec![];
+    if LISTED_VISIBILITIES.contains(&root.visibility.as_str()) {
+        thread.push(ap_id_of(&root)?);
+    }
+    let mut parents = vec![root.id];
+    while !parents.is_empty() && thread.len() < CONTEXT_MAX_ITEMS {
+        let children = prelude::Post::find()
+            .filter(post::Column::ReplyId.is_in(parents))
+            .filter(post::Column::Visibility.is_in(LISTED_VISIBILITIES))
+            .order_by_asc(post::Column::CreatedAt)
+            .all(db)
+            .await?;
+        parents = children.iter().map(|child| child.id.clone()).collect();
+        for child in children.iter().take(CONTEXT_MAX_ITEMS - thread.len()) {
+            thread.push(ap_id_of(child)?);
+        }
+    }
+
+    let collection = Collection::ordered(generate_context_id(&API_DOMAIN, &path)?, thread);
+
+    Ok(HttpResponse::Ok()
+        .content_type(FEDERATION_CONTENT_TYPE)
+        .json(WithContext::new_default(collection)))
+}
+
+/// The service actor, which signs our fetches, so it is reachable without a signature
+#[get("/actor")]
+async fn fetch_service_actor(data: Data<State>) -> actix_web::Result<HttpResponse, error::Error> {
+    let service_actor = data.local_user().await?.into_json(&data).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(FEDERATION_CONTENT_TYPE)
+        .json(WithContext::new(service_actor, actor_context())))
+}
+
 #[get("/apbridge/user/{user}")]
 async fn fetch_user(
+    request: HttpRequest,
     path: web::Path<String>,
-    state: web::Data<State>,
+    data: Data<State>,
 ) -> actix_web::Result<HttpResponse, error::Error> {
     let db = DB.get().unwrap();
 
@@ -138,18 +362,75 @@ async fn fetch_user(
         None => return Ok(HttpResponse::NotFound().finish()),
     };
 
-    let deserialized_user: Person = serde_json::from_str(user.ap_json.as_ref().unwrap().as_str())?;
+    // the service actor signs our own fetches, so it has to stay reachable for everyone
+    if user.id != data.local_user().await?.id {
+        if let Err(refusal) = fetch_signer(&request, &data).await {
+            return Ok(refusal.response());
+        }
+    }
+
+    let deserialized_user = user.into_json(&data).await?;
 
     Ok(HttpResponse::Ok()
         .content_type(FEDERATION_CONTENT_TYPE)
-        .json(WithContext::new_default(deserialized_user)))
+        .json(WithContext::new(deserialized_user, actor_context())))
+}
+
+/// The followers collection of a bridged user. Like most servers we only tell how many
+/// there are, the collection exists so replies can be addressed to it.
+#[get("/apbridge/user/{user}/followers")]
+async fn fetch_followers(
+    request: HttpRequest,
+    path: web::Path<String>,
+    data: Data<State>,
+) -> actix_web::Result<HttpResponse, error::Error> {
+    if let Err(refusal) = fetch_signer(&request, &data).await {
+        return Ok(refusal.response());
+    }
+    let db = DB.get().unwrap();
+
+    let user = prelude::User::find_by_id(path.as_str())
+        .filter(user::Column::Local.eq(true))
+        .one(db)
+        .await?;
+    if user.is_none() {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let followers = prelude::FollowRelation::find()
+        .filter(follow_relation::Column::FolloweeId.eq(path.as_str()))
+        .filter(follow_relation::Column::AcceptId.is_not_null())
+        .count(db)
+        .await?;
+    let mut collection = Collection::ordered(generate_followers_id(&API_DOMAIN, &path)?, vec![]);
+    collection.total_items = Some(followers);
+
+    Ok(HttpResponse::Ok()
+        .content_type(FEDERATION_CONTENT_TYPE)
+        .json(WithContext::new_default(collection)))
 }
 
 #[get("/apbridge/versia/object/{post}")]
 async fn fetch_versia_post(
+    request: HttpRequest,
     path: web::Path<String>,
-    state: web::Data<State>,
+    data: Data<State>,
 ) -> actix_web::Result<HttpResponse, error::Error> {
+    // the Versia server we bridge for may see every post, its users only what they could
+    // see on the fediverse
+    let versia_signer = match request.headers().contains_key(SIGNATURE_HEADER) {
+        true => verify_request(&request, &[]).await.ok(),
+        false => None,
+    };
+    let instance_signed = versia_signer.as_ref().is_some_and(is_instance_signer);
+    let viewer = match versia_signer {
+        Some(_) if instance_signed => None,
+        Some(signer) => Some(db_user_from_url(signer).await?),
+        None => match fetch_signer(&request, &data).await {
+            Ok(viewer) => viewer,
+            Err(refusal) => return Ok(refusal.response()),
+        },
+    };
     let db = DB.get().unwrap();
 
     let post = prelude::Post::find()
@@ -158,8 +439,8 @@ async fn fetch_versia_post(
         .await?;
 
     let post = match post {
-        Some(post) => post,
-        None => return Ok(HttpResponse::NotFound().finish()),
+        Some(post) if instance_signed || may_view(&post, viewer.as_ref()).await? => post,
+        _ => return Ok(HttpResponse::NotFound().finish()),
     };
 
     Ok(HttpResponse::Ok()
@@ -169,9 +450,14 @@ async fn fetch_versia_post(
 
 #[get("/apbridge/create/{id}/{base64url}")]
 async fn create_activity(
+    request: HttpRequest,
     path: web::Path<(String, String)>,
-    state: web::Data<State>,
+    data: Data<State>,
 ) -> actix_web::Result<HttpResponse, error::Error> {
+    let viewer = match fetch_signer(&request, &data).await {
+        Ok(viewer) => viewer,
+        Err(refusal) => return Ok(refusal.response()),
+    };
     let db = DB.get().unwrap();
 
     let url = base_url_decode(path.1.as_str());
@@ -182,20 +468,18 @@ async fn create_activity(
         .await?;
 
     let post = match post {
-        Some(post) => post,
-        None => return Ok(HttpResponse::NotFound().finish()),
+        Some(post) if may_view(&post, viewer.as_ref()).await? => post,
+        _ => return Ok(HttpResponse::NotFound().finish()),
     };
 
     let ap_post = crate::objects::post::Note::from_db(&post);
 
-    let data = FEDERATION_CONFIG.get().unwrap();
-
     let create = crate::activities::create_post::CreatePost {
         actor: ap_post.attributed_to.clone(),
         to: ap_post.to.clone(),
         object: ap_post,
         kind: CreateType::Create,
-        id: generate_create_id(&data.to_request_data().domain(), &path.0, &path.1)?,
+        id: generate_create_id(data.domain(), &path.0, &path.1)?,
     };
     let create_with_context = WithContext::new_default(create);
 
@@ -267,3 +551,140 @@ pub async fn main_versia_url_to_user_and_model(
 
     Ok((versia_user_from_db(target.clone()).await?, target))
 }
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn test_may_view() {
+        use super::may_view;
+        use crate::{entities::post, testing};
+        use chrono::Utc;
+        use sea_orm::{ActiveModelTrait, Set};
+        use serde_json::json;
+
+        let db = testing::db().await;
+        let author = testing::user("may-view-author", false).await;
+        let mentioned = testing::user("may-view-mentioned", true).await;
+        let follower = testing::user("may-view-follower", true).await;
+        let stranger = testing::user("may-view-stranger", true).await;
+        testing::follow(&follower, &author).await;
+
+        let post = |id: &str, visibility: &str| post::ActiveModel {
+            id: Set(id.to_string()),
+            content: Set(String::new()),
+            local: Set(false),
+            created_at: Set(Utc::now()),
+            content_type: Set("text/html".to_string()),
+            visibility: Set(visibility.to_string()),
+            sensitive: Set(false),
+            creator: Set(author.id.clone()),
+            url: Set(format!("https://remote.example/notes/{id}")),
+            ap_json: Set(Some(
+                json!({
+                    "type": "Note",
+                    "id": format!("https://remote.example/notes/{id}"),
+                    "attributedTo": author.url,
+                    "to": [],
+                    "content": "",
+                    "tag": [{
+                        "type": "Mention",
+                        "href": "https://bridge.example/apbridge/user/may-view-mentioned",
+                    }],
+                })
+                .to_string(),
+            )),
+            ..Default::default()
+        };
+        let public = post("may-view-public", "public").insert(db).await.unwrap();
+        let followers = post("may-view-followers", "followers")
+            .insert(db)
+            .await
+            .unwrap();
+        let direct = post("may-view-direct", "direct").insert(db).await.unwrap();
+
+        assert!(may_view(&public, None).await.unwrap());
+        assert!(!may_view(&followers, None).await.unwrap());
+        assert!(may_view(&followers, Some(&author)).await.unwrap());
+        assert!(may_view(&followers, Some(&follower)).await.unwrap());
+        assert!(may_view(&followers, Some(&mentioned)).await.unwrap());
+        assert!(!may_view(&followers, Some(&stranger)).await.unwrap());
+        assert!(may_view(&direct, Some(&mentioned)).await.unwrap());
+        assert!(!may_view(&direct, Some(&follower)).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_inbox_needs_signature() {
+        use super::versia_inbox;
+        use crate::{
+            database::State,
+            entities::{inbox_job, prelude},
+            testing,
+            versia::signatures::{SIGNATURE_HEADER, SIGNED_AT_HEADER, SIGNED_BY_HEADER},
+        };
+        use actix_web::{http::StatusCode, test, web, App};
+        use sea_orm::{ColumnTrait, EntityTrait, PaginatorTrait, QueryFilter};
+        use std::sync::Arc;
+
+        let db = testing::db().await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(State {
+                    database_connection: Arc::new(db.clone()),
+                }))
+                .service(versia_inbox),
+        )
+        .await;
+        let body = r#"{"type":"Note","id":"inbox-signature-note","author":"https://versia.example/users/a"}"#;
+        let now = time::OffsetDateTime::now_utc().unix_timestamp().to_string();
+        let deliver = |headers: &[(&'static str, &str)]| {
+            let mut request = test::TestRequest::post()
+                .uri("/apbridge/versia/inbox")
+                .peer_addr("192.0.2.31:443".parse().unwrap())
+                .insert_header(("content-type", "application/json"))
+                .set_payload(body);
+            for (name, value) in headers {
+                request = request.insert_header((*name, value.to_string()));
+            }
+            request.to_request()
+        };
+
+        let cases: [(&[(&'static str, &str)], &str); 4] = [
+            (&[], "Missing Versia-Signature header"),
+            (
+                &[(SIGNATURE_HEADER, "c2lnbmF0dXJl"), (SIGNED_AT_HEADER, &now)],
+                "Missing Versia-Signed-By header",
+            ),
+            // signed by someone other than the Versia server we bridge
+            (
+                &[
+                    (SIGNATURE_HEADER, "c2lnbmF0dXJl"),
+                    (SIGNED_BY_HEADER, "https://other.example/users/a"),
+                    (SIGNED_AT_HEADER, &now),
+                ],
+                "is not allowed to deliver",
+            ),
+            // a signer whose key can't be had, here because the test has no Versia server
+            (
+                &[
+                    (SIGNATURE_HEADER, "c2lnbmF0dXJl"),
+                    (SIGNED_BY_HEADER, "https://versia.example/users/a"),
+                    (SIGNED_AT_HEADER, &now),
+                ],
+                "Could not fetch public key",
+            ),
+        ];
+        for (headers, reason) in cases {
+            let response = test::call_service(&app, deliver(headers)).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{reason}");
+            let body = test::read_body(response).await;
+            assert!(String::from_utf8(body.to_vec()).unwrap().contains(reason));
+        }
+
+        let queued = prelude::InboxJob::find()
+            .filter(inbox_job::Column::EntityId.eq("inbox-signature-note"))
+            .count(db)
+            .await
+            .unwrap();
+        assert_eq!(queued, 0);
+    }
+}
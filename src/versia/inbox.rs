@@ -1,21 +1,28 @@
 use crate::{
-    activities::{create_post::CreatePost, follow::Follow},
+    activities::{
+        create_post::{follower_inbox, CreatePost},
+        follow::{follow_answered, Follow},
+    },
+    delivery::deliver,
     entities::{
         self, follow_relation,
         prelude::{self, FollowRelation},
         user,
     },
+    policy::{deliverable, federates_with},
+    relays,
     utils::generate_follow_req_id,
     versia::http::main_versia_url_to_user_and_model,
     API_DOMAIN, DB, FEDERATION_CONFIG,
 };
 use activitypub_federation::{
-    activity_sending::SendActivityTask, fetch::object_id::ObjectId, protocol::context::WithContext,
+    fetch::object_id::ObjectId, protocol::context::WithContext, traits::Actor,
 };
-use activitystreams_kinds::{activity::FollowType, public};
+use activitystreams_kinds::activity::FollowType;
 use anyhow::Result;
 use sea_orm::{ActiveModelTrait, ColumnTrait, EntityOrSelect, EntityTrait, QueryFilter, Set};
 use serde::Deserialize;
+use tracing::warn;
 use url::Url;
 
 use super::{
@@ -25,7 +32,7 @@ use super::{
 
 pub async fn inbox_entry(json: &str) -> Result<()> {
     // Deserialize the JSON string into a dynamic value
-    let value: serde_json::Value = serde_json::from_str(json).unwrap();
+    let value: serde_json::Value = serde_json::from_str(json)?;
 
     // Extract the "type" field from the JSON
     if let Some(json_type) = value.get("type") {
@@ -39,11 +46,17 @@ pub async fn inbox_entry(json: &str) -> Result<()> {
                 let follow_req: super::objects::Follow = serde_json::from_str(json)?;
                 follow_request(follow_req).await?;
             }
-            Some("FollowAccept") => {
-                let follow_accept: super::objects::FollowResult = serde_json::from_str(json)?;
-            }
-            Some("FollowReject") => {
-                let follow_rej: super::objects::FollowResult = serde_json::from_str(json)?;
+            Some(result @ ("FollowAccept" | "FollowReject")) => {
+                let follow_result: super::objects::FollowResult = serde_json::from_str(json)?;
+                let data = FEDERATION_CONFIG.get().unwrap().to_request_data();
+                follow_answered(
+                    &follow_result.follower,
+                    &follow_result.author,
+                    result == "FollowAccept",
+                    &data,
+                )
+                .await
+                .map_err(|err| err.0)?;
             }
             Some("Unfollow") => {
                 let unfollow: super::objects::Unfollow = serde_json::from_str(json)?;
@@ -75,19 +88,28 @@ async fn follow_request(follow: super::objects::Follow) -> Result<()> {
             "User is already follow requesting / following the followee"
         ));
     }
-    let data = FEDERATION_CONFIG.get().unwrap();
     let author = main_versia_url_to_user_and_model(follow.author.into()).await?;
     println!("Followee URL: {}", &follow.followee.to_string());
     let followee = versia_url_to_user_and_model(follow.followee.into()).await?;
-    let serial_ap_author = serde_json::from_str::<crate::objects::person::Person>(
-        &(author.1.ap_json.clone()).unwrap(),
-    )?;
-    let serial_ap_followee = serde_json::from_str::<crate::objects::person::Person>(
-        &(followee.1.ap_json.clone()).unwrap(),
-    )?;
+    let ap_json = |user: &user::Model| {
+        user.ap_json
+            .clone()
+            .ok_or(anyhow::anyhow!("{} has no ActivityPub actor", user.url))
+    };
+    let serial_ap_author =
+        serde_json::from_str::<crate::objects::person::Person>(&ap_json(&author.1)?)?;
+    let serial_ap_followee =
+        serde_json::from_str::<crate::objects::person::Person>(&ap_json(&followee.1)?)?;
 
     let id = uuid::Uuid::now_v7().to_string();
 
+    if !federates_with(&serial_ap_followee.inbox) {
+        return Err(anyhow::anyhow!(
+            "Domain of {} is rejected",
+            serial_ap_followee.inbox
+        ));
+    }
+
     let followee_object: ObjectId<user::Model> = serial_ap_followee.id;
     let localuser_object: ObjectId<user::Model> = serial_ap_author.id;
 
@@ -106,10 +128,15 @@ async fn follow_request(follow: super::objects::Follow) -> Result<()> {
 
     let create_with_context = WithContext::new_default(create);
 
+    let followee_inbox = followee.1.shared_inbox_or_inbox();
     let follow_db_entry = follow_relation::ActiveModel {
         id: Set(id.clone()),
         followee_id: Set(followee.0.id.to_string()),
         follower_id: Set(author.0.id.to_string()),
+        followee_inbox: Set(Some(followee_inbox.to_string())),
+        followee_host: Set(followee_inbox.host_str().map(str::to_string)),
+        follower_inbox: Set(Some(author.0.inbox.to_string())),
+        follower_host: Set(author.0.inbox.host_str().map(str::to_string)),
         ap_id: Set(Some(id.clone())),
         ap_json: Set(ap_json),
         remote: Set(false),
@@ -117,18 +144,13 @@ async fn follow_request(follow: super::objects::Follow) -> Result<()> {
     };
     follow_db_entry.insert(db).await?;
 
-    let sends = SendActivityTask::prepare(
+    deliver(
         &create_with_context,
         &author.1,
         vec![serial_ap_followee.inbox],
-        &data.to_request_data(),
     )
     .await?;
 
-    for send in sends {
-        send.sign_and_send(&data.to_request_data()).await?;
-    }
-
     Ok(())
 }
 
@@ -136,52 +158,150 @@ async fn federate_inbox(note: super::objects::Note) -> Result<()> {
     let db_user = db_user_from_url(note.author.clone()).await?;
     let note = receive_versia_note(note, db_user.id).await?;
 
-    let ap_str = note.ap_json.clone().unwrap();
+    let ap_str = note
+        .ap_json
+        .clone()
+        .ok_or(anyhow::anyhow!("{} was not converted to a Note", note.url))?;
     let ap_note = serde_json::from_str::<crate::objects::post::Note>(&ap_str)?;
 
-    tokio::spawn(async move {
-        let conf = FEDERATION_CONFIG.get().unwrap();
-        let inbox = get_inbox_vec(&ap_note).await;
-
-        let res = CreatePost::sends(ap_note, note, inbox, &conf.to_request_data()).await;
-        if let Err(e) = res {
-            panic!("Problem federating: {e:?}");
-        }
-    });
+    // only queues the deliveries, so once this returns they survive a restart
+    let conf = FEDERATION_CONFIG.get().unwrap();
+    let inbox = get_inbox_vec(&ap_note, &note).await;
+    CreatePost::sends(ap_note, note, inbox, &conf.to_request_data())
+        .await
+        .map_err(|err| err.0)?;
 
     Ok(())
 }
 
-async fn get_inbox_vec(ap_note: &crate::objects::post::Note) -> Vec<Url> {
-    let mut inbox_users: Vec<Url> = Vec::new();
+async fn get_inbox_vec(
+    ap_note: &crate::objects::post::Note,
+    post: &entities::post::Model,
+) -> Vec<Url> {
+    // the public and followers collections aside, a note is addressed to who it mentions
+    let mut inbox_users: Vec<Url> = ap_note
+        .tag
+        .iter()
+        .map(|mention| mention.href.clone())
+        .collect();
     let mut inbox: Vec<Url> = Vec::new();
 
-    let entry = ap_note.to.get(0).unwrap();
-    if entry
-        .to_string()
-        .eq_ignore_ascii_case(public().to_string().as_str())
-    {
-        let (_, mentions) = ap_note.to.split_at(2);
-        inbox_users.append(&mut mentions.to_vec());
-    } else {
-        let (_, mentions) = ap_note.to.split_at(1);
-        inbox_users.append(&mut mentions.to_vec());
-    }
-
+    inbox_users.sort();
     inbox_users.dedup();
+    inbox_users.retain(federates_with);
 
     let conf = FEDERATION_CONFIG.get().unwrap();
     let data = &conf.to_request_data();
 
     for user in inbox_users {
-        let ap_user = ObjectId::<user::Model>::from(user)
+        match ObjectId::<user::Model>::from(user.clone())
             .dereference(data)
             .await
-            .unwrap();
-        inbox.push(Url::parse(&ap_user.inbox).unwrap());
+        {
+            // one delivery per server is enough for everyone mentioned on it
+            Ok(ap_user) => inbox.push(ap_user.shared_inbox_or_inbox()),
+            Err(err) => warn!(
+                "Not delivering to {}, it could not be fetched: {}",
+                user, err
+            ),
+        }
     }
 
+    if post.visibility != "direct" {
+        inbox.extend(follower_inboxes(&post.creator).await);
+    }
+    inbox.extend(relays::publish_inboxes(&ap_note.to).await);
+    inbox.sort();
     inbox.dedup();
 
-    inbox
+    deliverable(inbox)
+}
+
+/// The inboxes of the accepted fediverse followers of a bridged user
+async fn follower_inboxes(followee_id: &str) -> Vec<Url> {
+    let relations = FollowRelation::find()
+        .filter(follow_relation::Column::FolloweeId.eq(followee_id))
+        .filter(follow_relation::Column::Remote.eq(true))
+        .filter(follow_relation::Column::AcceptId.is_not_null())
+        .filter(follow_relation::Column::FollowerInboxGoneAt.is_null())
+        .all(DB.get().unwrap())
+        .await;
+    let relations = match relations {
+        Ok(relations) => relations,
+        Err(err) => {
+            warn!(
+                "Could not look up the followers of {}: {}",
+                followee_id, err
+            );
+            return vec![];
+        }
+    };
+    let mut inboxes = vec![];
+    for relation in relations {
+        match follower_inbox(relation).await {
+            Ok(inbox) => inboxes.push(inbox),
+            Err(err) => warn!("Not delivering to a follower of {}: {}", followee_id, err),
+        }
+    }
+    inboxes
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn test_followers_share_inbox() {
+        use super::get_inbox_vec;
+        use crate::{entities::user, objects::post::Note, testing, FEDERATION_CONFIG};
+        use chrono::Utc;
+        use sea_orm::{ActiveModelTrait, Set};
+        use serde_json::json;
+
+        let _ = FEDERATION_CONFIG.set(testing::federation().await);
+        let db = testing::db().await;
+        let author = testing::user("shared-inbox-author", true).await;
+        for id in ["shared-inbox-a", "shared-inbox-b"] {
+            let follower = testing::user(id, false).await;
+            let follower = user::ActiveModel {
+                id: Set(follower.id),
+                shared_inbox: Set(Some("https://remote.example/inbox".to_string())),
+                ..Default::default()
+            }
+            .update(db)
+            .await
+            .unwrap();
+            testing::follow(&follower, &author).await;
+        }
+        let post = |visibility: &str| crate::entities::post::Model {
+            id: format!("shared-inbox-{visibility}"),
+            title: None,
+            content: String::new(),
+            local: true,
+            created_at: Utc::now(),
+            updated_at: None,
+            reblog_id: None,
+            content_type: "text/plain".to_string(),
+            visibility: visibility.to_string(),
+            reply_id: None,
+            quoting_id: None,
+            sensitive: false,
+            spoiler_text: None,
+            creator: author.id.clone(),
+            url: String::new(),
+            ap_json: None,
+        };
+        let note: Note = serde_json::from_value(json!({
+            "type": "Note",
+            "id": "https://bridge.example/apbridge/object/shared-inbox",
+            "attributedTo": "https://bridge.example/apbridge/user/shared-inbox-author",
+            "to": ["https://bridge.example/apbridge/versia/followers/shared-inbox-author"],
+            "content": "",
+            "tag": [],
+        }))
+        .unwrap();
+
+        let inboxes = get_inbox_vec(&note, &post("followers")).await;
+        assert_eq!(inboxes.len(), 1);
+        assert_eq!(inboxes[0].as_str(), "https://remote.example/inbox");
+        assert!(get_inbox_vec(&note, &post("direct")).await.is_empty());
+    }
 }
@@ -0,0 +1,324 @@
+use std::{
+    collections::HashSet,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use prometheus::{IntGaugeVec, Opts};
+use sea_orm::{
+    sea_query::Expr, ColumnTrait, DbErr, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder,
+    QuerySelect, Set,
+};
+use serde_json::Value;
+use tokio::sync::{Notify, Semaphore};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    dedup,
+    entities::{inbox_job, prelude},
+    queue::backoff,
+    shutdown, DB, INBOX_CONCURRENCY, INBOX_JOB_MAX_ATTEMPTS,
+};
+
+use super::inbox::inbox_entry;
+
+const PENDING: &str = "pending";
+const DEAD: &str = "dead";
+
+/// How often the worker looks for due entities when nothing new was received
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+const BATCH_SIZE: u64 = 50;
+
+/// How often the queue depth metric is updated
+const DEPTH_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Wakes the worker when an entity is received
+static RECEIVED: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Limits entities processed at once
+static WORKERS: Lazy<Arc<Semaphore>> =
+    Lazy::new(|| Arc::new(Semaphore::new((*INBOX_CONCURRENCY).max(1))));
+
+/// Entities being processed, so the next batch doesn't pick them up again
+static IN_FLIGHT: Lazy<Mutex<HashSet<String>>> = Lazy::new(Default::default);
+
+/// Received Versia entities by status
+pub static INBOX_QUEUE_DEPTH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    IntGaugeVec::new(
+        Opts::new(
+            "versia_inbox_queue_depth",
+            "Received Versia entities waiting to be processed, by status",
+        )
+        .namespace("activitypub_bridge"),
+        &["status"],
+    )
+    .unwrap()
+});
+
+/// What a Versia entity is deduplicated by: its uri once federated, otherwise its id
+pub fn entity_id(value: &Value) -> Option<&str> {
+    value
+        .get("uri")
+        .or(value.get("id"))
+        .and_then(|id| id.as_str())
+}
+
+/// Stores a received entity for the worker. Returns false if it was received before, in
+/// which case nothing is stored.
+pub async fn enqueue(body: String, entity_id: Option<&str>) -> Result<bool, DbErr> {
+    if let Some(id) = entity_id {
+        if !dedup::claim(id).await? {
+            info!("Skipping already received entity {}", id);
+            return Ok(false);
+        }
+    }
+    let now = Utc::now();
+    let inserted = prelude::InboxJob::insert(inbox_job::ActiveModel {
+        id: Set(Uuid::now_v7().to_string()),
+        entity_id: Set(entity_id.map(str::to_string)),
+        body: Set(body),
+        status: Set(PENDING.to_string()),
+        attempts: Set(0),
+        next_attempt_at: Set(now),
+        last_error: Set(None),
+        created_at: Set(now),
+    })
+    .exec_without_returning(DB.get().unwrap())
+    .await;
+    if let Err(err) = inserted {
+        if let Some(id) = entity_id {
+            if let Err(err) = dedup::release(id).await {
+                warn!("Could not release entity {}: {}", id, err);
+            }
+        }
+        return Err(err);
+    }
+    if let Some(id) = entity_id {
+        // stored, the worker takes it from here
+        dedup::mark_processed(id).await?;
+    }
+    RECEIVED.notify_one();
+    Ok(true)
+}
+
+/// Runs `future` in its own task, so that a panic fails the attempt instead of taking the
+/// worker down with it and leaving the entity to panic again after every restart
+async fn catch_panic<F>(future: F) -> anyhow::Result<()>
+where
+    F: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    match tokio::spawn(future).await {
+        Ok(result) => result,
+        Err(err) if err.is_panic() => {
+            let panic = err.into_panic();
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or(panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            Err(anyhow!("Panicked: {}", message))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Processes one entity. Failures are retried with backoff until the attempts run out,
+/// then the entity is kept as dead for an operator to look at.
+async fn process(job: inbox_job::Model) -> Result<(), DbErr> {
+    let db = DB.get().unwrap();
+    let body = job.body.clone();
+    let err = match catch_panic(async move { inbox_entry(&body).await }).await {
+        Ok(()) => {
+            prelude::InboxJob::delete_by_id(job.id).exec(db).await?;
+            return Ok(());
+        }
+        Err(err) => err,
+    };
+    let attempts = job.attempts + 1;
+    let mut model = inbox_job::ActiveModel {
+        id: Set(job.id.clone()),
+        attempts: Set(attempts),
+        last_error: Set(Some(err.to_string())),
+        ..Default::default()
+    };
+    let entity = job.entity_id.as_deref().unwrap_or(&job.id);
+    if attempts >= *INBOX_JOB_MAX_ATTEMPTS {
+        warn!(
+            "Giving up on processing {} after {} attempts: {}",
+            entity, attempts, err
+        );
+        model.status = Set(DEAD.to_string());
+    } else {
+        let retry_at = Utc::now() + chrono::Duration::from_std(backoff(attempts)).unwrap();
+        info!(
+            "Processing {} failed, retrying at {}: {}",
+            entity, retry_at, err
+        );
+        model.next_attempt_at = Set(retry_at);
+    }
+    prelude::InboxJob::update(model).exec(db).await?;
+    Ok(())
+}
+
+/// Hands the due entities to workers in the order they were received, returning how many
+/// were started
+pub async fn dispatch() -> Result<usize, DbErr> {
+    let in_flight: Vec<String> = IN_FLIGHT.lock().unwrap().iter().cloned().collect();
+    let due = prelude::InboxJob::find()
+        .filter(inbox_job::Column::Status.eq(PENDING))
+        .filter(inbox_job::Column::NextAttemptAt.lte(Utc::now()))
+        .filter(inbox_job::Column::Id.is_not_in(in_flight))
+        .order_by_asc(inbox_job::Column::NextAttemptAt)
+        .limit(BATCH_SIZE)
+        .all(DB.get().unwrap())
+        .await?;
+    let mut started = 0;
+    for job in due {
+        let permit = WORKERS.clone().acquire_owned().await.unwrap();
+        // checked with the permit held, so drain waits for everything started before it;
+        // the rest stay queued for after the restart
+        if shutdown::stopping() {
+            break;
+        }
+        if !IN_FLIGHT.lock().unwrap().insert(job.id.clone()) {
+            continue;
+        }
+        tokio::spawn(async move {
+            let id = job.id.clone();
+            if let Err(err) = process(job).await {
+                warn!("Could not update Versia inbox job {}: {}", id, err);
+            }
+            IN_FLIGHT.lock().unwrap().remove(&id);
+            drop(permit);
+            // a free worker may take what was left out of the last batch
+            RECEIVED.notify_one();
+        });
+        started += 1;
+    }
+    Ok(started)
+}
+
+async fn update_queue_depth() -> Result<(), DbErr> {
+    let (pending, dead) = counts().await?;
+    INBOX_QUEUE_DEPTH
+        .with_label_values(&["pending"])
+        .set(pending as i64);
+    INBOX_QUEUE_DEPTH
+        .with_label_values(&["dead"])
+        .set(dead as i64);
+    Ok(())
+}
+
+/// Processes received entities in the background, including those left over from before
+/// a restart
+pub fn spawn_worker() {
+    tokio::spawn(async {
+        while !shutdown::stopping() {
+            match dispatch().await {
+                Ok(started) if started > 0 => continue,
+                Ok(_) => {}
+                Err(err) => warn!("Could not work off the Versia inbox queue: {}", err),
+            }
+            tokio::select! {
+                _ = RECEIVED.notified() => {}
+                _ = shutdown::stopped() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    });
+    tokio::spawn(async {
+        loop {
+            if let Err(err) = update_queue_depth().await {
+                warn!("Could not measure the Versia inbox queue: {}", err);
+            }
+            tokio::time::sleep(DEPTH_INTERVAL).await;
+        }
+    });
+}
+
+/// Waits for the entities being processed once shutdown has begun. The permits are kept
+/// until drain returns, so no new entity is started meanwhile.
+pub async fn drain() {
+    let workers = (*INBOX_CONCURRENCY).max(1) as u32;
+    let _workers = WORKERS.acquire_many(workers).await;
+}
+
+/// Number of pending and dead entities
+pub async fn counts() -> Result<(u64, u64), DbErr> {
+    let db = DB.get().unwrap();
+    let count = |status: &'static str| {
+        prelude::InboxJob::find()
+            .filter(inbox_job::Column::Status.eq(status))
+            .count(db)
+    };
+    Ok((count(PENDING).await?, count(DEAD).await?))
+}
+
+pub async fn dead_jobs() -> Result<Vec<inbox_job::Model>, DbErr> {
+    prelude::InboxJob::find()
+        .filter(inbox_job::Column::Status.eq(DEAD))
+        .order_by_asc(inbox_job::Column::CreatedAt)
+        .all(DB.get().unwrap())
+        .await
+}
+
+/// Gives dead entities, or only the one with `id`, a fresh set of attempts
+pub async fn retry_dead(id: Option<String>) -> Result<u64, DbErr> {
+    let mut query = prelude::InboxJob::update_many()
+        .col_expr(inbox_job::Column::Status, Expr::value(PENDING))
+        .col_expr(inbox_job::Column::Attempts, Expr::value(0))
+        .col_expr(inbox_job::Column::NextAttemptAt, Expr::value(Utc::now()))
+        .filter(inbox_job::Column::Status.eq(DEAD));
+    if let Some(id) = id {
+        query = query.filter(inbox_job::Column::Id.eq(id));
+    }
+    Ok(query.exec(DB.get().unwrap()).await?.rows_affected)
+}
+
+pub async fn purge_dead() -> Result<u64, DbErr> {
+    Ok(prelude::InboxJob::delete_many()
+        .filter(inbox_job::Column::Status.eq(DEAD))
+        .exec(DB.get().unwrap())
+        .await?
+        .rows_affected)
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn test_panic_fails_attempt() {
+        use super::catch_panic;
+
+        assert!(catch_panic(async { Ok(()) }).await.is_ok());
+        let err = catch_panic(async {
+            let to: Vec<u8> = vec![];
+            let _ = to.split_at(2);
+            Ok(())
+        })
+        .await
+        .unwrap_err();
+        assert!(err.to_string().starts_with("Panicked: "));
+        // the runtime carries on
+        assert!(catch_panic(async { Err(anyhow::anyhow!("failed")) })
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_inbox_entity_id() {
+        use super::entity_id;
+        use serde_json::json;
+
+        let federated = json!({ "id": "1", "uri": "https://a.example/notes/1", "type": "Note" });
+        assert_eq!(entity_id(&federated), Some("https://a.example/notes/1"));
+        let local = json!({ "id": "2", "type": "Follow" });
+        assert_eq!(entity_id(&local), Some("2"));
+        assert_eq!(entity_id(&json!({ "type": "Delete" })), None);
+    }
+}
@@ -0,0 +1,140 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{pkcs8::EncodePublicKey, SigningKey, VerifyingKey};
+use once_cell::sync::OnceCell;
+use rand::rngs::OsRng;
+use sea_orm::{sea_query::Expr, ColumnTrait, Condition, EntityTrait, QueryFilter};
+use std::env;
+
+use crate::{
+    crypto,
+    entities::{prelude, user},
+    DB,
+};
+
+/// The ed25519 key the bridge identifies itself with towards Versia.
+///
+/// Read from `INSTANCE_KEY` (base64 encoded 32 byte seed). Without it the service actor's
+/// Versia key is used, which is generated once and stored with the actor, so peers that
+/// cached the key keep accepting it across restarts.
+static INSTANCE_KEY: OnceCell<SigningKey> = OnceCell::new();
+
+/// Sets up the instance key, at startup once the service actor exists
+pub async fn load_instance_key(service_actor: user::Model) -> anyhow::Result<()> {
+    let key = match env::var("INSTANCE_KEY") {
+        Ok(encoded) => signing_key_from_base64(&encoded)?,
+        Err(_) => versia_signing_key(&ensure_versia_keys(service_actor).await?)?,
+    };
+    let _ = INSTANCE_KEY.set(key);
+    Ok(())
+}
+
+pub fn instance_signing_key() -> &'static SigningKey {
+    INSTANCE_KEY
+        .get()
+        .expect("the instance key is loaded at startup")
+}
+
+pub fn signing_key_from_base64(encoded: &str) -> anyhow::Result<SigningKey> {
+    let seed: [u8; 32] = STANDARD
+        .decode(encoded.trim())?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("ed25519 seed must be 32 bytes"))?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+/// Encodes a public key the way Versia expects it: base64 of the SPKI DER.
+pub fn encode_public_key(key: &VerifyingKey) -> anyhow::Result<String> {
+    let der = key
+        .to_public_key_der()
+        .map_err(|e| anyhow::anyhow!("failed to encode public key: {e}"))?;
+    Ok(STANDARD.encode(der.as_bytes()))
+}
+
+/// A fresh keypair as stored in the user table: the SPKI public key and the private seed,
+/// both base64 encoded, the seed sealed with the master key
+pub fn generate_versia_keypair() -> anyhow::Result<(String, String)> {
+    let key = SigningKey::generate(&mut OsRng);
+    Ok((
+        encode_public_key(&key.verifying_key())?,
+        crypto::seal(&STANDARD.encode(key.to_bytes())),
+    ))
+}
+
+/// Returns the user with a Versia keypair, generating and persisting one if it has none yet.
+/// Only a user still without keys is updated, so of two concurrent calls the first keypair
+/// wins and both return it.
+pub async fn ensure_versia_keys(user: user::Model) -> anyhow::Result<user::Model> {
+    if user.versia_public_key.is_some() && user.versia_private_key.is_some() {
+        return Ok(user);
+    }
+    let db = DB.get().unwrap();
+    let (public_key, private_key) = generate_versia_keypair()?;
+    prelude::User::update_many()
+        .col_expr(user::Column::VersiaPublicKey, Expr::value(public_key))
+        .col_expr(user::Column::VersiaPrivateKey, Expr::value(private_key))
+        .filter(user::Column::Id.eq(user.id.as_str()))
+        .filter(
+            Condition::any()
+                .add(user::Column::VersiaPublicKey.is_null())
+                .add(user::Column::VersiaPrivateKey.is_null()),
+        )
+        .exec(db)
+        .await?;
+    prelude::User::find_by_id(user.id.as_str())
+        .one(db)
+        .await?
+        .ok_or(anyhow::anyhow!("User {} no longer exists", user.id))
+}
+
+pub fn versia_signing_key(user: &user::Model) -> anyhow::Result<SigningKey> {
+    let private_key = user
+        .versia_private_key
+        .as_ref()
+        .ok_or(anyhow::anyhow!("User {} has no Versia key", user.id))?;
+    signing_key_from_base64(&crypto::open(private_key)?)
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn test_concurrent_versia_keys() {
+        use super::ensure_versia_keys;
+        use crate::testing;
+
+        testing::db().await;
+        let user = testing::user("keys-race", true).await;
+        let (first, second) = tokio::join!(
+            ensure_versia_keys(user.clone()),
+            ensure_versia_keys(user.clone())
+        );
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert!(first.versia_public_key.is_some());
+        assert_eq!(first.versia_public_key, second.versia_public_key);
+        assert_eq!(first.versia_private_key, second.versia_private_key);
+
+        // a stale copy without keys gets the stored ones
+        let again = ensure_versia_keys(user).await.unwrap();
+        assert_eq!(again.versia_public_key, first.versia_public_key);
+    }
+
+    #[tokio::test]
+    async fn test_instance_key_is_stored() {
+        use super::{instance_signing_key, load_instance_key, versia_signing_key};
+        use crate::{entities::prelude, testing};
+        use sea_orm::EntityTrait;
+
+        let db = testing::db().await;
+        let actor = testing::user("instance-key-actor", true).await;
+        load_instance_key(actor.clone()).await.unwrap();
+        let stored = prelude::User::find_by_id(actor.id.as_str())
+            .one(db)
+            .await
+            .unwrap()
+            .unwrap();
+        // the next start reads the same key back
+        assert_eq!(
+            versia_signing_key(&stored).unwrap().verifying_key(),
+            instance_signing_key().verifying_key()
+        );
+    }
+}
@@ -2,6 +2,9 @@ pub mod conversion;
 pub mod funcs;
 pub mod http;
 pub mod inbox;
+pub mod inbox_queue;
+pub mod keys;
 pub mod objects;
+pub mod signatures;
 pub mod superx;
 pub mod test;
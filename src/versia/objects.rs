@@ -403,3 +403,46 @@ pub struct Delete {
     pub deleted_type: String,
     pub deleted: Url,
 }
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InstanceMetadata {
+    #[serde(rename = "type")]
+    pub rtype: String,
+    pub name: String,
+    pub software: InstanceSoftware,
+    pub compatibility: InstanceCompatibility,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub host: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shared_inbox: Option<Url>,
+    pub public_key: InstancePublicKey,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub moderators: Option<Url>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admins: Option<Url>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logo: Option<ContentFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub banner: Option<ContentFormat>,
+    #[serde(with = "iso_versia")]
+    pub created_at: OffsetDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InstanceSoftware {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InstanceCompatibility {
+    pub versions: Vec<String>,
+    pub extensions: Vec<VersiaExtensions>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InstancePublicKey {
+    pub key: String,
+    pub algorithm: String,
+}
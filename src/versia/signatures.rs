@@ -0,0 +1,266 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use actix_web::HttpRequest;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{
+    pkcs8::DecodePublicKey, Signature, Signer, SigningKey, Verifier, VerifyingKey,
+};
+use once_cell::sync::Lazy;
+use reqwest::{header::CONTENT_TYPE, RequestBuilder};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use url::{Position, Url};
+
+use crate::{entities::user, fetcher::fetcher, LYSAND_DOMAIN};
+
+use super::{
+    conversion::fetch_user_from_url,
+    keys::{ensure_versia_keys, versia_signing_key},
+    objects::InstanceMetadata,
+};
+
+pub const SIGNATURE_HEADER: &str = "Versia-Signature";
+pub const SIGNED_BY_HEADER: &str = "Versia-Signed-By";
+pub const SIGNED_AT_HEADER: &str = "Versia-Signed-At";
+
+/// How far the signing timestamp may drift from our clock, in seconds
+const MAX_CLOCK_SKEW: i64 = 300;
+
+/// Signatures seen within the accepted time window. A signature covers the timestamp,
+/// so it is unique per request and doubles as the request nonce.
+static SEEN_SIGNATURES: Lazy<Mutex<HashMap<String, i64>>> = Lazy::new(Default::default);
+
+#[derive(Debug, Error)]
+pub enum SignatureError {
+    #[error("Missing {0} header")]
+    MissingHeader(&'static str),
+    #[error("Malformed {0} header")]
+    MalformedHeader(&'static str),
+    #[error("Request was signed too long ago or in the future")]
+    Expired,
+    #[error("Request was already received")]
+    Replayed,
+    #[error("Signer {0} is not allowed to deliver to this inbox")]
+    ForeignSigner(String),
+    #[error("Could not fetch public key of {0}")]
+    KeyUnavailable(String),
+    #[error("Signature does not match")]
+    Invalid,
+}
+
+/// The string a Versia request signature is made over
+pub fn signing_string(method: &str, path: &str, signed_at: i64, body: &[u8]) -> String {
+    let hash = STANDARD.encode(Sha256::digest(body));
+    format!("{} {} {} {}", method.to_lowercase(), path, signed_at, hash)
+}
+
+/// Checks a signature made by `key`, without looking at where the key came from
+pub fn verify_signature(
+    key: &VerifyingKey,
+    method: &str,
+    path: &str,
+    signed_at: i64,
+    body: &[u8],
+    signature: &str,
+    now: i64,
+) -> Result<(), SignatureError> {
+    if (now - signed_at).abs() > MAX_CLOCK_SKEW {
+        return Err(SignatureError::Expired);
+    }
+    let signature = STANDARD
+        .decode(signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or(SignatureError::MalformedHeader(SIGNATURE_HEADER))?;
+    key.verify(
+        signing_string(method, path, signed_at, body).as_bytes(),
+        &signature,
+    )
+    .map_err(|_| SignatureError::Invalid)
+}
+
+/// Adds the Versia signature headers for a request with `body` to `url`
+pub fn sign_request(
+    request: RequestBuilder,
+    method: &str,
+    url: &Url,
+    body: &[u8],
+    signed_by: &Url,
+    key: &SigningKey,
+) -> RequestBuilder {
+    let signed_at = time::OffsetDateTime::now_utc().unix_timestamp();
+    let path = &url[Position::BeforePath..];
+    let signature = key.sign(signing_string(method, path, signed_at, body).as_bytes());
+    request
+        .header(SIGNATURE_HEADER, STANDARD.encode(signature.to_bytes()))
+        .header(SIGNED_BY_HEADER, signed_by.as_str())
+        .header(SIGNED_AT_HEADER, signed_at.to_string())
+}
+
+/// Builds a POST of a serialized entity to a Versia inbox, signed by the bridged user acting
+pub async fn signed_versia_body(
+    inbox: &Url,
+    body: Vec<u8>,
+    signer: user::Model,
+) -> anyhow::Result<RequestBuilder> {
+    let signer = ensure_versia_keys(signer).await?;
+    let key = versia_signing_key(&signer)?;
+    let signed_by = Url::parse(&signer.url)?;
+    fetcher().check(inbox)?;
+    let request = fetcher()
+        .client()
+        .post(inbox.clone())
+        .header(CONTENT_TYPE, "application/json; charset=utf-8");
+    Ok(sign_request(request, "POST", inbox, &body, &signed_by, &key).body(body))
+}
+
+pub fn decode_public_key(encoded: &str) -> anyhow::Result<VerifyingKey> {
+    let bytes = STANDARD.decode(encoded.trim())?;
+    // SPKI is what the spec asks for, some implementations publish the raw key instead
+    if let Ok(raw) = <[u8; 32]>::try_from(bytes.as_slice()) {
+        return Ok(VerifyingKey::from_bytes(&raw)?);
+    }
+    VerifyingKey::from_public_key_der(&bytes)
+        .map_err(|e| anyhow::anyhow!("invalid ed25519 public key: {e}"))
+}
+
+/// Verifies a request to the Versia inbox and returns who signed it.
+///
+/// Only the Versia server we bridge for, or its users, may deliver to us.
+pub async fn verify_request(request: &HttpRequest, body: &[u8]) -> Result<Url, SignatureError> {
+    let header = |name: &'static str| {
+        request
+            .headers()
+            .get(name)
+            .ok_or(SignatureError::MissingHeader(name))?
+            .to_str()
+            .map_err(|_| SignatureError::MalformedHeader(name))
+    };
+    let signature = header(SIGNATURE_HEADER)?;
+    let signed_by = header(SIGNED_BY_HEADER)?;
+    let signed_at = header(SIGNED_AT_HEADER)?
+        .parse::<i64>()
+        .map_err(|_| SignatureError::MalformedHeader(SIGNED_AT_HEADER))?;
+
+    // users sign with their uri, the instance itself with its bare domain
+    let signer = match Url::parse(signed_by) {
+        Ok(url) => url,
+        Err(_) => Url::parse(&format!("https://{}/", signed_by))
+            .map_err(|_| SignatureError::MalformedHeader(SIGNED_BY_HEADER))?,
+    };
+    if signer.host_str() != Some(LYSAND_DOMAIN.as_str()) {
+        return Err(SignatureError::ForeignSigner(signed_by.to_string()));
+    }
+
+    let key = fetch_signer_key(&signer)
+        .await
+        .map_err(|_| SignatureError::KeyUnavailable(signed_by.to_string()))?;
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+    verify_signature(
+        &key,
+        request.method().as_str(),
+        path,
+        signed_at,
+        body,
+        signature,
+        now,
+    )?;
+
+    check_replay(request.method().as_str(), signature, signed_at, now)?;
+
+    Ok(signer)
+}
+
+/// Refuses a signature seen before. Fetches only read, so a retried signed GET is let
+/// through again.
+fn check_replay(
+    method: &str,
+    signature: &str,
+    signed_at: i64,
+    now: i64,
+) -> Result<(), SignatureError> {
+    if matches!(method, "GET" | "HEAD") {
+        return Ok(());
+    }
+    let mut seen = SEEN_SIGNATURES.lock().unwrap();
+    seen.retain(|_, signed_at| (now - *signed_at).abs() <= MAX_CLOCK_SKEW);
+    if seen.insert(signature.to_string(), signed_at).is_some() {
+        return Err(SignatureError::Replayed);
+    }
+    Ok(())
+}
+
+/// Whether the request was signed by the Versia instance itself rather than one of its users
+pub fn is_instance_signer(signer: &Url) -> bool {
+    signer.path() == "/"
+}
+
+/// Users may only deliver their own entities, the instance may deliver anything
+pub fn signer_may_act_for(signer: &Url, author: Option<&str>) -> bool {
+    is_instance_signer(signer) || author.is_some_and(|author| author == signer.as_str())
+}
+
+async fn fetch_signer_key(signer: &Url) -> anyhow::Result<VerifyingKey> {
+    if is_instance_signer(signer) {
+        let metadata_url = signer.join("/.well-known/versia")?;
+        let metadata = fetcher()
+            .get_json::<InstanceMetadata>(&metadata_url)
+            .await?;
+        return decode_public_key(&metadata.public_key.key);
+    }
+    let user = fetch_user_from_url(signer.clone()).await?;
+    decode_public_key(&user.public_key.key)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_signature_roundtrip() {
+        use super::{signing_string, verify_signature};
+        use base64::{engine::general_purpose::STANDARD, Engine};
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let body = br#"{"type":"Follow"}"#;
+        let signed_at = 1_700_000_000;
+        let signature = STANDARD.encode(
+            key.sign(signing_string("POST", "/apbridge/versia/inbox", signed_at, body).as_bytes())
+                .to_bytes(),
+        );
+        let verify = |path: &str, body: &[u8], now: i64| {
+            verify_signature(
+                &key.verifying_key(),
+                "POST",
+                path,
+                signed_at,
+                body,
+                &signature,
+                now,
+            )
+        };
+
+        assert!(verify("/apbridge/versia/inbox", body, signed_at + 10).is_ok());
+        assert!(verify("/apbridge/versia/inbox", b"{}", signed_at).is_err());
+        assert!(verify("/other/inbox", body, signed_at).is_err());
+        assert!(verify("/apbridge/versia/inbox", body, signed_at + 3600).is_err());
+    }
+
+    #[test]
+    fn test_replays() {
+        use super::{check_replay, SignatureError};
+
+        let now = 1_700_000_000;
+        assert!(check_replay("GET", "get-signature", now, now).is_ok());
+        assert!(check_replay("GET", "get-signature", now, now + 1).is_ok());
+        assert!(check_replay("POST", "post-signature", now, now).is_ok());
+        assert!(matches!(
+            check_replay("POST", "post-signature", now, now + 1),
+            Err(SignatureError::Replayed)
+        ));
+    }
+}
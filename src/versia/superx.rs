@@ -39,15 +39,3 @@ pub async fn serialize_outbox(outbox: super::objects::Outbox) -> anyhow::Result<
     let data = serde_json::to_string(&SortAlphabetically(&outbox))?;
     Ok(data)
 }
-
-#[inline]
-pub fn request_client() -> reqwest::Client {
-    reqwest::Client::builder()
-        .user_agent(concat!(
-            env!("CARGO_PKG_NAME"),
-            "/",
-            env!("CARGO_PKG_VERSION"),
-        ))
-        .build()
-        .unwrap()
-}
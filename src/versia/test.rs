@@ -1,10 +1,10 @@
 use crate::versia::objects::SortAlphabetically;
 
-use super::superx::request_client;
+use crate::fetcher::fetcher;
 
 #[actix_web::test]
 async fn test_user_serial() {
-    let client = request_client();
+    let client = fetcher().client();
     let response = client
         .get("https://versia.social/users/018ec082-0ae1-761c-b2c5-22275a611771")
         .send()
@@ -25,7 +25,7 @@ async fn test_user_serial() {
 }
 
 pub async fn main() -> anyhow::Result<()> {
-    let client = request_client();
+    let client = fetcher().client();
 
     println!("Requesting user");
     let response = client